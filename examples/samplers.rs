@@ -0,0 +1,70 @@
+//! Compare samplers on integrals with known values, the way pixels use them: each of
+//! many "pixels" averages `spp` samples, and the RMS error over pixels is reported.
+//!
+//! cargo run --release --example samplers
+
+use std::f64::consts::PI;
+
+use raytracing::sampler::{self, Sampler};
+
+const PIXELS: i32 = 1024;
+
+type Integrand = fn(&mut dyn Sampler) -> f64;
+
+/// Quarter disc coverage from one 2D sample, like an edge crossing a pixel
+fn disc(sampler: &mut dyn Sampler) -> f64 {
+    let (x, y) = sampler.next_2d();
+    if x * x + y * y < 1.0 {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Smooth product over six dimensions, like a few bounces' worth of decisions
+fn product(sampler: &mut dyn Sampler) -> f64 {
+    (0..6)
+        .map(|_| 0.5 * PI * (PI * sampler.next_f64()).sin())
+        .product()
+}
+
+fn rms_error(name: &str, spp: u32, f: Integrand, expected: f64) -> f64 {
+    let kind = sampler::by_name(name).unwrap();
+    let mut sampler = kind.create(7, spp);
+
+    let mut squared_error = 0.0;
+    for pixel in 0..PIXELS {
+        let mut sum = 0.0;
+        for index in 0..spp {
+            sampler.start_pixel_sample(pixel, 0, index);
+            sum += f(sampler.as_mut());
+        }
+        let error = sum / spp as f64 - expected;
+        squared_error += error * error;
+    }
+
+    (squared_error / PIXELS as f64).sqrt()
+}
+
+fn main() {
+    let integrals: [(&str, Integrand, f64); 2] = [
+        ("quarter disc", disc, PI / 4.0),
+        ("6D product", product, 1.0),
+    ];
+
+    for (label, f, expected) in integrals {
+        println!("{label}");
+        println!(
+            "{:>6} {:>12} {:>12} {:>12} {:>12}",
+            "spp", "random", "stratified", "halton", "sobol"
+        );
+        for spp in [16, 64, 256] {
+            print!("{spp:>6}");
+            for name in ["random", "stratified", "halton", "sobol"] {
+                print!(" {:>12.3e}", rms_error(name, spp, f, expected));
+            }
+            println!();
+        }
+        println!();
+    }
+}
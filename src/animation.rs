@@ -0,0 +1,609 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{
+    geometry::{deg_to_rad, Point3, Vec3},
+    scene::{MaterialDescription, Scene, Shape, Tokens},
+};
+
+/// Scene changes over time, as keyframed tracks applied to a scene frame by frame.
+///
+/// Animation files hold one directive per line, with `#` starting a comment:
+///
+/// ```text
+/// frames FIRST LAST
+/// key FRAME camera look_from|look_at X Y Z [INTERPOLATION]
+/// key FRAME camera vfov|focus_dist VALUE [INTERPOLATION]
+/// key FRAME object INDEX translate X Y Z [INTERPOLATION]
+/// key FRAME object INDEX rotate DEGREES [INTERPOLATION]
+/// key FRAME object INDEX scale FACTOR [INTERPOLATION]
+/// key FRAME material NAME albedo|emit|sheen R G B [INTERPOLATION]
+/// key FRAME material NAME fuzz|refraction_index|roughness|anisotropy VALUE [INTERPOLATION]
+/// ```
+///
+/// Objects are counted from 0 in the order the scene lists them. The interpolation, linear
+/// unless given, is how the value moves on to the next key: `linear`, `catmull-rom`, `ease`,
+/// `ease-in`, `ease-out`, `ease-in-out` or `bezier X1 Y1 X2 Y2`. Material parameters a curve
+/// takes out of range, such as a negative albedo, stop at the bound; a field of view, focus
+/// distance or scale out of range is an error in the frame it happens.
+#[derive(Debug, Clone, Default)]
+pub struct Animation {
+    pub tracks: Vec<Track>,
+    /// Frames to render, both ends included; from the first key to the last if not given
+    pub frames: Option<(i32, i32)>,
+}
+
+/// One animated value and its keys
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub target: Target,
+    /// In frame order. Scalar values are held in `x`.
+    pub keys: Vec<Keyframe>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe {
+    pub frame: f64,
+    pub value: Vec3,
+    /// How the value moves on to the next key
+    pub interpolation: Interpolation,
+}
+
+/// What a track animates
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    LookFrom,
+    LookAt,
+    Vfov,
+    FocusDist,
+    /// Offset of object `index` from where the scene puts it
+    Translate(usize),
+    /// Turn of object `index` in degrees about the vertical through its center
+    Rotate(usize),
+    /// Size of object `index` relative to the scene's, about its center
+    Scale(usize),
+    /// Parameter of the named material, as the scene file calls it
+    Material {
+        name: String,
+        param: String,
+    },
+}
+
+impl Target {
+    /// Whether the values are vectors or colors rather than numbers
+    fn is_vector(&self) -> bool {
+        match self {
+            Target::LookFrom | Target::LookAt | Target::Translate(_) => true,
+            Target::Material { param, .. } => matches!(param.as_str(), "albedo" | "emit" | "sheen"),
+            _ => false,
+        }
+    }
+
+    /// `value` held within the range of a material parameter, or an error for any other
+    /// value out of range
+    fn limit(&self, value: Vec3) -> Result<Vec3, String> {
+        let clamp = |min: f64, max: f64| Vec3 {
+            x: value.x.clamp(min, max),
+            y: value.y.clamp(min, max),
+            z: value.z.clamp(min, max),
+        };
+        match self {
+            Target::Vfov if !(value.x > 0.0 && value.x < 180.0) => {
+                Err(format!("vfov {} is not between 0 and 180", value.x))
+            }
+            Target::FocusDist if value.x <= 0.0 => {
+                Err(format!("focus_dist {} is not positive", value.x))
+            }
+            Target::Scale(index) if value.x <= 0.0 => Err(format!(
+                "scale {} of object {index} is not positive",
+                value.x
+            )),
+            Target::Material { name, param } => match param.as_str() {
+                "refraction_index" if value.x <= 0.0 => Err(format!(
+                    "refraction_index {} of material `{name}` is not positive",
+                    value.x
+                )),
+                "roughness" | "anisotropy" => Ok(clamp(0.0, 1.0)),
+                "refraction_index" => Ok(value),
+                _ => Ok(clamp(0.0, f64::INFINITY)),
+            },
+            _ => Ok(value),
+        }
+    }
+}
+
+/// How a value moves from one key to the next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    /// At a constant rate
+    Linear,
+    /// Along a smooth curve through the keys before and after as well, which may overshoot
+    CatmullRom,
+    /// Along the straight line, timed by the cubic Bezier easing curve from (0,0) to (1,1)
+    /// with control points (`x1`,`y1`) and (`x2`,`y2`), as in CSS
+    Bezier { x1: f64, y1: f64, x2: f64, y2: f64 },
+}
+
+impl Interpolation {
+    /// Look up a named interpolation; the easing curves are the CSS ones
+    pub fn by_name(name: &str) -> Option<Interpolation> {
+        let bezier = |x1, y1, x2, y2| Interpolation::Bezier { x1, y1, x2, y2 };
+        match name {
+            "linear" => Some(Interpolation::Linear),
+            "catmull-rom" => Some(Interpolation::CatmullRom),
+            "ease" => Some(bezier(0.25, 0.1, 0.25, 1.0)),
+            "ease-in" => Some(bezier(0.42, 0.0, 1.0, 1.0)),
+            "ease-out" => Some(bezier(0.0, 0.0, 0.58, 1.0)),
+            "ease-in-out" => Some(bezier(0.42, 0.0, 0.58, 1.0)),
+            _ => None,
+        }
+    }
+}
+
+impl Track {
+    pub fn value_at(&self, frame: f64) -> Vec3 {
+        let keys = &self.keys;
+        let next = keys.partition_point(|key| key.frame <= frame);
+        if next == 0 {
+            return keys[0].value;
+        }
+        if next == keys.len() {
+            return keys[next - 1].value;
+        }
+
+        let (k0, k1) = (&keys[next - 1], &keys[next]);
+        let t = (frame - k0.frame) / (k1.frame - k0.frame);
+        match k0.interpolation {
+            Interpolation::Linear => lerp(k0.value, k1.value, t),
+            Interpolation::CatmullRom => {
+                // The end keys stand in for the missing neighbours
+                let p0 = keys[next.saturating_sub(2)].value;
+                let p3 = keys[(next + 1).min(keys.len() - 1)].value;
+                let (p1, p2) = (k0.value, k1.value);
+                0.5 * (2.0 * p1
+                    + t * (p2 - p0)
+                    + t * t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3)
+                    + t * t * t * (3.0 * p1 - p0 - 3.0 * p2 + p3))
+            }
+            Interpolation::Bezier { x1, y1, x2, y2 } => {
+                lerp(k0.value, k1.value, ease(t, x1, y1, x2, y2))
+            }
+        }
+    }
+}
+
+fn lerp(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+/// Progress at time `t` along the easing curve with control points (`x1`,`y1`), (`x2`,`y2`)
+fn ease(t: f64, x1: f64, y1: f64, x2: f64, y2: f64) -> f64 {
+    let bezier = |s: f64, p1: f64, p2: f64| {
+        let r = 1.0 - s;
+        3.0 * r * r * s * p1 + 3.0 * r * s * s * p2 + s * s * s
+    };
+    // The curve's x grows with its parameter when both control x are in [0,1], so bisect
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..50 {
+        let mid = 0.5 * (lo + hi);
+        if bezier(mid, x1, x2) < t {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    bezier(0.5 * (lo + hi), y1, y2)
+}
+
+/// Frame range `FIRST-LAST` as given on the command line, both ends possibly negative
+pub fn parse_frame_range(text: &str) -> Option<(i32, i32)> {
+    // The range's dash is the first one after a leading minus sign
+    let dash = text.get(1..)?.find('-')? + 1;
+    let first = text[..dash].parse().ok()?;
+    let last = text[dash + 1..].parse().ok()?;
+    (first <= last).then_some((first, last))
+}
+
+/// Scale, turn and offset of one object, in that order
+struct Transform {
+    scale: f64,
+    angle: f64,
+    offset: Vec3,
+}
+
+impl Animation {
+    pub fn parse(text: &str) -> Result<Animation, String> {
+        let mut animation = Animation::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut tokens = Tokens(line.split_whitespace());
+            let Some(directive) = tokens.0.next() else {
+                continue;
+            };
+            animation
+                .parse_directive(directive, &mut tokens)
+                .and_then(|()| tokens.end())
+                .map_err(|msg| format!("line {}: {msg}", number + 1))?;
+        }
+        Ok(animation)
+    }
+
+    fn parse_directive(&mut self, directive: &str, tokens: &mut Tokens) -> Result<(), String> {
+        match directive {
+            "frames" => {
+                let first = tokens.parse("first frame")?;
+                let last = tokens.parse("last frame")?;
+                if last < first {
+                    return Err("the last frame comes before the first".to_string());
+                }
+                self.frames = Some((first, last));
+            }
+            "key" => {
+                let frame = tokens.number()?;
+                let target = match tokens.word("target")? {
+                    "camera" => match tokens.word("camera setting")? {
+                        "look_from" => Target::LookFrom,
+                        "look_at" => Target::LookAt,
+                        "vfov" => Target::Vfov,
+                        "focus_dist" => Target::FocusDist,
+                        key => return Err(format!("cannot animate camera setting `{key}`")),
+                    },
+                    "object" => {
+                        let index = tokens.parse("object index")?;
+                        match tokens.word("transform")? {
+                            "translate" => Target::Translate(index),
+                            "rotate" => Target::Rotate(index),
+                            "scale" => Target::Scale(index),
+                            kind => return Err(format!("unknown transform `{kind}`")),
+                        }
+                    }
+                    "material" => {
+                        let name = tokens.word("material name")?.to_string();
+                        let param = tokens.word("material parameter")?;
+                        if !matches!(
+                            param,
+                            "albedo"
+                                | "emit"
+                                | "sheen"
+                                | "fuzz"
+                                | "refraction_index"
+                                | "roughness"
+                                | "anisotropy"
+                        ) {
+                            return Err(format!("unknown material parameter `{param}`"));
+                        }
+                        Target::Material {
+                            name,
+                            param: param.to_string(),
+                        }
+                    }
+                    target => return Err(format!("unknown target `{target}`")),
+                };
+
+                let value = if target.is_vector() {
+                    tokens.vec3()?
+                } else {
+                    let value = tokens.number()?;
+                    Vec3 {
+                        x: value,
+                        y: value,
+                        z: value,
+                    }
+                };
+                if !frame.is_finite() || ![value.x, value.y, value.z].iter().all(|v| v.is_finite())
+                {
+                    return Err("key frames and values must be finite".to_string());
+                }
+                if matches!(target, Target::Scale(_)) && value.x <= 0.0 {
+                    return Err("scale must be positive".to_string());
+                }
+
+                let interpolation = match tokens.0.next() {
+                    None => Interpolation::Linear,
+                    Some("bezier") => {
+                        let (x1, y1) = (tokens.number()?, tokens.number()?);
+                        let (x2, y2) = (tokens.number()?, tokens.number()?);
+                        if !(0.0..=1.0).contains(&x1) || !(0.0..=1.0).contains(&x2) {
+                            return Err("bezier control x must be between 0 and 1".to_string());
+                        }
+                        if !y1.is_finite() || !y2.is_finite() {
+                            return Err("bezier control y must be finite".to_string());
+                        }
+                        Interpolation::Bezier { x1, y1, x2, y2 }
+                    }
+                    Some(name) => Interpolation::by_name(name)
+                        .ok_or(format!("unknown interpolation `{name}`"))?,
+                };
+
+                let key = Keyframe {
+                    frame,
+                    value,
+                    interpolation,
+                };
+                match self.tracks.iter_mut().find(|track| track.target == target) {
+                    Some(track) => {
+                        let at = track.keys.partition_point(|k| k.frame <= frame);
+                        track.keys.insert(at, key);
+                    }
+                    None => self.tracks.push(Track {
+                        target,
+                        keys: vec![key],
+                    }),
+                }
+            }
+            _ => return Err(format!("unknown directive `{directive}`")),
+        }
+        Ok(())
+    }
+
+    /// Frames to render: the given range, or from the first key to the last
+    pub fn frame_range(&self) -> RangeInclusive<i32> {
+        if let Some((first, last)) = self.frames {
+            return first..=last;
+        }
+        let frames = self
+            .tracks
+            .iter()
+            .flat_map(|track| &track.keys)
+            .map(|key| key.frame);
+        let first = frames.clone().fold(f64::INFINITY, f64::min);
+        let last = frames.fold(f64::NEG_INFINITY, f64::max);
+        if first > last {
+            return 1..=1;
+        }
+        first.floor() as i32..=last.ceil() as i32
+    }
+
+    /// `scene` as it is at `frame`, with the animated values brought within range
+    pub fn apply(&self, scene: &Scene, frame: f64) -> Result<Scene, String> {
+        let mut scene = scene.clone();
+        let mut transforms: HashMap<usize, Transform> = HashMap::new();
+        for track in &self.tracks {
+            let value = track
+                .target
+                .limit(track.value_at(frame))
+                .map_err(|msg| format!("frame {frame}: {msg}"))?;
+            match &track.target {
+                Target::LookFrom => scene.camera.look_from = value,
+                Target::LookAt => scene.camera.look_at = value,
+                Target::Vfov => scene.camera.vfov = value.x,
+                Target::FocusDist => scene.camera.focus_dist = value.x,
+                &(Target::Translate(index) | Target::Rotate(index) | Target::Scale(index)) => {
+                    if index >= scene.objects.len() {
+                        return Err(format!("there is no object {index}"));
+                    }
+                    let transform = transforms.entry(index).or_insert(Transform {
+                        scale: 1.0,
+                        angle: 0.0,
+                        offset: Vec3::zeros(),
+                    });
+                    match track.target {
+                        Target::Translate(_) => transform.offset = value,
+                        Target::Rotate(_) => transform.angle = deg_to_rad(value.x),
+                        _ => transform.scale = value.x,
+                    }
+                }
+                Target::Material { name, param } => {
+                    let (_, material) = scene
+                        .materials
+                        .iter_mut()
+                        .find(|(material, _)| material == name)
+                        .ok_or(format!("unknown material `{name}`"))?;
+                    if !set_parameter(material, param, value) {
+                        return Err(format!("material `{name}` has no `{param}`"));
+                    }
+                }
+            }
+        }
+
+        for (index, transform) in transforms {
+            let shape = &mut scene.objects[index].shape;
+            let turn = |v: Vec3| {
+                let (sin, cos) = transform.angle.sin_cos();
+                transform.scale
+                    * Vec3 {
+                        x: cos * v.x + sin * v.z,
+                        y: v.y,
+                        z: cos * v.z - sin * v.x,
+                    }
+            };
+            match shape {
+                Shape::Sphere { center, radius } => {
+                    *center += transform.offset;
+                    *radius *= transform.scale;
+                }
+                Shape::Quad { q, u, v } => {
+                    let center: Point3 = *q + 0.5 * (*u + *v);
+                    (*u, *v) = (turn(*u), turn(*v));
+                    *q = center + transform.offset - 0.5 * (*u + *v);
+                }
+            }
+        }
+        Ok(scene)
+    }
+}
+
+/// Set the parameter of `material` a scene file calls `param`, scalars taken from `value.x`.
+/// Returns whether the material has it.
+fn set_parameter(material: &mut MaterialDescription, param: &str, value: Vec3) -> bool {
+    use MaterialDescription as M;
+    match (material, param) {
+        (
+            M::Lambertian { albedo }
+            | M::Metal { albedo, .. }
+            | M::AnisotropicMetal { albedo, .. }
+            | M::Sheen { albedo, .. }
+            | M::Subsurface { albedo, .. },
+            "albedo",
+        ) => *albedo = value,
+        (M::Light { emit }, "emit") => *emit = value,
+        (M::Sheen { sheen, .. }, "sheen") => *sheen = value,
+        (M::Metal { fuzz, .. }, "fuzz") => *fuzz = value.x,
+        (
+            M::Dielectric { refraction_index }
+            | M::Subsurface {
+                refraction_index, ..
+            },
+            "refraction_index",
+        ) => *refraction_index = value.x,
+        (M::AnisotropicMetal { roughness, .. } | M::Sheen { roughness, .. }, "roughness") => {
+            *roughness = value.x
+        }
+        (M::AnisotropicMetal { anisotropy, .. }, "anisotropy") => *anisotropy = value.x,
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scalar(v: f64) -> Vec3 {
+        Vec3 { x: v, y: v, z: v }
+    }
+
+    fn track(keys: &[(f64, f64)], interpolation: Interpolation) -> Track {
+        Track {
+            target: Target::Vfov,
+            keys: keys
+                .iter()
+                .map(|&(frame, value)| Keyframe {
+                    frame,
+                    value: scalar(value),
+                    interpolation,
+                })
+                .collect(),
+        }
+    }
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn linear_tracks_hold_their_ends_and_pass_through_keys() {
+        let track = track(
+            &[(0.0, 0.0), (10.0, 10.0), (20.0, 40.0)],
+            Interpolation::Linear,
+        );
+        for (frame, value) in [
+            (-5.0, 0.0),
+            (0.0, 0.0),
+            (5.0, 5.0),
+            (10.0, 10.0),
+            (15.0, 25.0),
+            (20.0, 40.0),
+            (25.0, 40.0),
+        ] {
+            assert_near(track.value_at(frame).x, value);
+        }
+    }
+
+    #[test]
+    fn catmull_rom_repeats_the_end_keys_for_missing_neighbours() {
+        let track = track(
+            &[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)],
+            Interpolation::CatmullRom,
+        );
+        for frame in [0.0, 1.0, 2.0, 3.0] {
+            assert_near(track.value_at(frame).x, frame);
+        }
+        // Evenly spaced keys on a line stay on it between inner keys
+        assert_near(track.value_at(1.5).x, 1.5);
+        // The first key stands in for the one before it, bending the curve
+        assert_near(track.value_at(0.5).x, 0.4375);
+        assert_near(track.value_at(2.5).x, 2.5625);
+    }
+
+    #[test]
+    fn easing_curves_keep_the_ends_and_bend_between() {
+        let ease = Interpolation::by_name("ease").unwrap();
+        let ease_in_out = Interpolation::by_name("ease-in-out").unwrap();
+        let eased = track(&[(0.0, 0.0), (10.0, 1.0)], ease);
+        let symmetric = track(&[(0.0, 0.0), (10.0, 1.0)], ease_in_out);
+        assert_near(eased.value_at(0.0).x, 0.0);
+        assert_near(eased.value_at(10.0).x, 1.0);
+        // CSS's `ease` is most of the way there by half time
+        assert!((eased.value_at(5.0).x - 0.8024).abs() < 1e-3);
+        assert_near(symmetric.value_at(5.0).x, 0.5);
+        let quarter = symmetric.value_at(2.5).x;
+        assert_near(quarter + symmetric.value_at(7.5).x, 1.0);
+        assert!(quarter < 0.25);
+    }
+
+    #[test]
+    fn keys_are_kept_in_frame_order_and_later_ones_win_ties() {
+        let animation = Animation::parse(
+            "\
+key 20 camera vfov 20
+key 0 camera vfov 0
+key 10 camera vfov 10
+key 10 camera vfov 30
+",
+        )
+        .unwrap();
+        let track = &animation.tracks[0];
+        let frames: Vec<f64> = track.keys.iter().map(|key| key.frame).collect();
+        assert_eq!(frames, [0.0, 10.0, 10.0, 20.0]);
+        // A jump at frame 10
+        assert_near(track.value_at(5.0).x, 5.0);
+        assert_near(track.value_at(10.0).x, 30.0);
+        assert_near(track.value_at(15.0).x, 25.0);
+    }
+
+    #[test]
+    fn values_out_of_range_are_held_or_rejected() {
+        let scene = Scene::parse(
+            "\
+camera look_from 0 0 0 look_at 0 0 -1
+material ball metal 0.5 0.5 0.5 0.1
+sphere 0 0 -1 0.5 ball
+",
+        )
+        .unwrap();
+        // Catmull-Rom overshoots below zero after the drop
+        let animation = Animation::parse(
+            "\
+key 0 material ball albedo 1 1 1 catmull-rom
+key 1 material ball albedo 0 0 0 catmull-rom
+key 2 material ball albedo 0 0 0
+key 0 material ball fuzz -1
+key 4 material ball fuzz 1
+",
+        )
+        .unwrap();
+        assert!(animation.tracks[0].value_at(1.5).x < 0.0);
+        assert!(animation.tracks[1].value_at(1.5).x < 0.0);
+        let frame = animation.apply(&scene, 1.5).unwrap();
+        let MaterialDescription::Metal { albedo, fuzz } = frame.materials[0].1 else {
+            panic!("not a metal");
+        };
+        assert_eq!(albedo.x, 0.0);
+        assert_eq!(fuzz, 0.0);
+
+        let animation = Animation::parse("key 0 camera vfov 40\nkey 10 camera vfov -40\n").unwrap();
+        assert!(animation.apply(&scene, 4.0).is_ok());
+        let err = animation.apply(&scene, 5.0).err().unwrap();
+        assert!(err.contains("frame 5") && err.contains("vfov"), "{err}");
+
+        for key in [
+            "key nan camera vfov 40",
+            "key 0 camera vfov NaN",
+            "key 0 camera look_at 0 inf 0",
+        ] {
+            let err = Animation::parse(key).unwrap_err();
+            assert!(err.contains("finite"), "{err}");
+        }
+    }
+
+    #[test]
+    fn frame_ranges_may_be_negative() {
+        assert_eq!(parse_frame_range("1-48"), Some((1, 48)));
+        assert_eq!(parse_frame_range("-5-10"), Some((-5, 10)));
+        assert_eq!(parse_frame_range("-10--5"), Some((-10, -5)));
+        assert_eq!(parse_frame_range("3-3"), Some((3, 3)));
+        for invalid in ["", "5", "-5", "10-5", "1-", "-1-", "a-b"] {
+            assert_eq!(parse_frame_range(invalid), None, "{invalid}");
+        }
+    }
+}
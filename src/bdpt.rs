@@ -0,0 +1,579 @@
+use std::f64::consts::PI;
+
+use crate::{
+    camera::Camera,
+    color::Color,
+    geometry::{Interval, Onb, Point3, Ray, Vec3},
+    hittable::{HitRecord, Hittable},
+    hittable_list::HittableList,
+    integrator::{trace, Integrator, RenderContext},
+    sampler::Sampler,
+};
+
+/// Bidirectional path tracer.
+///
+/// Traces a subpath from the camera and one from a light in `world.lights`, then
+/// connects every prefix of one to every prefix of the other, weighting each strategy
+/// with the balance heuristic. Connections straight to the lens (light tracing) are
+/// splatted onto the film, which is what resolves caustics seen through glass.
+///
+/// The background is only found by camera subpaths that escape, as in `PathTracer`.
+/// Subpaths are cut short by Russian roulette after `rr_min_depth` bounces.
+pub struct Bdpt {
+    pub max_depth: i32,
+    pub rr_min_depth: i32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    p: Point3,
+    /// Surface normal; only its line matters. For the camera, the view direction.
+    n: Vec3,
+    /// Hit record for surface and light vertices
+    rec: Option<HitRecord>,
+    /// Ray that arrived at this vertex while the subpath was generated
+    r_in: Ray,
+    beta: Color,
+    /// Area density of generating this vertex from its predecessor on the same subpath
+    pdf_fwd: f64,
+    /// Area density of generating this vertex from the other direction
+    pdf_rev: f64,
+    /// Scattered specularly, so it cannot be connected to
+    delta: bool,
+    /// Area pdf of choosing this point when starting a light subpath (emitters only)
+    light_pdf_pos: f64,
+}
+
+impl Vertex {
+    fn camera(ray: &Ray, forward: Vec3) -> Vertex {
+        Vertex {
+            kind: VertexKind::Camera,
+            p: ray.orig,
+            n: forward,
+            rec: None,
+            r_in: *ray,
+            beta: Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            light_pdf_pos: 0.0,
+        }
+    }
+
+    fn light(rec: HitRecord, beta: Color, pdf_pos: f64) -> Vertex {
+        Vertex {
+            kind: VertexKind::Light,
+            p: rec.p,
+            n: rec.normal,
+            r_in: Ray {
+                orig: rec.p,
+                dir: -rec.normal,
+            },
+            rec: Some(rec),
+            beta,
+            pdf_fwd: pdf_pos,
+            pdf_rev: 0.0,
+            delta: false,
+            light_pdf_pos: pdf_pos,
+        }
+    }
+
+    fn surface(rec: HitRecord, r_in: Ray, beta: Color) -> Vertex {
+        Vertex {
+            kind: VertexKind::Surface,
+            p: rec.p,
+            n: rec.normal,
+            rec: Some(rec),
+            r_in,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            light_pdf_pos: 0.0,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        self.kind != VertexKind::Camera
+    }
+
+    /// Turn a solid-angle density at this vertex into an area density at `next`
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let distance_squared = w.length_squared();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+
+        let mut pdf = pdf / distance_squared;
+        if next.on_surface() {
+            pdf *= Vec3::dot(next.n, w / distance_squared.sqrt()).abs();
+        }
+        pdf
+    }
+
+    /// BSDF times cosine for light travelling between this vertex and `next`,
+    /// the other direction being the one the subpath arrived from
+    fn f(&self, next: &Vertex) -> Color {
+        match &self.rec {
+            Some(rec) if self.kind == VertexKind::Surface => {
+                rec.mat.eval(&self.r_in, rec, next.p - self.p)
+            }
+            _ => Color::zeros(),
+        }
+    }
+
+    /// Radiance emitted from this vertex towards `to`
+    fn le(&self, to: &Vertex) -> Color {
+        let Some(rec) = &self.rec else {
+            return Color::zeros();
+        };
+
+        let w = to.p - self.p;
+        let mut rec = rec.clone();
+        if Vec3::dot(rec.normal, w) < 0.0 {
+            rec.normal = -rec.normal;
+            rec.front_face = !rec.front_face;
+        }
+        rec.mat.emitted(&rec)
+    }
+
+    /// Area density at `next` of sampling it from this vertex, having arrived from `prev`
+    fn pdf(&self, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        match self.kind {
+            VertexKind::Light => self.pdf_light(next),
+            VertexKind::Camera => {
+                self.convert_density(camera.pdf_direction(self.p, next.p - self.p), next)
+            }
+            VertexKind::Surface => {
+                let (Some(rec), Some(prev)) = (&self.rec, prev) else {
+                    return 0.0;
+                };
+                let r_in = Ray {
+                    orig: prev.p,
+                    dir: self.p - prev.p,
+                };
+                self.convert_density(rec.mat.pdf(&r_in, rec, next.p - self.p), next)
+            }
+        }
+    }
+
+    /// Area density at `next` of emitting towards it from this (emissive) vertex
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let w = Vec3::unit_vector(next.p - self.p);
+        let pdf_dir = Vec3::dot(self.n, w).abs() / PI;
+        self.convert_density(pdf_dir, next)
+    }
+}
+
+impl Bdpt {
+    /// Extend `path` by following BSDF samples from `ray` until it holds `max_vertices`.
+    /// `pdf_fwd` is the solid-angle pdf of `ray`'s direction at the last vertex.
+    /// Returns the throughput and ray of a subpath that escaped the scene.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk(
+        &self,
+        world: &HittableList,
+        mut ray: Ray,
+        mut beta: Color,
+        mut pdf_fwd: f64,
+        max_vertices: usize,
+        path: &mut Vec<Vertex>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        while path.len() < max_vertices {
+            let Some(rec) = trace(world, &ray) else {
+                return Some((beta, ray));
+            };
+
+            let mut vertex = Vertex::surface(rec.clone(), ray, beta);
+            let prev = path.len() - 1;
+            vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+            if rec.mat.emitted(&rec).max_component() > 0.0 {
+                vertex.light_pdf_pos = Self::light_pdf_pos(world, &ray, &rec);
+            }
+            path.push(vertex);
+
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let Some(scatter_result) = rec.mat.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+
+            let cur = path.len() - 1;
+            let pdf_rev = if scatter_result.pdf > 0.0 {
+                let reverse = Ray {
+                    orig: scatter_result.scattered.at(1.0),
+                    dir: -scatter_result.scattered.dir,
+                };
+                pdf_fwd = scatter_result.pdf;
+                rec.mat.pdf(&reverse, &rec, -ray.dir)
+            } else {
+                path[cur].delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            };
+
+            beta = beta * scatter_result.attenuation;
+            path[prev].pdf_rev = path[cur].convert_density(pdf_rev, &path[prev]);
+            ray = scatter_result.scattered;
+
+            // Roulette only changes throughput; MIS weights still sum to one without it
+            if path.len() as i32 > self.rr_min_depth {
+                let survive = f64::min(beta.max_component(), 0.95);
+                if sampler.next_f64() >= survive {
+                    break;
+                }
+                beta /= survive;
+            }
+        }
+
+        None
+    }
+
+    /// Probability of a light subpath starting at the emitter `rec` hit by `ray`:
+    /// the chance of choosing that light times its area pdf, or 0 if it is not a sampled light
+    fn light_pdf_pos(world: &HittableList, ray: &Ray, rec: &HitRecord) -> f64 {
+        let window = Interval {
+            min: rec.t * (1.0 - 1e-6),
+            max: rec.t * (1.0 + 1e-6),
+        };
+        world
+            .lights
+            .iter()
+            .find(|light| light.hit(ray, &window).is_some())
+            .map_or(0.0, |light| {
+                light.surface_pdf(rec.p) / world.lights.len() as f64
+            })
+    }
+
+    fn light_subpath(&self, world: &HittableList, sampler: &mut dyn Sampler) -> Vec<Vertex> {
+        let mut path = Vec::new();
+        let Some((rec, pdf_pos)) = world.sample_light_surface(sampler) else {
+            return path;
+        };
+        if pdf_pos <= 0.0 {
+            return path;
+        }
+
+        // Cosine-weighted emission around the outward normal
+        let frame = Onb::from_w(rec.normal);
+        let dir = frame.local(Vec3::rand_cosine_direction(sampler));
+        let cos_theta = Vec3::dot(dir, rec.normal);
+        let pdf_dir = cos_theta / PI;
+
+        // Sampled records face outwards, the side the emission leaves from
+        let le = rec.mat.alpha(&rec) * rec.mat.emitted(&rec);
+        path.push(Vertex::light(rec, le, pdf_pos));
+
+        if pdf_dir <= 0.0 || le.max_component() <= 0.0 {
+            return path;
+        }
+
+        let beta = (cos_theta / (pdf_pos * pdf_dir)) * le;
+        let ray = Ray {
+            orig: path[0].p,
+            dir,
+        };
+        self.random_walk(
+            world,
+            ray,
+            beta,
+            pdf_dir,
+            self.max_depth as usize + 1,
+            &mut path,
+            sampler,
+        );
+        path
+    }
+
+    /// Whether nothing blocks the segment between `a` and `b`
+    fn visible(world: &HittableList, a: Point3, b: Point3) -> bool {
+        let dir = b - a;
+        let len = dir.length();
+        if len <= 0.002 {
+            return true;
+        }
+
+        world
+            .hit(
+                &Ray { orig: a, dir },
+                &Interval {
+                    min: 0.001 / len,
+                    max: 1.0 - 0.001 / len,
+                },
+            )
+            .is_none()
+    }
+
+    /// Contribution of the strategy using `s` light and `t` camera vertices, plus the
+    /// raster position to splat it at when `t == 1`
+    fn connect(
+        world: &HittableList,
+        camera: &Camera,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Option<(f64, f64)>)> {
+        let mut sampled: Option<Vertex> = None;
+        let mut raster = None;
+
+        let contribution = if s == 0 {
+            // The camera subpath hit an emitter on its own
+            let pt = &camera_path[t - 1];
+            pt.beta * pt.le(&camera_path[t - 2])
+        } else if t == 1 {
+            // Connect a light subpath vertex straight to the lens
+            let qs = &light_path[s - 1];
+            if qs.delta {
+                return None;
+            }
+
+            let cs = camera.sample_lens(qs.p, sampler)?;
+            if cs.pdf <= 0.0 || !Self::visible(world, qs.p, cs.lens_point) {
+                return None;
+            }
+            raster = Some(cs.raster);
+
+            let mut vertex = Vertex::camera(
+                &Ray {
+                    orig: cs.lens_point,
+                    dir: qs.p - cs.lens_point,
+                },
+                camera.forward(),
+            );
+            let we = cs.importance / cs.pdf;
+            vertex.beta = Color {
+                x: we,
+                y: we,
+                z: we,
+            };
+            let contribution = qs.beta * qs.f(&vertex) * vertex.beta;
+            sampled = Some(vertex);
+            contribution
+        } else if s == 1 {
+            // Connect a camera subpath vertex to a freshly sampled light point
+            let pt = &camera_path[t - 1];
+            if pt.delta {
+                return None;
+            }
+
+            let (rec, pdf_pos) = world.sample_light_surface(sampler)?;
+            let coverage = rec.mat.alpha(&rec);
+            let mut vertex = Vertex::light(rec, Color::zeros(), pdf_pos);
+
+            let w = pt.p - vertex.p;
+            let distance_squared = w.length_squared();
+            let cos_light = Vec3::dot(vertex.n, w).abs() / distance_squared.sqrt();
+            if pdf_pos <= 0.0 || cos_light <= 0.0 {
+                return None;
+            }
+
+            // Solid-angle pdf of the light point as seen from `pt`
+            let pdf = pdf_pos * distance_squared / cos_light;
+            vertex.beta = coverage * vertex.le(pt) / pdf;
+            if vertex.beta.max_component() <= 0.0 || !Self::visible(world, pt.p, vertex.p) {
+                return None;
+            }
+
+            let contribution = pt.beta * pt.f(&vertex) * vertex.beta;
+            sampled = Some(vertex);
+            contribution
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if qs.delta || pt.delta {
+                return None;
+            }
+
+            let distance_squared = (qs.p - pt.p).length_squared();
+            let contribution = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta / distance_squared;
+            if contribution.max_component() <= 0.0 || !Self::visible(world, qs.p, pt.p) {
+                return None;
+            }
+            contribution
+        };
+
+        if contribution.max_component() <= 0.0 {
+            return None;
+        }
+
+        let weight = Self::mis_weight(camera, light_path, camera_path, sampled, s, t);
+        Some((weight * contribution, raster))
+    }
+
+    /// Balance heuristic weight of strategy (`s`,`t`) against every other way of sampling
+    /// the same path, computed from the ratios of reverse to forward vertex densities
+    fn mis_weight(
+        camera: &Camera,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let mut light: Vec<Vertex> = light_path[..s].to_vec();
+        let mut cam: Vec<Vertex> = camera_path[..t].to_vec();
+
+        // An emitter outside `world.lights` can only ever be found by hitting it
+        if s == 0 && cam[t - 1].light_pdf_pos <= 0.0 {
+            return 1.0;
+        }
+
+        if let Some(vertex) = sampled {
+            if s == 1 {
+                light[0] = vertex;
+            } else if t == 1 {
+                cam[0] = vertex;
+            }
+        }
+
+        // Connection vertices are never specular for this strategy
+        cam[t - 1].delta = false;
+        if s > 0 {
+            light[s - 1].delta = false;
+        }
+
+        // Densities of the connection vertices as if sampled from the other side
+        let pt_pdf_rev = if s > 0 {
+            let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+            light[s - 1].pdf(camera, qs_minus, &cam[t - 1])
+        } else {
+            cam[t - 1].light_pdf_pos
+        };
+        let pt_minus_pdf_rev = if t > 1 {
+            Some(if s > 0 {
+                cam[t - 1].pdf(camera, Some(&light[s - 1]), &cam[t - 2])
+            } else {
+                cam[t - 1].pdf_light(&cam[t - 2])
+            })
+        } else {
+            None
+        };
+        let qs_pdf_rev = if s > 0 {
+            let pt_minus = if t > 1 { Some(&cam[t - 2]) } else { None };
+            Some(cam[t - 1].pdf(camera, pt_minus, &light[s - 1]))
+        } else {
+            None
+        };
+        let qs_minus_pdf_rev = if s > 1 {
+            Some(light[s - 1].pdf(camera, Some(&cam[t - 1]), &light[s - 2]))
+        } else {
+            None
+        };
+
+        cam[t - 1].pdf_rev = pt_pdf_rev;
+        if let Some(pdf) = pt_minus_pdf_rev {
+            cam[t - 2].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_pdf_rev {
+            light[s - 1].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_minus_pdf_rev {
+            light[s - 2].pdf_rev = pdf;
+        }
+
+        let remap0 = |f: f64| if f != 0.0 { f } else { 1.0 };
+        let mut sum_ri = 0.0;
+
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(cam[i].pdf_rev) / remap0(cam[i].pdf_fwd);
+            if !cam[i].delta && !cam[i - 1].delta {
+                sum_ri += ri;
+            }
+        }
+
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(light[i].pdf_rev) / remap0(light[i].pdf_fwd);
+            let delta_light_vertex = i > 0 && light[i - 1].delta;
+            if !light[i].delta && !delta_light_vertex {
+                sum_ri += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum_ri)
+    }
+}
+
+impl Integrator for Bdpt {
+    fn renders_regions(&self) -> bool {
+        false
+    }
+
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let camera = ctx.camera;
+        let mut radiance = Color::zeros();
+
+        let mut camera_path = vec![Vertex::camera(r, camera.forward())];
+        // Light subpaths cannot reach a lens that camera rays do not all pass through, so the
+        // camera vertex counts as specular and strategies connecting to it get no weight
+        camera_path[0].delta = !camera.connects_to_lens();
+        let pdf_dir = camera.pdf_direction(r.orig, r.dir);
+        if let Some((beta, escaped)) = self.random_walk(
+            world,
+            *r,
+            camera_path[0].beta,
+            pdf_dir,
+            self.max_depth as usize + 2,
+            &mut camera_path,
+            sampler,
+        ) {
+            radiance += beta * world.background.value(&escaped);
+        }
+
+        let light_path = self.light_subpath(world, sampler);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = (s + t) as i32 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth {
+                    continue;
+                }
+
+                let Some((contribution, raster)) =
+                    Self::connect(world, camera, &light_path, &camera_path, s, t, sampler)
+                else {
+                    continue;
+                };
+
+                match raster {
+                    Some((i, j)) => {
+                        ctx.splats
+                            .add(i.floor() as i32, j.floor() as i32, contribution)
+                    }
+                    None => radiance += contribution,
+                }
+            }
+        }
+
+        radiance
+    }
+}
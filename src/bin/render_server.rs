@@ -0,0 +1,561 @@
+//! HTTP service rendering scene files in the background, one job at a time.
+//!
+//! cargo run --release --bin render_server -- --listen 127.0.0.1:8080
+//!
+//! POST   /jobs?spp=64&integrator=path   scene file as the body; answers with the new job's id
+//! GET    /jobs                          status of every job
+//! GET    /jobs/ID                       status and progress of one job
+//! GET    /jobs/ID/preview               PNG of the latest progressive snapshot
+//! GET    /jobs/ID/image                 final PNG, once the job is done
+//! DELETE /jobs/ID                       cancel the job, or forget it once finished
+//!
+//! Render settings are taken from the query string: integrator, spp, seed, sampler, filter,
+//! filter_radius and max_depth, named as on the command line and defaulting to
+//! `RenderSettings::default()`. Scenes may not read files (lens, aperture image).
+//!
+//! Finished jobs, with their images, are forgotten an hour after they finish, or sooner once
+//! more than 100 have finished.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use raytracing::{
+    camera::{RenderStats, SnapshotInterval, StopReason},
+    image::Image,
+    progress::CancellationToken,
+    scene::{RenderSettings, Scene},
+};
+
+/// Largest scene file accepted
+const MAX_BODY: usize = 16 << 20;
+
+/// Largest image accepted, in pixels
+const MAX_PIXELS: i64 = 16 << 20;
+
+/// Most samples per pixel accepted
+const MAX_SPP: i32 = 1 << 16;
+
+/// Deepest paths accepted
+const MAX_DEPTH: i32 = 1000;
+
+/// Widest filter accepted, in pixels
+const MAX_FILTER_RADIUS: f64 = 8.0;
+
+/// Most jobs waiting to render at once
+const MAX_QUEUED_JOBS: usize = 64;
+
+/// Most finished jobs kept, with their images, before the oldest are forgotten
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// How long a finished job is kept
+const FINISHED_JOB_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Most connections served at once
+const MAX_CONNECTIONS: usize = 64;
+
+/// Longest a client may take to send its request or read the response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+enum JobState {
+    Queued,
+    Rendering,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+impl JobState {
+    fn is_finished(&self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Rendering)
+    }
+}
+
+struct Job {
+    id: u64,
+    scene: Scene,
+    settings: RenderSettings,
+    cancellation: CancellationToken,
+    status: Mutex<JobStatus>,
+}
+
+struct JobStatus {
+    state: JobState,
+    /// Fraction rendered, in \[0,1\]
+    progress: f64,
+    submitted: Instant,
+    /// When the job was done, cancelled or failed
+    finished: Option<Instant>,
+    /// Latest snapshot, or the final image once done, as PNG
+    preview: Option<Arc<Vec<u8>>>,
+    /// Final image as PNG
+    image: Option<Arc<Vec<u8>>>,
+    stats: Option<RenderStats>,
+}
+
+/// Jobs known to the service and the queue of those waiting to render
+struct Service {
+    jobs: Mutex<Vec<Arc<Job>>>,
+    queue: Mutex<VecDeque<Arc<Job>>>,
+    queued: Condvar,
+    next_id: AtomicU64,
+    /// Seconds between preview snapshots
+    preview_interval: f64,
+}
+
+impl Service {
+    /// Queue a job, unless too many are waiting already
+    fn submit(&self, scene: Scene, settings: RenderSettings) -> Option<Arc<Job>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_QUEUED_JOBS {
+            return None;
+        }
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            scene,
+            settings,
+            cancellation: CancellationToken::new(),
+            status: Mutex::new(JobStatus {
+                state: JobState::Queued,
+                progress: 0.0,
+                submitted: Instant::now(),
+                finished: None,
+                preview: None,
+                image: None,
+                stats: None,
+            }),
+        });
+        self.jobs.lock().unwrap().push(Arc::clone(&job));
+        queue.push_back(Arc::clone(&job));
+        self.queued.notify_one();
+        Some(job)
+    }
+
+    /// Forget finished jobs past their lifetime, and the oldest beyond `MAX_FINISHED_JOBS`
+    fn evict(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut finished: Vec<(Instant, u64)> = jobs
+            .iter()
+            .filter_map(|job| Some((job.status.lock().unwrap().finished?, job.id)))
+            .collect();
+        finished.sort();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_JOBS);
+        let evicted: Vec<u64> = finished
+            .iter()
+            .enumerate()
+            .filter(|&(i, (at, _))| i < excess || at.elapsed() > FINISHED_JOB_LIFETIME)
+            .map(|(_, &(_, id))| id)
+            .collect();
+        jobs.retain(|job| !evicted.contains(&job.id));
+    }
+
+    fn finish(&self, job: &Job, state: JobState) {
+        let mut status = job.status.lock().unwrap();
+        status.state = state;
+        status.finished = Some(Instant::now());
+    }
+
+    fn job(&self, id: u64) -> Option<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Render queued jobs one after another, forever
+    fn run_jobs(&self) {
+        loop {
+            let job = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    match queue.pop_front() {
+                        Some(job) => break job,
+                        None => queue = self.queued.wait(queue).unwrap(),
+                    }
+                }
+            };
+            if job.cancellation.is_cancelled() {
+                self.finish(&job, JobState::Cancelled);
+                self.evict();
+                continue;
+            }
+
+            job.status.lock().unwrap().state = JobState::Rendering;
+            // A panicking render fails its job rather than the queue behind it
+            let state = match panic::catch_unwind(AssertUnwindSafe(|| self.render(&job))) {
+                Ok(Ok(StopReason::Cancelled)) => JobState::Cancelled,
+                Ok(Ok(_)) => JobState::Done,
+                Ok(Err(e)) => JobState::Failed(e.to_string()),
+                Err(payload) => {
+                    let msg = payload
+                        .downcast_ref::<&str>()
+                        .map(|msg| msg.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown cause".to_string());
+                    JobState::Failed(format!("render panicked: {msg}"))
+                }
+            };
+            self.finish(&job, state);
+            self.evict();
+        }
+    }
+
+    fn render(&self, job: &Arc<Job>) -> std::io::Result<StopReason> {
+        let invalid_input = |msg| Error::new(ErrorKind::InvalidInput, msg);
+        let world = job.scene.build_world().map_err(invalid_input)?;
+        let mut camera = job
+            .scene
+            .build_camera(&job.settings)
+            .map_err(invalid_input)?;
+
+        let progress_job = Arc::clone(job);
+        camera.set_progress_callback(Some(Arc::new(move |progress| {
+            progress_job.status.lock().unwrap().progress = progress.fraction();
+        })));
+        camera.set_cancellation_token(Some(job.cancellation.clone()));
+        camera.set_progressive(Some(SnapshotInterval::Seconds(self.preview_interval)));
+        camera.set_quiet(true);
+
+        let (image, stats) = camera.render_to_image(Arc::new(world), &mut |image| {
+            if let Ok(png) = encode_png(&image) {
+                job.status.lock().unwrap().preview = Some(Arc::new(png));
+            }
+        })?;
+
+        let png = Arc::new(encode_png(&image)?);
+        let mut status = job.status.lock().unwrap();
+        status.preview = Some(Arc::clone(&png));
+        status.image = Some(png);
+        if stats.stop_reason != StopReason::Cancelled {
+            status.progress = 1.0;
+        }
+        status.stats = Some(stats);
+        Ok(stats.stop_reason)
+    }
+
+    /// Answer one request, given its method, path with query string, and body
+    fn handle(&self, method: &str, target: &str, body: &[u8]) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("POST", ["jobs"]) => {
+                let Ok(text) = std::str::from_utf8(body) else {
+                    return Response::error(400, "scene file is not UTF-8");
+                };
+                let settings = match parse_settings(query) {
+                    Ok(settings) => settings,
+                    Err(msg) => return Response::error(400, &msg),
+                };
+                // Catch mistakes now rather than when the job comes up
+                let scene = match Scene::parse(text).and_then(|scene| {
+                    // Not even whether a file exists is the client's business
+                    if !scene.camera.files().is_empty() {
+                        return Err("scenes may not read files".to_string());
+                    }
+                    let camera = scene.build_camera(&settings)?;
+                    if camera.image_width() as i64 * camera.image_height() as i64 > MAX_PIXELS {
+                        return Err(format!("image is larger than {MAX_PIXELS} pixels"));
+                    }
+                    Ok(scene)
+                }) {
+                    Ok(scene) => scene,
+                    Err(msg) => return Response::error(400, &msg),
+                };
+                match self.submit(scene, settings) {
+                    Some(job) => Response::json(201, job_json(&job)),
+                    None => Response::error(503, "too many jobs waiting"),
+                }
+            }
+            ("GET", ["jobs"]) => {
+                let jobs = self.jobs.lock().unwrap();
+                let list: Vec<String> = jobs.iter().map(|job| job_json(job)).collect();
+                Response::json(200, format!("[{}]", list.join(",")))
+            }
+            (_, ["jobs", id, rest @ ..]) => {
+                let Some(job) = id.parse().ok().and_then(|id| self.job(id)) else {
+                    return Response::error(404, "no such job");
+                };
+                match (method, rest) {
+                    ("GET", []) => Response::json(200, job_json(&job)),
+                    ("GET", ["preview"]) => match &job.status.lock().unwrap().preview {
+                        Some(png) => Response::png(png),
+                        None => Response::error(404, "no preview yet"),
+                    },
+                    ("GET", ["image"]) => match &job.status.lock().unwrap().image {
+                        Some(png) => Response::png(png),
+                        None => Response::error(409, "job has not finished"),
+                    },
+                    ("DELETE", []) => {
+                        if job.status.lock().unwrap().state.is_finished() {
+                            self.jobs.lock().unwrap().retain(|other| other.id != job.id);
+                        } else {
+                            job.cancellation.cancel();
+                        }
+                        Response::json(200, job_json(&job))
+                    }
+                    _ => Response::error(405, "method not allowed"),
+                }
+            }
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    fn serve(&self, stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Response::error(400, "malformed request").write(stream);
+        };
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    match value.trim().parse() {
+                        Ok(len) => content_length = len,
+                        Err(_) => return Response::error(400, "invalid length").write(stream),
+                    }
+                }
+            }
+        }
+        if content_length > MAX_BODY {
+            return Response::error(413, "scene file too large").write(stream);
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        self.handle(method, target, &body).write(stream)
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Arc<Vec<u8>>,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: Arc::new(body.into_bytes()),
+        }
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response::json(status, format!("{{\"error\":{}}}", json_string(msg)))
+    }
+
+    fn png(png: &Arc<Vec<u8>>) -> Response {
+        Response {
+            status: 200,
+            content_type: "image/png",
+            body: Arc::clone(png),
+        }
+    }
+
+    fn write(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+/// Render settings from a query string like `spp=64&integrator=path`
+fn parse_settings(query: &str) -> Result<RenderSettings, String> {
+    let mut settings = RenderSettings::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let invalid = || format!("invalid {key} `{value}`");
+        match key {
+            "integrator" => settings.integrator = value.to_string(),
+            "spp" => {
+                settings.samples_per_pixel = value
+                    .parse()
+                    .ok()
+                    .filter(|spp| (1..=MAX_SPP).contains(spp))
+                    .ok_or_else(invalid)?;
+            }
+            "seed" => settings.seed = value.parse().map_err(|_| invalid())?,
+            "sampler" => settings.sampler = value.to_string(),
+            "filter" => settings.filter = value.to_string(),
+            "filter_radius" => {
+                let radius: f64 = value.parse().map_err(|_| invalid())?;
+                if !(radius > 0.0 && radius <= MAX_FILTER_RADIUS) {
+                    return Err(invalid());
+                }
+                settings.filter_radius = Some(radius);
+            }
+            "max_depth" => {
+                settings.max_depth = value
+                    .parse()
+                    .ok()
+                    .filter(|depth| (1..=MAX_DEPTH).contains(depth))
+                    .ok_or_else(invalid)?;
+            }
+            _ => return Err(format!("unknown setting `{key}`")),
+        }
+    }
+    Ok(settings)
+}
+
+fn job_json(job: &Job) -> String {
+    let status = job.status.lock().unwrap();
+    let state = match &status.state {
+        JobState::Queued => "queued",
+        JobState::Rendering => "rendering",
+        JobState::Done => "done",
+        JobState::Cancelled => "cancelled",
+        JobState::Failed(_) => "failed",
+    };
+
+    let mut json = format!(
+        "{{\"id\":{},\"state\":\"{state}\",\"progress\":{:.4},\"age\":{:.1}",
+        job.id,
+        status.progress,
+        status.submitted.elapsed().as_secs_f64()
+    );
+    if let JobState::Failed(msg) = &status.state {
+        write!(json, ",\"error\":{}", json_string(msg)).unwrap();
+    }
+    if let Some(stats) = &status.stats {
+        write!(
+            json,
+            ",\"samples_per_pixel\":{:.2},\"render_time\":{:.2},\"stop_reason\":{}",
+            stats.samples_per_pixel,
+            stats.elapsed.as_secs_f64(),
+            json_string(&stats.stop_reason.to_string())
+        )
+        .unwrap();
+    }
+    json.push('}');
+    json
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// One of the `MAX_CONNECTIONS` connections being served, given back when dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn encode_png(image: &Image) -> std::io::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    image.write_png(&mut png)?;
+    Ok(png)
+}
+
+fn main() -> std::io::Result<()> {
+    let mut listen = String::from("127.0.0.1:8080");
+    let mut preview_interval = 2.0;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let usage = |msg: &str| Error::new(ErrorKind::InvalidInput, msg.to_string());
+        match arg.as_str() {
+            "--listen" => listen = args.next().ok_or(usage("--listen needs an address"))?,
+            "--preview-every" => {
+                let value = args
+                    .next()
+                    .ok_or(usage("--preview-every needs a value in seconds"))?;
+                preview_interval = value
+                    .parse()
+                    .ok()
+                    .filter(|&seconds: &f64| seconds > 0.0)
+                    .ok_or(usage(&format!("invalid preview interval `{value}`")))?;
+            }
+            _ => return Err(usage(&format!("unknown argument `{arg}`"))),
+        }
+    }
+
+    let listener = TcpListener::bind(&listen)?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+
+    let service = Arc::new(Service {
+        jobs: Mutex::new(Vec::new()),
+        queue: Mutex::new(VecDeque::new()),
+        queued: Condvar::new(),
+        next_id: AtomicU64::new(1),
+        preview_interval,
+    });
+    let renderer = Arc::clone(&service);
+    thread::spawn(move || renderer.run_jobs());
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        // Running out of file descriptors or a client hanging up early is no reason to stop
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Accept failed: {e}");
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::Relaxed);
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let _ = Response::error(503, "too many connections").write(stream);
+            continue;
+        }
+        let service = Arc::clone(&service);
+        let slot = ConnectionSlot(Arc::clone(&connections));
+        thread::spawn(move || {
+            if let Err(e) = service.serve(stream) {
+                eprintln!("Request failed: {e}");
+            }
+            drop(slot);
+        });
+    }
+    Ok(())
+}
@@ -1,37 +1,247 @@
 use crate::{
-    color::Color,
-    geometry::{deg_to_rad, Interval, Point3, Ray, Vec3},
-    hittable::Hittable,
-    hittable_list::HittableList,
-    util::rand_f64,
+    filter::Filter,
+    geometry::{deg_to_rad, Point3, Ray, Vec3},
+    image::Image,
+    integrator::{Integrator, PathTracer},
+    lens::{Aperture, LensSystem},
+    progress::{CancellationToken, ProgressCallback},
+    sampler::{Sampler, SamplerKind},
+    tiles::TileOrder,
 };
 
-use rayon::prelude::*;
-use std::{
-    fs::OpenOptions,
-    io::{stdout, Write},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-};
+use std::{f64::consts::PI, sync::Arc};
+
+mod adaptive;
+mod checkpoint;
+mod render;
+
+pub use adaptive::AdaptiveSampling;
+pub use checkpoint::Checkpoints;
+pub use render::{RenderStats, SnapshotInterval, StopConditions, StopReason};
 
 pub struct Camera {
+    /// Size of the rendered image, which holds both eyes' views in stereo
     image_width: i32,
     image_height: i32,
+    /// Size of the view of one eye
+    view_width: i32,
+    view_height: i32,
     cam_center: Point3,
     pixel00_loc: Point3,
     pixel_delta_u: Vec3,
     pixel_delta_v: Vec3,
     samples_per_pixel: i32,
-    pixel_samples_scale: f64,
-    max_depth: i32,
+    integrator: Arc<dyn Integrator>,
     defocus_angle: f64,
     defocus_disc_u: Vec3,
     defocus_disc_v: Vec3,
+    defocus_radius: f64,
+    aperture: Aperture,
+    /// How far the lens barrel's circle shifts across the aperture at the image corners, in
+    /// aperture radii
+    cat_eye: f64,
+    lens_system: Option<LensSystem>,
+    f_number: Option<f64>,
+    exposure: Exposure,
+    focus_dist: f64,
+    forward: Vec3,
+    /// Unit vectors to the right of and up from the view direction
+    right: Vec3,
+    up: Vec3,
+    vfov: f64,
+    projection: Projection,
+    stereo: Option<Stereo>,
+    seed: u64,
+    sampler: SamplerKind,
+    adaptive: Option<AdaptiveSampling>,
+    filter: Filter,
+    snapshots: Option<SnapshotInterval>,
+    stop: StopConditions,
+    checkpoints: Option<Checkpoints>,
+    resume_from: Option<String>,
+    tile_size: i32,
+    tile_order: TileOrder,
+    progress: Option<ProgressCallback>,
+    quiet: bool,
+    cancellation: Option<CancellationToken>,
+}
+
+/// How camera rays leave the camera for each point of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// Rays spread from the lens through the image, which spans the vertical field of view
+    Perspective,
+    /// Parallel rays along the view direction, from an image `view_height` scene units tall
+    /// centered on the look-from point. Depth of field still focuses at the focus distance.
+    Orthographic { view_height: f64 },
+    /// Full latitude-longitude panorama: 360 degrees across the image and 180 degrees down it,
+    /// centered on the view direction. Meant for 2:1 images.
+    Equirectangular,
+    /// Panorama around the up direction spanning `hfov` degrees across the image (360 for a
+    /// full turn), and as far up and down as square pixels make it
+    Cylindrical { hfov: f64 },
+    /// Circular fisheye filling the shorter side of the image, seeing `fov` degrees across
+    /// (up to 360); pixels outside the circle stay black
+    Fisheye { mapping: FisheyeMapping, fov: f64 },
+}
+
+/// Two eyes' views rendered into one image for stereoscopic display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stereo {
+    /// Distance between the eyes, which sit either side of the look-from point
+    pub interocular: f64,
+    /// Distance in front of the eyes at which their views meet, so things there appear at
+    /// the depth of the screen. Omni-directional stereo ignores it.
+    pub convergence: f64,
+    /// How the eyes of perspective and orthographic cameras look at the convergence
+    /// distance. Panoramic projections render omni-directional stereo instead.
+    pub rig: StereoRig,
+    pub layout: StereoLayout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoRig {
+    /// Eyes looking straight ahead, with image windows shifted to meet at the convergence
+    /// distance (off-axis projection)
+    Parallel,
+    /// Eyes turned inwards to look at the point at the convergence distance. Simple, but
+    /// adds vertical parallax towards the image corners.
+    ToedIn,
+}
+
+impl StereoRig {
+    /// Look up a rig by its command line name
+    pub fn by_name(name: &str) -> Option<StereoRig> {
+        match name {
+            "parallel" => Some(StereoRig::Parallel),
+            "toed-in" => Some(StereoRig::ToedIn),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StereoRig::Parallel => "parallel",
+            StereoRig::ToedIn => "toed-in",
+        }
+    }
+}
+
+/// Where the two eyes' views go in the image, the left eye's first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// Left eye on the left, right eye on the right
+    SideBySide,
+    /// Left eye above the right eye, as VR players expect of stereo panoramas
+    TopBottom,
+}
+
+impl StereoLayout {
+    /// Look up a layout by its command line name
+    pub fn by_name(name: &str) -> Option<StereoLayout> {
+        match name {
+            "side-by-side" => Some(StereoLayout::SideBySide),
+            "top-bottom" => Some(StereoLayout::TopBottom),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StereoLayout::SideBySide => "side-by-side",
+            StereoLayout::TopBottom => "top-bottom",
+        }
+    }
+}
+
+/// How distance from a fisheye image's center grows with the angle off the view direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FisheyeMapping {
+    /// In proportion to the angle, as in most fisheye lenses and dome masters
+    Equidistant,
+    /// So that equal solid angles cover equal image areas
+    EqualArea,
+}
+
+impl FisheyeMapping {
+    /// Look up a mapping by its command line name
+    pub fn by_name(name: &str) -> Option<FisheyeMapping> {
+        match name {
+            "equidistant" => Some(FisheyeMapping::Equidistant),
+            "equal-area" => Some(FisheyeMapping::EqualArea),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FisheyeMapping::Equidistant => "equidistant",
+            FisheyeMapping::EqualArea => "equal-area",
+        }
+    }
+}
+
+/// How rendered radiance turns into image values
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Exposure {
+    pub mode: ExposureMode,
+    /// Stops of extra exposure, each doubling the image's brightness
+    pub compensation: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExposureMode {
+    /// Image values are the radiance itself
+    #[default]
+    Radiance,
+    /// Exposed as a camera with this sensitivity and shutter time in seconds, and the
+    /// camera's f-number, would be; radiance counts as luminance in cd/m^2. Uses the
+    /// saturation-based exposure value, so a sensor saturates at 1.2 times the exposure.
+    Manual { iso: f64, shutter: f64 },
+    /// Scaled so the image's log-average luminance comes out middle grey (0.18)
+    Auto,
+}
+
+impl Exposure {
+    /// Factor the pixels of `image` are scaled by, with the camera at `f_number`
+    fn scale(&self, image: &Image, f_number: Option<f64>) -> f64 {
+        let scale = match self.mode {
+            ExposureMode::Radiance => 1.0,
+            ExposureMode::Manual { iso, shutter } => {
+                // Exposure value at ISO 100 is log2(N^2 / t) - log2(S / 100)
+                let f_number = f_number.unwrap_or(f64::INFINITY);
+                iso * shutter / (1.2 * 100.0 * f_number * f_number)
+            }
+            ExposureMode::Auto => {
+                // Offset so black pixels do not drag the average to zero
+                const DELTA: f64 = 1e-4;
+                let log_sum: f64 = image
+                    .pixels
+                    .iter()
+                    .map(|pixel| (DELTA + pixel.luminance().max(0.0)).ln())
+                    .sum();
+                let log_average = (log_sum / image.pixels.len().max(1) as f64).exp();
+                0.18 / log_average
+            }
+        };
+        scale * self.compensation.exp2()
+    }
+}
+
+/// A connection from a scene point to the camera lens, for light tracing
+pub struct CameraSample {
+    /// Point on the lens the connection ends at
+    pub lens_point: Point3,
+    /// Continuous raster position (`i`,`j`) the point projects to
+    pub raster: (f64, f64),
+    /// Importance carried by the connection
+    pub importance: f64,
+    /// Solid-angle pdf of choosing `lens_point`, as seen from the scene point
+    pub pdf: f64,
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         aspect_ratio: f64,
         image_width: i32,
@@ -44,190 +254,560 @@ impl Camera {
         focus_dist: f64,
     ) -> Camera {
         let samples_per_pixel = 500;
-        let pixel_samples_scale = 1.0 / samples_per_pixel as f64;
         let image_height = f64::max(image_width as f64 / aspect_ratio, 1.0) as i32;
 
-        let center = look_from;
-
-        // Determine viewport dimensions
-        let theta = deg_to_rad(vfov);
-        let h = (theta / 2.0).tan();
-        let viewport_height = 2.0 * h * focus_dist;
-        let viewport_width = viewport_height * (image_width as f64 / image_height as f64);
-
         // Calculate u,v,w unit basis vectors for camera coordinate frame
         let w = Vec3::unit_vector(look_from - look_at);
         let u = Vec3::unit_vector(Vec3::cross(vup, w));
         let v = Vec3::cross(w, u);
 
-        // Vectors along horizontal and vertical edges of viewport
-        let viewport_u = viewport_width * u;
-        let viewport_v = viewport_height * -v;
-
-        // Calculate the horizontal and vertical delta vectors between each pixel
-        let pixel_delta_u = viewport_u / image_width as f64;
-        let pixel_delta_v = viewport_v / image_height as f64;
-
-        // Calculate location of upper left pixel
-        let viewport_upper_left = center - (focus_dist * w) - viewport_u / 2.0 - viewport_v / 2.0;
-        let pixel00_loc = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
-
         // Calculate defocus disc basis vectors
         let defocus_radius = focus_dist * deg_to_rad(defocus_angle / 2.0).tan();
         let defocus_disc_u = u * defocus_radius;
         let defocus_disc_v = v * defocus_radius;
 
-        return Camera {
+        let mut camera = Camera {
             image_width,
             image_height,
-            cam_center: center,
-            pixel00_loc,
-            pixel_delta_u,
-            pixel_delta_v,
+            view_width: image_width,
+            view_height: image_height,
+            cam_center: look_from,
+            pixel00_loc: Point3::zeros(),
+            pixel_delta_u: Vec3::zeros(),
+            pixel_delta_v: Vec3::zeros(),
             samples_per_pixel,
-            pixel_samples_scale,
-            max_depth,
+            integrator: Arc::new(PathTracer {
+                max_depth,
+                rr_min_depth: 5,
+            }),
             defocus_angle,
             defocus_disc_u,
             defocus_disc_v,
+            defocus_radius,
+            aperture: Aperture::Circle,
+            cat_eye: 0.0,
+            lens_system: None,
+            f_number: None,
+            exposure: Exposure::default(),
+            focus_dist,
+            forward: -w,
+            right: u,
+            up: v,
+            vfov,
+            projection: Projection::Perspective,
+            stereo: None,
+            seed: 0,
+            sampler: SamplerKind::Random,
+            adaptive: None,
+            filter: Filter::BOX,
+            snapshots: None,
+            stop: StopConditions::default(),
+            checkpoints: None,
+            resume_from: None,
+            tile_size: 16,
+            tile_order: TileOrder::Spiral,
+            progress: None,
+            quiet: false,
+            cancellation: None,
         };
+        camera.place_viewport();
+        camera
     }
 
-    pub fn render(&self, world: Arc<HittableList>, path: &str) -> Result<(), std::io::Error> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-
-        let w = self.image_width;
-        let h = self.image_height;
-        write!(file, "P3\n{w} {h}\n255\n")?;
-
-        let progress = Arc::new(AtomicUsize::new(0)); // Progress counter
-
-        let rows: Vec<String> = (0..h)
-            .into_par_iter()
-            .map(|j| {
-                let mut row = String::new();
-                for i in 0..w {
-                    let mut pixel_color = Color {
-                        x: 0.0,
-                        y: 0.0,
-                        z: 0.0,
-                    };
-                    for _ in 0..self.samples_per_pixel {
-                        let r = self.get_ray(i, j);
-                        pixel_color += Camera::ray_color(&r, self.max_depth, &world);
-                    }
-                    row.push_str(&format!(
-                        "{}",
-                        (self.pixel_samples_scale * pixel_color).to_string()
-                    ));
-                }
+    /// Lay the image out on the plane of focus, facing the camera, as large as the projection
+    /// makes it
+    fn place_viewport(&mut self) {
+        // Determine viewport dimensions
+        let viewport_height = match self.projection {
+            Projection::Orthographic { view_height } => view_height,
+            // Panoramas map pixels to directions themselves and leave the viewport unused
+            _ => {
+                let theta = deg_to_rad(self.vfov);
+                let h = (theta / 2.0).tan();
+                2.0 * h * self.focus_dist
+            }
+        };
+        let viewport_width = viewport_height * (self.view_width as f64 / self.view_height as f64);
 
-                // Update and display progress
-                let completed = progress.fetch_add(1, Ordering::Relaxed) + 1;
-                if completed % 10 == 0 || completed == h as usize {
-                    // Reduce printing frequency
-                    let percent = (completed as f64 / h as f64) * 100.0;
-                    print!("\rRendering: {:.2}%", percent);
-                    stdout().flush().unwrap();
-                }
+        // Vectors along horizontal and vertical edges of viewport
+        let viewport_u = viewport_width * self.right;
+        let viewport_v = viewport_height * -self.up;
+
+        // Calculate the horizontal and vertical delta vectors between each pixel
+        self.pixel_delta_u = viewport_u / self.view_width as f64;
+        self.pixel_delta_v = viewport_v / self.view_height as f64;
+
+        // Calculate location of upper left pixel
+        let w = -self.forward;
+        let viewport_upper_left =
+            self.cam_center - (self.focus_dist * w) - viewport_u / 2.0 - viewport_v / 2.0;
+        self.pixel00_loc = viewport_upper_left + 0.5 * (self.pixel_delta_u + self.pixel_delta_v);
+    }
+
+    /// Choose how rays leave the camera (perspective by default). The camera keeps looking
+    /// from the same point in the same direction.
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.place_viewport();
+    }
 
-                row
-            })
-            .collect();
+    /// Render a stereo pair, or an omni-directional stereo panorama, into one image holding
+    /// both eyes' views (mono by default). The image grows to fit the second view.
+    pub fn set_stereo(&mut self, stereo: Option<Stereo>) -> Result<(), String> {
+        if let Some(stereo) = stereo {
+            if !stereo.interocular.is_finite() {
+                return Err("stereo interocular distance must be finite".to_string());
+            }
+            if !(stereo.convergence.is_finite() && stereo.convergence > 0.0) {
+                return Err("stereo convergence must be positive".to_string());
+            }
+        }
+        self.stereo = stereo;
+        (self.image_width, self.image_height) = match stereo.map(|stereo| stereo.layout) {
+            None => (self.view_width, self.view_height),
+            Some(StereoLayout::SideBySide) => (2 * self.view_width, self.view_height),
+            Some(StereoLayout::TopBottom) => (self.view_width, 2 * self.view_height),
+        };
+        Ok(())
+    }
+
+    /// Shape the defocus disc, and the stop of a lens system, take (round by default)
+    pub fn set_aperture(&mut self, aperture: Aperture) {
+        self.aperture = aperture;
+    }
+
+    /// Open the thin lens's aperture to focal length over `f_number`, taking the focal length
+    /// a film `film_height` scene units tall gives the vertical field of view. This replaces
+    /// the defocus angle; with a lens system the f-number only sets the exposure.
+    pub fn set_f_number(&mut self, f_number: f64, film_height: f64) {
+        let focal_length = film_height / (2.0 * (deg_to_rad(self.vfov) / 2.0).tan());
+        let radius = focal_length / (2.0 * f_number);
+        self.f_number = Some(f_number);
+        self.defocus_radius = radius;
+        self.defocus_angle = 2.0 * (radius / self.focus_dist).atan().to_degrees();
+        self.defocus_disc_u = radius * self.right;
+        self.defocus_disc_v = radius * self.up;
+    }
 
-        // Write all rows sequentially to file
-        for row in rows {
-            write!(file, "{}", row)?;
+    /// Choose how radiance is exposed into image values (radiance as is by default). Manual
+    /// exposure needs an f-number.
+    pub fn set_exposure(&mut self, exposure: Exposure) -> Result<(), String> {
+        if let (ExposureMode::Manual { .. }, None) = (exposure.mode, self.f_number) {
+            return Err("manual exposure needs an f-number".to_string());
         }
+        self.exposure = exposure;
+        Ok(())
+    }
 
-        print!("\rDone                      \n");
+    /// Cut off the aperture towards the image's edges as the lens barrel does, so bokeh turns
+    /// into cat's eyes and the corners darken; `strength` is how far the barrel's circle moves
+    /// across the aperture at the corners, in aperture radii (0, no vignetting, by default)
+    pub fn set_cat_eye(&mut self, strength: f64) {
+        self.cat_eye = strength;
+    }
 
+    /// Trace perspective camera rays through a real lens instead of an ideal thin one, with
+    /// its front surface at the look-from point. The lens is focused at the focus distance;
+    /// its focal length and film size decide the field of view, and its stop the depth of
+    /// field, in place of the vertical field of view and defocus angle. Rays the lens blocks
+    /// leave the image darker, towards the edges in particular, as with a real lens.
+    pub fn set_lens_system(&mut self, lens: Option<LensSystem>) -> Result<(), String> {
+        if let Some(mut lens) = lens {
+            if !lens.focus(self.focus_dist / lens.scale()) {
+                return Err(format!(
+                    "the lens cannot focus at distance {}",
+                    self.focus_dist
+                ));
+            }
+            self.lens_system = Some(lens);
+        } else {
+            self.lens_system = None;
+        }
         Ok(())
     }
 
-    /// Get a ray from camera defocus disc to target pixel at (`i`,`j`) plus some offset
-    fn get_ray(&self, i: i32, j: i32) -> Ray {
-        let offset = Camera::sample_square();
-        let pixel_sample = self.pixel00_loc
-            + ((i as f64 + offset.x) * self.pixel_delta_u)
-            + ((j as f64 + offset.y) * self.pixel_delta_v);
+    /// Whether light paths can be connected to the lens with `sample_lens`: only with a mono
+    /// perspective projection through a thin lens, whose rays all pass through the one lens.
+    /// Cat's-eye vignetting leaves some samples without a camera ray to carry such light.
+    pub fn connects_to_lens(&self) -> bool {
+        self.projection == Projection::Perspective
+            && self.stereo.is_none()
+            && self.lens_system.is_none()
+            && self.cat_eye <= 0.0
+    }
+
+    /// Replace the light transport algorithm (a `PathTracer` with `max_depth` by default)
+    pub fn set_integrator(&mut self, integrator: Arc<dyn Integrator>) {
+        self.integrator = integrator;
+    }
+
+    /// Choose the seed every pixel sample's random numbers derive from (0 by default).
+    /// Renders with the same seed and settings are identical.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Choose how pixel samples draw their random numbers (independent random by default)
+    pub fn set_sampler(&mut self, sampler: SamplerKind) {
+        self.sampler = sampler;
+    }
+
+    /// Samples taken in every pixel, or at most in any pixel with adaptive sampling
+    pub fn set_samples_per_pixel(&mut self, samples_per_pixel: i32) {
+        self.samples_per_pixel = samples_per_pixel.max(1);
+    }
+
+    /// Stop sampling pixels once their error is low enough (off by default).
+    /// Light splatted onto the image (light tracing in BDPT, MLT) is not part of the error
+    /// estimate, so those integrators gain little from it.
+    pub fn set_adaptive_sampling(&mut self, adaptive: Option<AdaptiveSampling>) {
+        self.adaptive = adaptive;
+    }
+
+    /// Choose how samples are weighted into the pixels around them (a box over each pixel by default)
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Write the image so far to the output path at this interval while rendering (off by default)
+    pub fn set_progressive(&mut self, snapshots: Option<SnapshotInterval>) {
+        self.snapshots = snapshots;
+    }
 
-        let orig = if self.defocus_angle <= 0.0 {
+    /// End renders early on a time budget or error target (neither by default)
+    pub fn set_stop_conditions(&mut self, stop: StopConditions) {
+        self.stop = stop;
+    }
+
+    /// Save checkpoints while rendering, and once more at the end (off by default)
+    pub fn set_checkpoints(&mut self, checkpoints: Option<Checkpoints>) {
+        self.checkpoints = checkpoints;
+    }
+
+    /// Continue the render saved in the checkpoint at `path` instead of starting afresh.
+    /// The camera, sampler, filter and integrator must be set up as they were for that render;
+    /// only with the random sampler may the samples per pixel differ.
+    pub fn set_resume_from(&mut self, path: Option<String>) {
+        self.resume_from = path;
+    }
+
+    /// Split every pass into tiles of `size` pixels square, handed out in `order`
+    /// (16 pixel tiles in a spiral from the center by default)
+    pub fn set_tiles(&mut self, size: i32, order: TileOrder) {
+        self.tile_size = size.max(1);
+        self.tile_order = order;
+    }
+
+    /// Report progress to `progress` as tiles finish, instead of printing it to stderr
+    pub fn set_progress_callback(&mut self, progress: Option<ProgressCallback>) {
+        self.progress = progress;
+    }
+
+    /// Print nothing while rendering (progress is printed to stderr by default)
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Stop the render early once `token` is cancelled
+    pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation = token;
+    }
+
+    pub fn image_width(&self) -> i32 {
+        self.image_width
+    }
+
+    pub fn image_height(&self) -> i32 {
+        self.image_height
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn integrator(&self) -> &dyn Integrator {
+        self.integrator.as_ref()
+    }
+
+    /// Area of the image rectangle scaled to unit distance from the lens
+    fn image_area_at_unit_distance(&self) -> f64 {
+        let width = self.pixel_delta_u.length() * self.view_width as f64;
+        let height = self.pixel_delta_v.length() * self.view_height as f64;
+        width * height / (self.focus_dist * self.focus_dist)
+    }
+
+    /// Density per unit area of `defocus_disc_sample` choosing `lens_point`, or 1 for a
+    /// pinhole so importance stays finite
+    fn lens_density(&self, lens_point: Point3) -> f64 {
+        if self.defocus_angle <= 0.0 {
+            return 1.0;
+        }
+        let (x, y) = self.aperture_position(lens_point);
+        self.aperture.pdf(x, y) / (self.defocus_radius * self.defocus_radius)
+    }
+
+    /// Position of `lens_point` in the aperture's unit coordinates
+    fn aperture_position(&self, lens_point: Point3) -> (f64, f64) {
+        let offset = (lens_point - self.cam_center) / self.defocus_radius;
+        (Vec3::dot(offset, self.right), Vec3::dot(offset, self.up))
+    }
+
+    /// Whether the lens barrel blocks light between `lens_point` and raster position
+    /// (`x`,`y`). Seen from further off the image's center, the barrel's front opening moves
+    /// further off the aperture, the opposite way.
+    fn vignetted(&self, lens_point: Point3, x: f64, y: f64) -> bool {
+        if self.cat_eye <= 0.0 || self.defocus_angle <= 0.0 {
+            return false;
+        }
+        let (w, h) = (self.view_width as f64, self.view_height as f64);
+        let shift = self.cat_eye / (0.5 * w.hypot(h));
+        let (a, b) = self.aperture_position(lens_point);
+        (a + shift * (x - 0.5 * w)).hypot(b + shift * (0.5 * h - y)) > 1.0
+    }
+
+    /// Raster position of the camera ray leaving `lens_point` along `dir`, if it lands in the image
+    fn raster_position(&self, lens_point: Point3, dir: Vec3) -> Option<(f64, f64)> {
+        let cos_theta = Vec3::dot(dir, self.forward);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+
+        // Where the ray meets the plane of focus, relative to the image's upper left corner
+        let focus_point = lens_point + (self.focus_dist / cos_theta) * dir;
+        let upper_left = self.pixel00_loc - 0.5 * (self.pixel_delta_u + self.pixel_delta_v);
+        let local = focus_point - upper_left;
+
+        let i = Vec3::dot(local, self.pixel_delta_u) / self.pixel_delta_u.length_squared();
+        let j = Vec3::dot(local, self.pixel_delta_v) / self.pixel_delta_v.length_squared();
+        if i < 0.0 || j < 0.0 || i >= self.view_width as f64 || j >= self.view_height as f64 {
+            return None;
+        }
+
+        Some((i, j))
+    }
+
+    /// Solid-angle pdf of `get_ray_at` generating direction `dir` from the lens, 0 unless the
+    /// camera `connects_to_lens`
+    pub fn pdf_direction(&self, lens_point: Point3, dir: Vec3) -> f64 {
+        let dir = Vec3::unit_vector(dir);
+        if !self.connects_to_lens() || self.raster_position(lens_point, dir).is_none() {
+            return 0.0;
+        }
+
+        let cos_theta = Vec3::dot(dir, self.forward);
+        1.0 / (self.image_area_at_unit_distance() * cos_theta * cos_theta * cos_theta)
+    }
+
+    /// Pick a lens point visible from scene point `p` and return the importance it carries
+    /// and the pixel it lands on, or `None` if `p` is outside the view or the camera does not
+    /// `connects_to_lens`
+    pub fn sample_lens(&self, p: Point3, sampler: &mut dyn Sampler) -> Option<CameraSample> {
+        if !self.connects_to_lens() {
+            return None;
+        }
+        let lens_point = if self.defocus_angle <= 0.0 {
             self.cam_center
         } else {
-            self.defocus_disc_sample()
+            self.defocus_disc_sample(sampler)
         };
-        let dir = pixel_sample - orig;
 
-        Ray { orig, dir }
+        let to_point = p - lens_point;
+        let distance_squared = to_point.length_squared();
+        let dir = to_point / distance_squared.sqrt();
+        let raster = self.raster_position(lens_point, dir)?;
+
+        let cos_theta = Vec3::dot(dir, self.forward);
+        let cos2 = cos_theta * cos_theta;
+        let density = self.lens_density(lens_point);
+        let importance = density / (self.image_area_at_unit_distance() * cos2 * cos2);
+        let pdf = distance_squared * density / cos_theta;
+
+        Some(CameraSample {
+            lens_point,
+            raster,
+            importance,
+            pdf,
+        })
     }
 
-    /// Vector to random point in \[-.5,-.5\] - \[+.5,+.5\] unit square.
-    fn sample_square() -> Vec3 {
-        Vec3 {
-            x: rand_f64() - 0.5,
-            y: rand_f64() - 0.5,
-            z: 0.0,
-        }
+    /// Direction the camera looks along
+    pub fn forward(&self) -> Vec3 {
+        self.forward
     }
 
-    /// Returns a random point in the camera's defocus disc
-    fn defocus_disc_sample(&self) -> Point3 {
-        let p = Vec3::rand_in_unit_disc();
-        self.cam_center + (p.x * self.defocus_disc_u) + (p.y * self.defocus_disc_v)
+    /// Get a ray from camera defocus disc through continuous raster position (`x`,`y`),
+    /// where pixel (`i`,`j`) covers \[i,i+1) x \[j,j+1). An orthographic camera moves the
+    /// disc in front of the pixel; panoramas look out from the camera center. `None` where the
+    /// projection sees nothing (outside a fisheye's circle) or the lens blocks the ray. The
+    /// ray comes with the weight its radiance carries to the film, 1 but through a lens system.
+    pub fn get_ray_at(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<(Ray, f64)> {
+        let Some(stereo) = self.stereo else {
+            return self.view_ray(x, y, sampler);
+        };
+
+        // The left eye's view comes first, then the right eye's
+        let (w, h) = (self.view_width as f64, self.view_height as f64);
+        let (eye, x, y) = match stereo.layout {
+            StereoLayout::SideBySide if x >= w => (1.0, x - w, y),
+            StereoLayout::TopBottom if y >= h => (1.0, x, y - h),
+            _ => (-1.0, x, y),
+        };
+        let (ray, weight) = self.view_ray(x, y, sampler)?;
+        Some((self.eye_ray(&stereo, eye, ray), weight))
     }
 
-    /// Calculate color for the ray based on what in the world it hits.
-    /// Recursively bounces until `depth` is reached.
-    fn ray_color(r: &Ray, depth: i32, world: &HittableList) -> Color {
-        if depth <= 0 {
-            return Color {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            };
+    /// Ray through raster position (`x`,`y`) of the view from between the eyes
+    fn view_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<(Ray, f64)> {
+        if let (Projection::Perspective, Some(lens)) = (self.projection, &self.lens_system) {
+            return self.lens_system_ray(lens, x, y, sampler);
         }
+        let pixel_sample =
+            self.pixel00_loc + ((x - 0.5) * self.pixel_delta_u) + ((y - 0.5) * self.pixel_delta_v);
 
-        match world.hit(
-            r,
-            &Interval {
-                min: 0.001,
-                max: f64::INFINITY,
-            },
-        ) {
-            Some(hit_record) => match hit_record.mat.scatter(r, &hit_record) {
-                Some(scatter_result) => {
-                    return scatter_result.attenuation
-                        * Self::ray_color(&scatter_result.scattered, depth - 1, world);
+        // A pinhole's disc has zero radius; sampling it anyway keeps every camera
+        // consuming the same sample dimensions
+        let orig = self.defocus_disc_sample(sampler);
+        let orig = match self.projection {
+            Projection::Perspective if self.vignetted(orig, x, y) => return None,
+            Projection::Perspective => orig,
+            // Every pixel looks straight ahead through a lens of its own on the camera plane
+            Projection::Orthographic { .. } => {
+                orig + (pixel_sample - self.focus_dist * self.forward - self.cam_center)
+            }
+            _ => {
+                let dir = self.panorama_direction(x, y)?;
+                return Some((
+                    Ray {
+                        orig: self.cam_center,
+                        dir,
+                    },
+                    1.0,
+                ));
+            }
+        };
+        let dir = pixel_sample - orig;
+
+        Some((Ray { orig, dir }, 1.0))
+    }
+
+    /// Move `ray` of the view from between the eyes to the left (`eye` -1) or right (`eye` 1) eye
+    fn eye_ray(&self, stereo: &Stereo, eye: f64, ray: Ray) -> Ray {
+        let half = 0.5 * eye * stereo.interocular;
+        match (self.projection, stereo.rig) {
+            // The eye's image window shifts against the eye (an off-axis frustum), so both
+            // windows coincide at the convergence distance
+            (Projection::Perspective | Projection::Orthographic { .. }, StereoRig::Parallel) => {
+                let offset = half * self.right;
+                Ray {
+                    orig: ray.orig + offset,
+                    dir: ray.dir - (self.focus_dist / stereo.convergence) * offset,
                 }
-                None => {
-                    return Color {
-                        x: 0.0,
-                        y: 0.0,
-                        z: 0.0,
-                    }
+            }
+            // The whole camera turns about the eye to look at the convergence point
+            (Projection::Perspective | Projection::Orthographic { .. }, StereoRig::ToedIn) => {
+                let (sin, cos) = (-half / stereo.convergence).atan().sin_cos();
+                let turn = |v: Vec3| {
+                    let (r, f) = (Vec3::dot(v, self.right), Vec3::dot(v, self.forward));
+                    v + (r * (cos - 1.0) + f * sin) * self.right
+                        + (f * (cos - 1.0) - r * sin) * self.forward
+                };
+                Ray {
+                    orig: self.cam_center + half * self.right + turn(ray.orig - self.cam_center),
+                    dir: turn(ray.dir),
                 }
-            },
-            None => {}
+            }
+            // Omni-directional stereo: the eyes sit on a circle around the center, and each ray
+            // leaves from the point of it facing sideways to where the ray heads. Rays straight
+            // up or down have no heading and leave from the center.
+            _ => {
+                let r = Vec3::dot(ray.dir, self.right);
+                let f = Vec3::dot(ray.dir, self.forward);
+                let heading = r.hypot(f);
+                if heading == 0.0 {
+                    return ray;
+                }
+                let side = (f / heading) * self.right - (r / heading) * self.forward;
+                Ray {
+                    orig: ray.orig + half * side,
+                    dir: ray.dir,
+                }
+            }
         }
+    }
 
-        let unit_direction = Vec3::unit_vector(r.dir);
-        let a = 0.5 * (unit_direction.y + 1.0);
-
-        return (1.0 - a)
-            * Color {
-                x: 1.0,
-                y: 1.0,
-                z: 1.0,
+    /// Direction a panoramic projection sees at raster position (`x`,`y`)
+    fn panorama_direction(&self, x: f64, y: f64) -> Option<Vec3> {
+        let (w, h) = (self.view_width as f64, self.view_height as f64);
+        // Longitude to the right of the view direction and latitude above it
+        let (longitude, latitude) = match self.projection {
+            Projection::Equirectangular => ((x / w - 0.5) * 2.0 * PI, (0.5 - y / h) * PI),
+            Projection::Cylindrical { hfov } => {
+                let hfov = deg_to_rad(hfov);
+                let height = (0.5 - y / h) * h * hfov / w;
+                ((x / w - 0.5) * hfov, height.atan())
+            }
+            Projection::Fisheye { mapping, fov } => {
+                let radius = 0.5 * f64::min(w, h);
+                let (dx, dy) = ((x - 0.5 * w) / radius, (0.5 * h - y) / radius);
+                let r = dx.hypot(dy);
+                if r > 1.0 {
+                    return None;
+                }
+                let half_fov = deg_to_rad(fov.min(360.0)) / 2.0;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => r * half_fov,
+                    FisheyeMapping::EqualArea => 2.0 * (r * (half_fov / 2.0).sin()).asin(),
+                };
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                let (dx, dy) = if r > 0.0 {
+                    (dx / r, dy / r)
+                } else {
+                    (0.0, 0.0)
+                };
+                return Some(
+                    cos_theta * self.forward + sin_theta * (dx * self.right + dy * self.up),
+                );
             }
-            + a * Color {
-                x: 0.5,
-                y: 0.7,
-                z: 1.0,
-            };
+            _ => unreachable!("not a panoramic projection"),
+        };
+
+        let (sin_lon, cos_lon) = longitude.sin_cos();
+        let (sin_lat, cos_lat) = latitude.sin_cos();
+        Some(cos_lat * (sin_lon * self.right + cos_lon * self.forward) + sin_lat * self.up)
+    }
+
+    /// Returns a random point in the camera's defocus disc, within the aperture's shape
+    fn defocus_disc_sample(&self, sampler: &mut dyn Sampler) -> Point3 {
+        let (x, y) = self.aperture.sample(sampler);
+        self.cam_center + (x * self.defocus_disc_u) + (y * self.defocus_disc_v)
+    }
+
+    /// Ray through raster position (`x`,`y`) traced through `lens` from the film, with its weight
+    fn lens_system_ray(
+        &self,
+        lens: &LensSystem,
+        x: f64,
+        y: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, f64)> {
+        // The lens turns the image upside down and back to front on the film
+        let (w, h) = (self.view_width as f64, self.view_height as f64);
+        let (film_width, film_height) = lens.film_size(w, h);
+        let film_x = (0.5 - x / w) * film_width;
+        let film_y = (y / h - 0.5) * film_height;
+
+        let (orig, dir, weight) = lens.camera_ray(film_x, film_y, sampler, &self.aperture)?;
+        let to_world = |v: Vec3| v.x * self.right + v.y * self.up + v.z * self.forward;
+        Some((
+            Ray {
+                orig: self.cam_center + lens.scale() * to_world(orig),
+                dir: to_world(dir),
+            },
+            weight,
+        ))
     }
 }
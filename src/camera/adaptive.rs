@@ -0,0 +1,152 @@
+use rayon::prelude::*;
+
+use super::Camera;
+use crate::{color::Color, image::Image};
+
+/// Settings for sampling each pixel only until its estimate is precise enough.
+/// The camera's samples per pixel become the maximum any pixel takes.
+#[derive(Debug, Clone)]
+pub struct AdaptiveSampling {
+    /// Samples every pixel takes before its error is trusted
+    pub min_samples: i32,
+    /// Standard error of a pixel's mean luminance, relative to that mean, at which it stops.
+    /// The per-sample variance behind it is pooled over the surrounding 5x5 pixels.
+    pub target_error: f64,
+    /// Where to write an image of the samples each pixel took, brighter meaning more
+    pub debug_image: Option<String>,
+}
+
+/// Running sums of the samples taken in one pixel, for adaptive sampling and error estimates
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PixelState {
+    pub(super) luminance_sum: f64,
+    pub(super) luminance_squared_sum: f64,
+    pub(super) samples: i32,
+}
+
+impl PixelState {
+    /// Mean luminance below which the error is measured against this floor instead,
+    /// so near-black pixels are not sampled forever over invisible noise
+    const DARK: f64 = 0.01;
+
+    /// Pixels on each side of a pixel whose samples also estimate its variance. A pixel's own
+    /// first samples can all miss a small light and look converged; its neighbours rarely all do.
+    const NEIGHBOURHOOD: usize = 2;
+
+    pub(super) fn new() -> PixelState {
+        PixelState {
+            luminance_sum: 0.0,
+            luminance_squared_sum: 0.0,
+            samples: 0,
+        }
+    }
+
+    pub(super) fn add(&mut self, c: Color) {
+        let y = c.luminance();
+        self.luminance_sum += y;
+        self.luminance_squared_sum += y * y;
+        self.samples += 1;
+    }
+
+    /// Mean and variance of a single sample's luminance around pixel (`i`,`j`), pooled over
+    /// the pixel's neighbourhood, or `None` before there are two samples to measure them from
+    fn neighbourhood_statistics(
+        rows: &[Vec<PixelState>],
+        i: usize,
+        j: usize,
+    ) -> Option<(f64, f64)> {
+        let (mut n, mut sum, mut squared_sum) = (0.0, 0.0, 0.0);
+        for row in &rows
+            [j.saturating_sub(Self::NEIGHBOURHOOD)..rows.len().min(j + Self::NEIGHBOURHOOD + 1)]
+        {
+            for pixel in &row
+                [i.saturating_sub(Self::NEIGHBOURHOOD)..row.len().min(i + Self::NEIGHBOURHOOD + 1)]
+            {
+                n += pixel.samples as f64;
+                sum += pixel.luminance_sum;
+                squared_sum += pixel.luminance_squared_sum;
+            }
+        }
+        if n < 2.0 {
+            return None;
+        }
+
+        let mean = sum / n;
+        let variance = f64::max(0.0, (squared_sum - n * mean * mean) / (n - 1.0));
+        Some((mean, variance))
+    }
+
+    /// Standard error of the mean luminance of pixel (`i`,`j`) relative to that mean
+    fn relative_error(rows: &[Vec<PixelState>], i: usize, j: usize) -> f64 {
+        let own = rows[j][i].samples as f64;
+        match PixelState::neighbourhood_statistics(rows, i, j) {
+            Some((mean, variance)) if own >= 1.0 => {
+                (variance / own).sqrt() / f64::max(mean, PixelState::DARK)
+            }
+            _ => f64::INFINITY,
+        }
+    }
+
+    /// Estimated mean squared error of the pixels' mean luminances: the variance of each
+    /// pixel's mean, averaged over the image. Infinite while some pixel has no samples.
+    pub(super) fn estimated_mse(rows: &[Vec<PixelState>]) -> f64 {
+        let pixels = rows.iter().map(|row| row.len()).sum::<usize>().max(1);
+        let total: f64 = (0..rows.len())
+            .into_par_iter()
+            .map(|j| {
+                (0..rows[j].len())
+                    .map(|i| {
+                        let own = rows[j][i].samples as f64;
+                        match PixelState::neighbourhood_statistics(rows, i, j) {
+                            Some((_, variance)) if own >= 1.0 => variance / own,
+                            _ => f64::INFINITY,
+                        }
+                    })
+                    .sum::<f64>()
+            })
+            .sum();
+        total / pixels as f64
+    }
+
+    /// Which pixels adaptive sampling still wants samples in (all of them without it)
+    pub(super) fn needs_samples(
+        rows: &[Vec<PixelState>],
+        adaptive: Option<&AdaptiveSampling>,
+    ) -> Vec<Vec<bool>> {
+        (0..rows.len())
+            .into_par_iter()
+            .map(|j| {
+                (0..rows[j].len())
+                    .map(|i| match adaptive {
+                        None => true,
+                        Some(adaptive) => {
+                            rows[j][i].samples < adaptive.min_samples
+                                || PixelState::relative_error(rows, i, j) > adaptive.target_error
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Camera {
+    /// Write a gray image of the samples each pixel took, white for the maximum, as PNG if
+    /// `path` ends in `.png` and as PPM otherwise
+    pub(super) fn write_sample_counts(
+        &self,
+        rows: &[Vec<PixelState>],
+        path: &str,
+    ) -> std::io::Result<()> {
+        let mut image = Image::new(self.image_width, self.image_height);
+        for (j, row) in rows.iter().enumerate() {
+            for (i, pixel) in row.iter().enumerate() {
+                // Squared, so after gamma the gray level is proportional to the count
+                let fraction = pixel.samples as f64 / self.samples_per_pixel as f64;
+                let v = fraction * fraction;
+                image.set(i as i32, j as i32, Color { x: v, y: v, z: v });
+            }
+        }
+        image.save(path)
+    }
+}
@@ -0,0 +1,165 @@
+use std::{fs, io::Write};
+
+use super::{adaptive::PixelState, render::SnapshotInterval, Camera, Projection};
+use crate::{
+    checkpoint::{invalid_data, StateReader, StateWriter},
+    film::{Film, SplatFilm},
+    sampler::SamplerKind,
+    util::replace_file,
+};
+
+/// Where and how often a render saves checkpoints it can be resumed from. A checkpoint holds
+/// the linear framebuffer, per-pixel sample counts and the integrator's state after a pass;
+/// samplers restart from the pass index, so resuming gives the same image as not stopping.
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    pub path: String,
+    pub interval: SnapshotInterval,
+}
+
+impl Camera {
+    const CHECKPOINT_MAGIC: &'static [u8] = b"raytracing checkpoint";
+    /// Layout of checkpoints and of the settings they record, bumped whenever either changes
+    const CHECKPOINT_VERSION: u64 = 2;
+
+    /// Everything that decides which samples a pass takes and where they land, which a
+    /// resumed render must share with the checkpoint. The random sampler's samples do not
+    /// depend on the sample count, so only other samplers include it.
+    fn write_checkpoint_settings(&self, out: &mut StateWriter) {
+        out.write_u64(self.image_width as u64);
+        out.write_u64(self.image_height as u64);
+        match self.projection {
+            Projection::Perspective => out.write_u64(0),
+            Projection::Orthographic { view_height } => {
+                out.write_u64(1);
+                out.write_f64(view_height);
+            }
+            Projection::Equirectangular => out.write_u64(2),
+            Projection::Cylindrical { hfov } => {
+                out.write_u64(3);
+                out.write_f64(hfov);
+            }
+            Projection::Fisheye { mapping, fov } => {
+                out.write_u64(4);
+                out.write_bytes(mapping.name().as_bytes());
+                out.write_f64(fov);
+            }
+        }
+        out.write_bool(self.stereo.is_some());
+        if let Some(stereo) = self.stereo {
+            out.write_f64(stereo.interocular);
+            out.write_f64(stereo.convergence);
+            out.write_bytes(stereo.rig.name().as_bytes());
+            out.write_bytes(stereo.layout.name().as_bytes());
+        }
+        self.aperture.write_state(out);
+        out.write_f64(self.cat_eye);
+        out.write_bool(self.lens_system.is_some());
+        if let Some(lens) = &self.lens_system {
+            lens.write_state(out);
+        }
+        out.write_vec3(self.cam_center);
+        out.write_vec3(self.pixel00_loc);
+        out.write_vec3(self.pixel_delta_u);
+        out.write_vec3(self.pixel_delta_v);
+        out.write_vec3(self.defocus_disc_u);
+        out.write_vec3(self.defocus_disc_v);
+        out.write_u64(self.seed);
+        self.sampler.write_state(out);
+        if self.sampler != SamplerKind::Random {
+            out.write_u64(self.samples_per_pixel as u64);
+        }
+        self.filter.write_state(out);
+        out.write_bool(self.adaptive.is_some());
+        if let Some(adaptive) = &self.adaptive {
+            out.write_u64(adaptive.min_samples as u64);
+            out.write_f64(adaptive.target_error);
+        }
+    }
+
+    /// `write_checkpoint_settings` on its own
+    fn checkpoint_settings(&self) -> Vec<u8> {
+        let mut settings = StateWriter::new();
+        self.write_checkpoint_settings(&mut settings);
+        settings.into_bytes()
+    }
+
+    /// Save the render after `next_pass` passes and `total_samples` camera samples to `path`
+    pub(super) fn write_checkpoint(
+        &self,
+        path: &str,
+        (next_pass, total_samples): (i32, u64),
+        film: &Film,
+        splats: &SplatFilm,
+        rows: &[Vec<PixelState>],
+    ) -> std::io::Result<()> {
+        let mut out = StateWriter::new();
+        out.write_bytes(Camera::CHECKPOINT_MAGIC);
+        out.write_u64(Camera::CHECKPOINT_VERSION);
+        out.write_bytes(&self.checkpoint_settings());
+        out.write_u64(next_pass as u64);
+        out.write_u64(total_samples);
+        film.write_state(&mut out);
+        splats.write_state(&mut out);
+        for pixel in rows.iter().flatten() {
+            out.write_f64(pixel.luminance_sum);
+            out.write_f64(pixel.luminance_squared_sum);
+            out.write_u64(pixel.samples as u64);
+        }
+
+        let mut integrator = StateWriter::new();
+        self.integrator.write_state(&mut integrator);
+        out.write_bytes(&integrator.into_bytes());
+
+        replace_file(path, |file| file.write_all(&out.into_bytes()))
+    }
+
+    /// Restore the render saved at `path`, returning the pass to continue from and the camera
+    /// samples taken so far
+    pub(super) fn read_checkpoint(
+        &self,
+        path: &str,
+        film: &Film,
+        splats: &SplatFilm,
+        rows: &mut [Vec<PixelState>],
+    ) -> std::io::Result<(i32, u64)> {
+        let bytes = fs::read(path)?;
+        let mut input = StateReader::new(&bytes);
+        if input.read_bytes().ok() != Some(Camera::CHECKPOINT_MAGIC) {
+            return Err(invalid_data(&format!(
+                "`{path}` is not a render checkpoint"
+            )));
+        }
+        let version = input.read_u64()?;
+        if version != Camera::CHECKPOINT_VERSION {
+            return Err(invalid_data(&format!(
+                "checkpoint `{path}` has version {version}, but this build reads version {}",
+                Camera::CHECKPOINT_VERSION
+            )));
+        }
+        if input.read_bytes()? != self.checkpoint_settings() {
+            return Err(invalid_data(&format!(
+                "checkpoint `{path}` was rendered with different camera settings"
+            )));
+        }
+
+        let next_pass = i32::try_from(input.read_u64()?)
+            .map_err(|_| invalid_data("invalid pass in checkpoint"))?;
+        let total_samples = input.read_u64()?;
+        film.read_state(&mut input)?;
+        splats.read_state(&mut input)?;
+        for pixel in rows.iter_mut().flatten() {
+            pixel.luminance_sum = input.read_f64()?;
+            pixel.luminance_squared_sum = input.read_f64()?;
+            pixel.samples = i32::try_from(input.read_u64()?)
+                .map_err(|_| invalid_data("invalid sample count in checkpoint"))?;
+        }
+
+        let mut integrator = StateReader::new(input.read_bytes()?);
+        self.integrator.read_state(&mut integrator)?;
+        integrator.finish()?;
+        input.finish()?;
+
+        Ok((next_pass, total_samples))
+    }
+}
@@ -0,0 +1,414 @@
+use rayon::prelude::*;
+use std::{
+    fmt,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{adaptive::PixelState, Camera};
+use crate::{
+    color::Color,
+    film::{Film, SplatFilm},
+    hittable_list::HittableList,
+    image::Image,
+    integrator::RenderContext,
+    progress::Progress,
+    tiles::{self, Tile, TileOrder},
+};
+
+/// How often a progressive render writes the image so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotInterval {
+    /// After every this many passes of one sample per pixel
+    Passes(u32),
+    /// After the first pass finishing at least this many seconds after the last snapshot
+    Seconds(f64),
+}
+
+impl SnapshotInterval {
+    /// Whether a snapshot is due after `passes` passes, `since` the last one
+    fn is_due(&self, passes: u32, since: Duration) -> bool {
+        match *self {
+            SnapshotInterval::Passes(interval) => passes.is_multiple_of(interval.max(1)),
+            SnapshotInterval::Seconds(seconds) => since.as_secs_f64() >= seconds,
+        }
+    }
+}
+
+/// Conditions that end a render before every pixel has taken the camera's samples per pixel,
+/// whichever is met first
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StopConditions {
+    /// Wall-clock time the render may take. It stops before a pass expected to overrun
+    /// the budget, judging by the previous pass, but always takes at least one pass.
+    pub time_budget: Option<Duration>,
+    /// Estimated mean squared error of the pixels' mean luminance at which it stops.
+    /// Light splatted onto the image (light tracing in BDPT, MLT) is not part of the estimate.
+    pub target_mse: Option<f64>,
+}
+
+/// Why a render stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Every pixel took the samples per pixel
+    SampleLimit,
+    /// Adaptive sampling found every pixel precise enough
+    Converged,
+    /// Another pass would have overrun the time budget
+    TimeBudget,
+    /// The estimated error reached the target
+    TargetError,
+    /// The render's cancellation token was cancelled
+    Cancelled,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            StopReason::SampleLimit => "sample limit reached",
+            StopReason::Converged => "all pixels converged",
+            StopReason::TimeBudget => "time budget reached",
+            StopReason::TargetError => "target error reached",
+            StopReason::Cancelled => "cancelled",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// What a finished render achieved
+#[derive(Debug, Clone, Copy)]
+pub struct RenderStats {
+    /// Camera samples taken per pixel, averaged over the image
+    pub samples_per_pixel: f64,
+    /// Estimated mean squared error of the pixels' mean luminance
+    pub mse: f64,
+    pub elapsed: Duration,
+    pub stop_reason: StopReason,
+}
+
+impl Camera {
+    /// Render the image to `path` (PNG if it ends in `.png`, PPM otherwise), along with any
+    /// progressive snapshots
+    pub fn render(&self, world: Arc<HittableList>, path: &str) -> std::io::Result<RenderStats> {
+        self.render_with(world, &mut |image, _| image.save(path))
+    }
+
+    /// Render the image into memory and return it. Progressive snapshots go to `preview`
+    /// as they are taken, so an application can show the render filling in.
+    pub fn render_to_image(
+        &self,
+        world: Arc<HittableList>,
+        preview: &mut dyn FnMut(Image),
+    ) -> std::io::Result<(Image, RenderStats)> {
+        let mut result = None;
+        let stats = self.render_with(world, &mut |image, last| {
+            if last {
+                result = Some(image);
+            } else {
+                preview(image);
+            }
+            Ok(())
+        })?;
+        Ok((result.unwrap(), stats))
+    }
+
+    /// Render, handing every snapshot and finally (with `true`) the image to `output`
+    fn render_with(
+        &self,
+        world: Arc<HittableList>,
+        output: &mut dyn FnMut(Image, bool) -> std::io::Result<()>,
+    ) -> std::io::Result<RenderStats> {
+        let w = self.image_width;
+        let h = self.image_height;
+
+        let film = Film::new(w, h, self.filter);
+        let splats = SplatFilm::new(w, h);
+        let ctx = RenderContext {
+            camera: self,
+            splats: &splats,
+        };
+
+        // Render in passes of one sample per pixel, so integrators can prepare
+        // per-pass data (e.g. photon maps) shared by every pixel. With adaptive sampling,
+        // pixels that are precise enough sit out the remaining passes.
+        let adaptive = self.adaptive.as_ref();
+        let mut rows = vec![vec![PixelState::new(); w as usize]; h as usize];
+        let (first_pass, mut total_samples) = match &self.resume_from {
+            Some(checkpoint) => self.read_checkpoint(checkpoint, &film, &splats, &mut rows)?,
+            None => (0, 0),
+        };
+        let mut next_pass = first_pass;
+
+        let start = Instant::now();
+        let mut last_snapshot = start;
+        let mut last_checkpoint = start;
+        let mut last_pass = Duration::ZERO;
+        let mut stop_reason = StopReason::SampleLimit;
+        let mut cancelled_mid_pass = false;
+        let tiles = tiles::tiles(w, h, self.tile_size, self.tile_order);
+        for pass in first_pass..self.samples_per_pixel {
+            if let Some(budget) = self.stop.time_budget {
+                if pass > first_pass && start.elapsed() + last_pass > budget {
+                    stop_reason = StopReason::TimeBudget;
+                    break;
+                }
+            }
+            let pass_start = Instant::now();
+
+            if self.is_cancelled() {
+                stop_reason = StopReason::Cancelled;
+                break;
+            }
+
+            self.integrator.begin_pass(pass as u32, &world, &ctx);
+
+            // Tiles are handed to threads in scheduling order; their samples are added to the
+            // pixel statistics afterwards, one per pixel, so the result does not depend on timing
+            let active = PixelState::needs_samples(&rows, adaptive);
+            let tiles_done = AtomicUsize::new(0);
+            let rendered: Vec<(&Tile, Vec<Option<Color>>)> = tiles
+                .iter()
+                .par_bridge()
+                .filter_map(|tile| {
+                    if self.is_cancelled() {
+                        return None;
+                    }
+                    let samples = self.render_tile(tile, pass, Some(&active), &world, &ctx, &film);
+                    if let Some(progress) = &self.progress {
+                        progress(Progress {
+                            pass: pass as u32,
+                            passes: self.samples_per_pixel as u32,
+                            tiles_done: tiles_done.fetch_add(1, Ordering::Relaxed) + 1,
+                            tiles: tiles.len(),
+                            elapsed: start.elapsed(),
+                        });
+                    }
+                    Some((tile, samples))
+                })
+                .collect();
+
+            let mut taken = 0;
+            let complete = rendered.len() == tiles.len();
+            for (tile, samples) in rendered {
+                let pixels =
+                    (tile.y0..tile.y1).flat_map(|j| (tile.x0..tile.x1).map(move |i| (i, j)));
+                for ((i, j), sample) in pixels.zip(samples) {
+                    if let Some(radiance) = sample {
+                        rows[j as usize][i as usize].add(radiance);
+                        taken += 1;
+                    }
+                }
+            }
+            total_samples += taken;
+
+            // A pass cut short by cancellation is in the image, but not a state to resume from
+            if !complete {
+                cancelled_mid_pass = true;
+                stop_reason = StopReason::Cancelled;
+                break;
+            }
+            next_pass = pass + 1;
+
+            if self.progress.is_none() && !self.quiet {
+                let percent = (next_pass as f64 / self.samples_per_pixel as f64) * 100.0;
+                if adaptive.is_some() {
+                    eprint!("\rRendering: {percent:.2}% ({taken} pixels sampled)   ");
+                } else {
+                    eprint!("\rRendering: {percent:.2}%");
+                }
+            }
+
+            if taken == 0 {
+                stop_reason = StopReason::Converged;
+                break;
+            }
+            last_pass = pass_start.elapsed();
+
+            if let Some(target) = self.stop.target_mse {
+                if PixelState::estimated_mse(&rows) <= target {
+                    stop_reason = StopReason::TargetError;
+                    break;
+                }
+            }
+
+            if next_pass == self.samples_per_pixel {
+                break;
+            }
+            if let Some(snapshots) = self.snapshots {
+                if snapshots.is_due(next_pass as u32, last_snapshot.elapsed()) {
+                    output(self.image(&film, Some(&splats), total_samples), false)?;
+                    last_snapshot = Instant::now();
+                }
+            }
+            if let Some(checkpoints) = &self.checkpoints {
+                if checkpoints
+                    .interval
+                    .is_due(next_pass as u32, last_checkpoint.elapsed())
+                {
+                    let state = (next_pass, total_samples);
+                    self.write_checkpoint(&checkpoints.path, state, &film, &splats, &rows)?;
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+
+        output(self.image(&film, Some(&splats), total_samples), true)?;
+        if let (Some(checkpoints), false) = (&self.checkpoints, cancelled_mid_pass) {
+            let state = (next_pass, total_samples);
+            self.write_checkpoint(&checkpoints.path, state, &film, &splats, &rows)?;
+        }
+
+        if let Some(path) = adaptive.and_then(|adaptive| adaptive.debug_image.as_deref()) {
+            self.write_sample_counts(&rows, path)?;
+        }
+
+        let stats = RenderStats {
+            samples_per_pixel: total_samples as f64 / (w as f64 * h as f64),
+            mse: PixelState::estimated_mse(&rows),
+            elapsed: start.elapsed(),
+            stop_reason,
+        };
+        if self.progress.is_none() && !self.quiet {
+            eprintln!(
+                "\rDone: {:.1} spp in {:.1}s, estimated MSE {:.3e} ({})   ",
+                stats.samples_per_pixel,
+                stats.elapsed.as_secs_f64(),
+                stats.mse,
+                stats.stop_reason
+            );
+        }
+
+        Ok(stats)
+    }
+
+    /// Set the integrator up for `render_region`, as `render` does before its first pass
+    pub fn prepare_regions(&self, world: &HittableList) {
+        let splats = SplatFilm::new(0, 0);
+        let ctx = RenderContext {
+            camera: self,
+            splats: &splats,
+        };
+        self.integrator.begin_pass(0, world, &ctx);
+    }
+
+    /// Render `passes` in the pixels of `tile` only, after `prepare_regions`, into a film
+    /// reaching as far past the tile as the filter spreads its samples. Merging the films of
+    /// tiles covering the image gives what `render` would, for integrators that render regions.
+    pub fn render_region(&self, world: &HittableList, tile: Tile, passes: Range<i32>) -> Film {
+        let margin = self.filter.radius().ceil() as i32 + 1;
+        let region = Tile {
+            x0: (tile.x0 - margin).max(0),
+            y0: (tile.y0 - margin).max(0),
+            x1: (tile.x1 + margin).min(self.image_width),
+            y1: (tile.y1 + margin).min(self.image_height),
+        };
+        let film = Film::for_region(region, self.filter);
+        let splats = SplatFilm::new(0, 0);
+        let ctx = RenderContext {
+            camera: self,
+            splats: &splats,
+        };
+
+        let tiles: Vec<Tile> = tiles::tiles(
+            tile.width(),
+            tile.height(),
+            self.tile_size,
+            TileOrder::Scanline,
+        )
+        .into_iter()
+        .map(|t| Tile {
+            x0: tile.x0 + t.x0,
+            y0: tile.y0 + t.y0,
+            x1: tile.x0 + t.x1,
+            y1: tile.y0 + t.y1,
+        })
+        .collect();
+        for pass in passes {
+            tiles.par_iter().for_each(|tile| {
+                self.render_tile(tile, pass, None, world, &ctx, &film);
+            });
+        }
+        film
+    }
+
+    /// The image of `film`, covering the whole image, as `render` would make it
+    pub fn film_image(&self, film: &Film) -> Image {
+        self.image(film, None, 0)
+    }
+
+    /// Take this pass's sample in every pixel of `tile` still `active` (all of them if `None`),
+    /// adding it to the film, and return the samples in row order (`None` for pixels that sat
+    /// the pass out)
+    fn render_tile(
+        &self,
+        tile: &Tile,
+        pass: i32,
+        active: Option<&[Vec<bool>]>,
+        world: &HittableList,
+        ctx: &RenderContext,
+        film: &Film,
+    ) -> Vec<Option<Color>> {
+        let mut sampler = self
+            .sampler
+            .create(self.seed, self.samples_per_pixel as u32);
+        let mut samples = Vec::with_capacity((tile.width() * tile.height()) as usize);
+        for j in tile.y0..tile.y1 {
+            for i in tile.x0..tile.x1 {
+                if active.is_some_and(|active| !active[j as usize][i as usize]) {
+                    samples.push(None);
+                    continue;
+                }
+                sampler.start_pixel_sample(i, j, pass as u32);
+                let (dx, dy) = sampler.next_2d();
+                let (x, y) = (i as f64 + dx, j as f64 + dy);
+                let radiance = match self.get_ray_at(x, y, sampler.as_mut()) {
+                    Some((r, weight)) => {
+                        weight * self.integrator.radiance(&r, world, ctx, sampler.as_mut())
+                    }
+                    None => Color::zeros(),
+                };
+                film.add_sample(x, y, radiance);
+                samples.push(Some(radiance));
+            }
+        }
+        samples
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+    }
+
+    /// The film plus light splats after `total_samples` camera samples
+    fn image(&self, film: &Film, splats: Option<&SplatFilm>, total_samples: u64) -> Image {
+        let w = self.image_width;
+        let h = self.image_height;
+        let mut image = Image::new(w, h);
+
+        // Every camera sample may trace one light path, so splats are scaled by the average
+        // samples per pixel: one over the sample count without adaptive sampling
+        let splat_scale = (w as f64 * h as f64) / total_samples.max(1) as f64;
+        for j in 0..h {
+            for i in 0..w {
+                let mut pixel = film.get(i, j);
+                if let Some(splats) = splats {
+                    pixel += splat_scale * splats.get(i, j);
+                }
+                image.set(i, j, pixel);
+            }
+        }
+
+        let scale = self.exposure.scale(&image, self.f_number);
+        if scale != 1.0 {
+            for pixel in &mut image.pixels {
+                *pixel *= scale;
+            }
+        }
+        image
+    }
+}
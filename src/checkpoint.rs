@@ -0,0 +1,120 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::geometry::Vec3;
+
+/// Little-endian encoder for the state saved in render checkpoints
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_i64(&mut self, v: i64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Exact bits, so a restored value is the same float
+    pub fn write_f64(&mut self, v: f64) {
+        self.write_u64(v.to_bits());
+    }
+
+    pub fn write_vec3(&mut self, v: Vec3) {
+        self.write_f64(v.x);
+        self.write_f64(v.y);
+        self.write_f64(v.z);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.bytes.push(u8::from(v));
+    }
+
+    /// Length-prefixed bytes
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.write_u64(v.len() as u64);
+        self.bytes.extend_from_slice(v);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Decoder for what a `StateWriter` wrote, failing on truncated or malformed data
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "checkpoint ends early",
+            ));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64> {
+        Ok(f64::from_bits(self.read_u64()?))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid flag in checkpoint")),
+        }
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_len()?;
+        self.take(len)
+    }
+
+    /// A count written with `write_u64`, checked against what is left to read so a corrupt
+    /// count fails instead of allocating
+    pub fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_u64()?;
+        if len > self.bytes.len() as u64 {
+            return Err(invalid_data("invalid length in checkpoint"));
+        }
+        Ok(len as usize)
+    }
+
+    /// Fail unless everything has been read
+    pub fn finish(self) -> Result<()> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_data("unexpected data at the end of checkpoint"))
+        }
+    }
+}
+
+pub fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
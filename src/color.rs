@@ -1,6 +1,6 @@
 use crate::{
     geometry::{Interval, Vec3},
-    util::{rand_f64, rand_f64_range},
+    sampler::Sampler,
 };
 
 pub type Color = Vec3;
@@ -14,23 +14,31 @@ pub fn linear_to_gamma(linear_component: f64) -> f64 {
 }
 
 impl Color {
-    pub fn rand() -> Color {
+    /// Relative luminance (Rec. 709 weights) of a linear color
+    pub fn luminance(self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
+    #[allow(clippy::needless_return)]
+    pub fn rand(sampler: &mut dyn Sampler) -> Color {
         return Color {
-            x: rand_f64(),
-            y: rand_f64(),
-            z: rand_f64(),
+            x: sampler.next_f64(),
+            y: sampler.next_f64(),
+            z: sampler.next_f64(),
         };
     }
 
-    pub fn rand_range(min: f64, max: f64) -> Color {
+    #[allow(clippy::needless_return)]
+    pub fn rand_range(min: f64, max: f64, sampler: &mut dyn Sampler) -> Color {
         return Color {
-            x: rand_f64_range(min, max),
-            y: rand_f64_range(min, max),
-            z: rand_f64_range(min, max),
+            x: sampler.next_range(min, max),
+            y: sampler.next_range(min, max),
+            z: sampler.next_range(min, max),
         };
     }
 
-    pub fn to_string(self) -> String {
+    /// Gamma-encoded 8-bit channels, clamped to the displayable range
+    pub fn to_rgb8(self) -> [u8; 3] {
         let intensity: Interval = Interval {
             min: 0.0,
             max: 0.999,
@@ -40,10 +48,16 @@ impl Color {
         let g = linear_to_gamma(self.y);
         let b = linear_to_gamma(self.z);
 
-        let ir = (256.0 * intensity.clamp(r)) as i32;
-        let ig = (256.0 * intensity.clamp(g)) as i32;
-        let ib = (256.0 * intensity.clamp(b)) as i32;
+        [
+            (256.0 * intensity.clamp(r)) as u8,
+            (256.0 * intensity.clamp(g)) as u8,
+            (256.0 * intensity.clamp(b)) as u8,
+        ]
+    }
 
+    #[allow(clippy::inherent_to_string, clippy::needless_return)]
+    pub fn to_string(self) -> String {
+        let [ir, ig, ib] = self.to_rgb8();
         return format!("{ir} {ig} {ib}\n");
     }
 }
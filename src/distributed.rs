@@ -0,0 +1,452 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    ops::Range,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    checkpoint::{invalid_data, StateReader, StateWriter},
+    film::Film,
+    filter::Filter,
+    image::Image,
+    scene::{RenderSettings, Scene},
+    tiles::{self, Tile, TileOrder},
+};
+
+/// Sent first by the coordinator, so a worker only ever talks to a coordinator it understands
+const PROTOCOL_MAGIC: &[u8] = b"raytracing distributed 2";
+
+/// Longest scene text a coordinator sends
+const MAX_SCENE: usize = 16 << 20;
+
+/// Room in a message for everything besides a scene or film: tag, magic, settings, tile
+const MESSAGE_OVERHEAD: usize = 4096;
+
+/// How often a worker tells the coordinator it is still rendering its tile
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// Longest the coordinator waits to hear from a worker before giving its tile to another
+const WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What coordinator and workers send each other, each message framed by its length
+enum Message {
+    /// Coordinator to worker: the render every following tile belongs to
+    Job {
+        scene: String,
+        settings: RenderSettings,
+    },
+    /// Worker to coordinator: the job is set up and the worker is waiting for tiles
+    Ready,
+    /// Worker to coordinator: the job cannot be rendered
+    Failed(String),
+    /// Coordinator to worker: render `passes` of the pixels in `tile`
+    Work { tile: Tile, passes: Range<i32> },
+    /// Worker to coordinator: the film of the last tile, as saved by `Film::write_state`
+    Rendered { region: Tile, film: Vec<u8> },
+    /// Coordinator to worker: there is no more work
+    Done,
+    /// Worker to coordinator, every `KEEP_ALIVE` while rendering: still working on the tile
+    Working,
+}
+
+impl Message {
+    fn send(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut out = StateWriter::new();
+        match self {
+            Message::Job { scene, settings } => {
+                out.write_u64(0);
+                out.write_bytes(PROTOCOL_MAGIC);
+                out.write_bytes(scene.as_bytes());
+                settings.write_state(&mut out);
+            }
+            Message::Ready => out.write_u64(1),
+            Message::Failed(msg) => {
+                out.write_u64(2);
+                out.write_bytes(msg.as_bytes());
+            }
+            Message::Work { tile, passes } => {
+                out.write_u64(3);
+                write_tile(tile, &mut out);
+                out.write_i64(passes.start as i64);
+                out.write_i64(passes.end as i64);
+            }
+            Message::Rendered { region, film } => {
+                out.write_u64(4);
+                write_tile(region, &mut out);
+                out.write_bytes(film);
+            }
+            Message::Done => out.write_u64(5),
+            Message::Working => out.write_u64(6),
+        }
+
+        let bytes = out.into_bytes();
+        stream.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        stream.write_all(&bytes)?;
+        stream.flush()
+    }
+
+    /// Next message from `stream`, failing on any longer than `max_len` bytes so a corrupt
+    /// length fails instead of allocating
+    fn receive(stream: &mut TcpStream, max_len: usize) -> io::Result<Message> {
+        let closed_or_timed_out = |e: Error| match e.kind() {
+            ErrorKind::UnexpectedEof => Error::new(e.kind(), "connection closed"),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                Error::new(ErrorKind::TimedOut, "timed out")
+            }
+            _ => e,
+        };
+        let mut len = [0; 8];
+        stream.read_exact(&mut len).map_err(closed_or_timed_out)?;
+        let len = u64::from_le_bytes(len);
+        if len > max_len as u64 {
+            return Err(invalid_data("message too long"));
+        }
+        let mut bytes = vec![0; len as usize];
+        stream.read_exact(&mut bytes).map_err(closed_or_timed_out)?;
+
+        let mut input = StateReader::new(&bytes);
+        let message = match input.read_u64()? {
+            0 => {
+                if input.read_bytes()? != PROTOCOL_MAGIC {
+                    return Err(invalid_data("not a raytracing coordinator"));
+                }
+                Message::Job {
+                    scene: read_string(&mut input)?,
+                    settings: RenderSettings::read_state(&mut input)?,
+                }
+            }
+            1 => Message::Ready,
+            2 => Message::Failed(read_string(&mut input)?),
+            3 => Message::Work {
+                tile: read_tile(&mut input)?,
+                passes: read_i32(&mut input)?..read_i32(&mut input)?,
+            },
+            4 => Message::Rendered {
+                region: read_tile(&mut input)?,
+                film: input.read_bytes()?.to_vec(),
+            },
+            5 => Message::Done,
+            6 => Message::Working,
+            _ => return Err(invalid_data("unknown message")),
+        };
+        input.finish()?;
+        Ok(message)
+    }
+}
+
+fn write_tile(tile: &Tile, out: &mut StateWriter) {
+    for v in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        out.write_i64(v as i64);
+    }
+}
+
+fn read_tile(input: &mut StateReader) -> io::Result<Tile> {
+    Ok(Tile {
+        x0: read_i32(input)?,
+        y0: read_i32(input)?,
+        x1: read_i32(input)?,
+        y1: read_i32(input)?,
+    })
+}
+
+fn read_i32(input: &mut StateReader) -> io::Result<i32> {
+    i32::try_from(input.read_i64()?).map_err(|_| invalid_data("number out of range"))
+}
+
+fn read_string(input: &mut StateReader) -> io::Result<String> {
+    String::from_utf8(input.read_bytes()?.to_vec()).map_err(|_| invalid_data("invalid text"))
+}
+
+/// Tiles waiting for a worker, shared by the threads serving each worker
+struct WorkQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+struct QueueState {
+    pending: VecDeque<Tile>,
+    finished: usize,
+    total: usize,
+}
+
+impl WorkQueue {
+    /// Next tile to render, waiting while every unfinished tile is out with some worker in
+    /// case one of them disconnects; `None` once all tiles are finished
+    fn take(&self) -> Option<Tile> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(tile) = state.pending.pop_front() {
+                return Some(tile);
+            }
+            if state.finished == state.total {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Hand out `tile` again, before anything else, after its worker went away
+    fn give_back(&self, tile: Tile) {
+        self.state.lock().unwrap().pending.push_front(tile);
+        self.changed.notify_all();
+    }
+
+    /// Count a tile as finished, returning how many are and of how many
+    fn finish(&self) -> (usize, usize) {
+        let mut state = self.state.lock().unwrap();
+        state.finished += 1;
+        self.changed.notify_all();
+        (state.finished, state.total)
+    }
+
+    fn is_finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.finished == state.total
+    }
+}
+
+/// Render `scene` with `settings` on whichever workers connect to `listener`, and return the
+/// image.
+///
+/// Every worker gets the scene and settings, then tiles of `tile_size` pixels square to render
+/// all passes of, one at a time. Workers may join at any point; the tile a worker was rendering
+/// when it disconnected, or went silent for longer than `WORKER_TIMEOUT`, goes to the next
+/// worker free. Tiles come back as the films of their pixels, in fixed point, so the merged
+/// image is exactly the one `Camera::render` makes.
+/// Only integrators that render regions can be distributed, without adaptive sampling, and
+/// only scenes that read no files, which workers could not open.
+pub fn coordinate(
+    listener: TcpListener,
+    scene: &Scene,
+    settings: &RenderSettings,
+    tile_size: i32,
+    quiet: bool,
+) -> io::Result<Image> {
+    let invalid_input = |msg| Error::new(ErrorKind::InvalidInput, msg);
+    if let Some(path) = scene.camera.files().first() {
+        return Err(invalid_input(format!(
+            "scenes reading files cannot be distributed, as workers cannot open `{path}`"
+        )));
+    }
+    let camera = scene.build_camera(settings).map_err(invalid_input)?;
+    scene.build_world().map_err(invalid_input)?;
+    if !camera.integrator().renders_regions() {
+        return Err(invalid_input(format!(
+            "the `{}` integrator cannot be distributed",
+            settings.integrator
+        )));
+    }
+    let text = scene.to_text();
+    if text.len() > MAX_SCENE {
+        return Err(invalid_input("scene too large to distribute".to_string()));
+    }
+
+    let (w, h) = (camera.image_width(), camera.image_height());
+    let tiles = tiles::tiles(w, h, tile_size, TileOrder::Spiral);
+    // Tile films reach as far past their tile as `Camera::render_region` makes them
+    let margin = camera.filter().radius().ceil() as i32 + 1;
+    let region_pixels =
+        (tile_size + 2 * margin).min(w) as usize * (tile_size + 2 * margin).min(h) as usize;
+    let render = DistributedRender {
+        job: Message::Job {
+            scene: text,
+            settings: settings.clone(),
+        },
+        passes: 0..settings.samples_per_pixel,
+        filter: camera.filter(),
+        film: Film::new(w, h, camera.filter()),
+        max_reply: Film::state_len(region_pixels) + MESSAGE_OVERHEAD,
+        queue: WorkQueue {
+            state: Mutex::new(QueueState {
+                total: tiles.len(),
+                pending: tiles.into(),
+                finished: 0,
+            }),
+            changed: Condvar::new(),
+        },
+        quiet,
+    };
+
+    if !quiet {
+        eprintln!("Waiting for workers on {}", listener.local_addr()?);
+    }
+    listener.set_nonblocking(true)?;
+    let start = Instant::now();
+    thread::scope(|scope| {
+        while !render.queue.is_finished() {
+            // Failing to take one connection (out of file descriptors, aborted by the peer)
+            // is no reason to give up on the render
+            let accepted = listener.accept().and_then(|(stream, addr)| {
+                stream.set_nonblocking(false)?;
+                // A worker that hangs without hanging up then fails like one that left
+                stream.set_read_timeout(Some(WORKER_TIMEOUT))?;
+                stream.set_write_timeout(Some(WORKER_TIMEOUT))?;
+                Ok((stream, addr))
+            });
+            let (stream, addr) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock && !quiet {
+                        eprintln!("\rAccepting a worker failed: {e}");
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+            };
+
+            let render = &render;
+            scope.spawn(move || {
+                if !quiet {
+                    eprintln!("\rWorker {addr} joined");
+                }
+                if let (Err(e), false) = (render.serve(stream, addr), quiet) {
+                    eprintln!("\rWorker {addr} left: {e}");
+                }
+            });
+        }
+    });
+
+    if !quiet {
+        eprintln!(
+            "\rDone: {} spp in {:.1}s   ",
+            settings.samples_per_pixel,
+            start.elapsed().as_secs_f64()
+        );
+    }
+    Ok(camera.film_image(&render.film))
+}
+
+/// What the threads serving each worker share
+struct DistributedRender {
+    job: Message,
+    passes: Range<i32>,
+    filter: Filter,
+    film: Film,
+    /// Longest valid message from a worker: the film of a tile and its margin
+    max_reply: usize,
+    queue: WorkQueue,
+    quiet: bool,
+}
+
+impl DistributedRender {
+    /// Hand tiles to the worker at the other end of `stream` and merge what it renders until no
+    /// tiles are left, giving back the tile it has if anything goes wrong
+    fn serve(&self, mut stream: TcpStream, addr: SocketAddr) -> io::Result<()> {
+        self.job.send(&mut stream)?;
+        match Message::receive(&mut stream, self.max_reply)? {
+            Message::Ready => {}
+            Message::Failed(msg) => return Err(Error::other(msg)),
+            _ => return Err(invalid_data("unexpected message from worker")),
+        }
+
+        while let Some(tile) = self.queue.take() {
+            let rendered = match self.render_remotely(&mut stream, tile) {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    self.queue.give_back(tile);
+                    return Err(e);
+                }
+            };
+            self.film.merge(&rendered);
+
+            let (finished, total) = self.queue.finish();
+            if !self.quiet {
+                let percent = finished as f64 / total as f64 * 100.0;
+                eprint!("\rRendering: {percent:.2}% (last tile from {addr})   ");
+            }
+        }
+
+        Message::Done.send(&mut stream)
+    }
+
+    fn render_remotely(&self, stream: &mut TcpStream, tile: Tile) -> io::Result<Film> {
+        let work = Message::Work {
+            tile,
+            passes: self.passes.clone(),
+        };
+        work.send(stream)?;
+        let (region, film) = loop {
+            match Message::receive(stream, self.max_reply)? {
+                Message::Working => continue,
+                Message::Rendered { region, film } => break (region, film),
+                _ => return Err(invalid_data("unexpected message from worker")),
+            }
+        };
+
+        // The film must cover the tile and stay inside the image
+        let image = self.film.region();
+        let covers = |outer: Tile, inner: Tile| {
+            outer.x0 <= inner.x0
+                && outer.y0 <= inner.y0
+                && outer.x1 >= inner.x1
+                && outer.y1 >= inner.y1
+        };
+        if !covers(region, tile) || !covers(image, region) {
+            return Err(invalid_data("worker rendered the wrong region"));
+        }
+
+        let rendered = Film::for_region(region, self.filter);
+        let mut input = StateReader::new(&film);
+        rendered.read_state(&mut input)?;
+        input.finish()?;
+        Ok(rendered)
+    }
+}
+
+/// Render tiles for the coordinator at the other end of `stream` until it has no more work
+pub fn work(mut stream: TcpStream) -> io::Result<()> {
+    let Message::Job { scene, settings } =
+        Message::receive(&mut stream, MAX_SCENE + MESSAGE_OVERHEAD)?
+    else {
+        return Err(invalid_data("unexpected message from coordinator"));
+    };
+    let built = Scene::parse(&scene)
+        .and_then(|scene| Ok((scene.build_world()?, scene.build_camera(&settings)?)));
+    let (world, camera) = match built {
+        Ok(built) => built,
+        Err(msg) => {
+            Message::Failed(msg.clone()).send(&mut stream)?;
+            return Err(invalid_data(&msg));
+        }
+    };
+    camera.prepare_regions(&world);
+    Message::Ready.send(&mut stream)?;
+
+    loop {
+        match Message::receive(&mut stream, MESSAGE_OVERHEAD)? {
+            Message::Work { tile, passes } => {
+                // Render on another thread, keeping the connection alive meanwhile
+                let film = thread::scope(|scope| {
+                    let (sender, receiver) = mpsc::channel();
+                    let (camera, world) = (&camera, &world);
+                    scope.spawn(move || sender.send(camera.render_region(world, tile, passes)));
+                    loop {
+                        match receiver.recv_timeout(KEEP_ALIVE) {
+                            Ok(film) => return Ok(film),
+                            Err(RecvTimeoutError::Timeout) => Message::Working.send(&mut stream)?,
+                            Err(RecvTimeoutError::Disconnected) => {
+                                return Err(Error::other("rendering the tile failed"))
+                            }
+                        }
+                    }
+                })?;
+                let mut out = StateWriter::new();
+                film.write_state(&mut out);
+                let rendered = Message::Rendered {
+                    region: film.region(),
+                    film: out.into_bytes(),
+                };
+                rendered.send(&mut stream)?;
+            }
+            Message::Done => return Ok(()),
+            _ => return Err(invalid_data("unexpected message from coordinator")),
+        }
+    }
+}
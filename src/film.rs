@@ -0,0 +1,221 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use crate::{
+    checkpoint::{invalid_data, StateReader, StateWriter},
+    color::Color,
+    filter::Filter,
+    tiles::Tile,
+};
+
+/// Fixed point units per unit of radiance (or filter weight)
+const SCALE: f64 = (1u64 << 32) as f64;
+
+/// Add `value` to `cell`, dropping NaN and infinite samples so one bad sample cannot wreck a
+/// pixel
+fn atomic_add(cell: &AtomicI64, value: f64) {
+    if value.is_finite() {
+        atomic_add_fixed(cell, (value * SCALE).round() as i64);
+    }
+}
+
+/// Add a fixed point value to `cell`, saturating rather than wrapping around
+fn atomic_add_fixed(cell: &AtomicI64, fixed: i64) {
+    if fixed != 0 {
+        let _ = cell.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+            Some(sum.saturating_add(fixed))
+        });
+    }
+}
+
+fn atomic_load(cell: &AtomicI64) -> f64 {
+    cell.load(Ordering::Relaxed) as f64 / SCALE
+}
+
+/// Save the exact fixed point values of `cells`
+fn write_cells(cells: &[AtomicI64], out: &mut StateWriter) {
+    out.write_u64(cells.len() as u64);
+    for cell in cells {
+        out.write_i64(cell.load(Ordering::Relaxed));
+    }
+}
+
+/// Restore what `write_cells` saved into `cells` of the same size
+fn read_cells(cells: &[AtomicI64], input: &mut StateReader) -> std::io::Result<()> {
+    if input.read_len()? != cells.len() {
+        return Err(invalid_data("checkpoint image size does not match"));
+    }
+    for cell in cells {
+        cell.store(input.read_i64()?, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Thread-safe framebuffer that any thread can add contributions to at any pixel.
+/// Used by integrators that deposit light paths directly onto the image (light tracing),
+/// where contributions for one pixel can come from any row being rendered.
+///
+/// Values are kept in fixed point so that, unlike float additions, the sum does not
+/// depend on the order threads add in and renders stay reproducible.
+pub struct SplatFilm {
+    width: i32,
+    height: i32,
+    data: Vec<AtomicI64>,
+}
+
+impl SplatFilm {
+    pub fn new(width: i32, height: i32) -> SplatFilm {
+        let len = (width.max(0) * height.max(0)) as usize * 3;
+        SplatFilm {
+            width,
+            height,
+            data: (0..len).map(|_| AtomicI64::new(0)).collect(),
+        }
+    }
+
+    /// Add `c` to pixel (`i`,`j`); contributions outside the image are dropped
+    pub fn add(&self, i: i32, j: i32, c: Color) {
+        if i < 0 || j < 0 || i >= self.width || j >= self.height {
+            return;
+        }
+
+        let base = (j * self.width + i) as usize * 3;
+        atomic_add(&self.data[base], c.x);
+        atomic_add(&self.data[base + 1], c.y);
+        atomic_add(&self.data[base + 2], c.z);
+    }
+
+    pub fn get(&self, i: i32, j: i32) -> Color {
+        let base = (j * self.width + i) as usize * 3;
+        Color {
+            x: atomic_load(&self.data[base]),
+            y: atomic_load(&self.data[base + 1]),
+            z: atomic_load(&self.data[base + 2]),
+        }
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        write_cells(&self.data, out);
+    }
+
+    pub fn read_state(&self, input: &mut StateReader) -> std::io::Result<()> {
+        read_cells(&self.data, input)
+    }
+}
+
+/// Framebuffer reconstructing the image from camera samples. Each sample is added, weighted
+/// by the filter, to every pixel within the filter radius, so pixels near a row boundary
+/// receive samples from rows rendered on other threads. Fixed point like `SplatFilm`.
+///
+/// A film may cover just a region of the image, taking the samples that land there; merging
+/// region films gives exactly the film of the whole image.
+pub struct Film {
+    filter: Filter,
+    region: Tile,
+    weighted: SplatFilm,
+    weights: Vec<AtomicI64>,
+}
+
+impl Film {
+    pub fn new(width: i32, height: i32, filter: Filter) -> Film {
+        Film::for_region(
+            Tile {
+                x0: 0,
+                y0: 0,
+                x1: width,
+                y1: height,
+            },
+            filter,
+        )
+    }
+
+    /// Film covering only the pixels of `region`, addressed by their image coordinates
+    pub fn for_region(region: Tile, filter: Filter) -> Film {
+        let (width, height) = (region.width().max(0), region.height().max(0));
+        Film {
+            filter,
+            region,
+            weighted: SplatFilm::new(width, height),
+            weights: (0..width * height).map(|_| AtomicI64::new(0)).collect(),
+        }
+    }
+
+    /// Pixels this film covers
+    pub fn region(&self) -> Tile {
+        self.region
+    }
+
+    /// Add a sample of radiance `c` taken at continuous raster position (`x`,`y`),
+    /// where pixel (`i`,`j`) covers \[i,i+1) x \[j,j+1)
+    pub fn add_sample(&self, x: f64, y: f64, c: Color) {
+        // Pixels whose centers lie within the radius, half-open so that a box filter of
+        // radius 0.5 gives every sample to exactly the pixel it falls in
+        let radius = self.filter.radius();
+        let (x, y) = (x - 0.5, y - 0.5);
+        let i_range = (x - radius).floor() as i32 + 1..=(x + radius).floor() as i32;
+        let j_range = (y - radius).floor() as i32 + 1..=(y + radius).floor() as i32;
+
+        let region = self.region;
+        for j in j_range {
+            if j < region.y0 || j >= region.y1 {
+                continue;
+            }
+            for i in i_range.clone() {
+                if i < region.x0 || i >= region.x1 {
+                    continue;
+                }
+
+                let weight = self.filter.evaluate(x - i as f64, y - j as f64);
+                if weight == 0.0 {
+                    continue;
+                }
+                self.weighted.add(i - region.x0, j - region.y0, weight * c);
+                atomic_add(&self.weights[self.index(i, j)], weight);
+            }
+        }
+    }
+
+    /// Weighted mean of the samples around pixel (`i`,`j`), black if none count towards it
+    pub fn get(&self, i: i32, j: i32) -> Color {
+        let weight = atomic_load(&self.weights[self.index(i, j)]);
+        if weight <= 0.0 {
+            return Color::zeros();
+        }
+        self.weighted.get(i - self.region.x0, j - self.region.y0) / weight
+    }
+
+    /// Add everything `other` took to the pixels it shares with this film
+    pub fn merge(&self, other: &Film) {
+        let (a, b) = (self.region, other.region);
+        for j in a.y0.max(b.y0)..a.y1.min(b.y1) {
+            for i in a.x0.max(b.x0)..a.x1.min(b.x1) {
+                let (to, from) = (self.index(i, j), other.index(i, j));
+                let weight = other.weights[from].load(Ordering::Relaxed);
+                atomic_add_fixed(&self.weights[to], weight);
+                for channel in 0..3 {
+                    let value = other.weighted.data[from * 3 + channel].load(Ordering::Relaxed);
+                    atomic_add_fixed(&self.weighted.data[to * 3 + channel], value);
+                }
+            }
+        }
+    }
+
+    fn index(&self, i: i32, j: i32) -> usize {
+        ((j - self.region.y0) * self.region.width() + i - self.region.x0) as usize
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        self.weighted.write_state(out);
+        write_cells(&self.weights, out);
+    }
+
+    /// Bytes `write_state` writes for a film covering `pixels` pixels
+    pub fn state_len(pixels: usize) -> usize {
+        // Three weighted channels and a weight per pixel, each preceded by its count
+        16 + 32 * pixels
+    }
+
+    pub fn read_state(&self, input: &mut StateReader) -> std::io::Result<()> {
+        self.weighted.read_state(input)?;
+        read_cells(&self.weights, input)
+    }
+}
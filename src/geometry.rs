@@ -26,7 +26,7 @@ impl Ray {
 // VEC3
 // =======================
 
-use crate::util::{rand_f64, rand_f64_range};
+use crate::sampler::Sampler;
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub};
 
 #[derive(Debug, Clone, Copy)]
@@ -48,6 +48,10 @@ impl Vec3 {
         self.length_squared().sqrt()
     }
 
+    pub fn max_component(self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+
     pub fn dot(u: Vec3, v: Vec3) -> f64 {
         (u.x * v.x) + (u.y * v.y) + (u.z * v.z)
     }
@@ -64,18 +68,22 @@ impl Vec3 {
         v / v.length()
     }
 
-    pub fn rand_unit_vector() -> Vec3 {
-        loop {
-            let p = Vec3::random_range(-1.0, 1.0);
-            let lensq = p.length_squared();
-            if 1e-160 < lensq && lensq <= 1.0 {
-                return p / lensq.sqrt();
-            }
+    /// Uniform direction on the unit sphere, mapped from one 2D sample
+    pub fn rand_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.next_2d();
+        let z = 1.0 - 2.0 * u1;
+        let r = f64::max(0.0, 1.0 - z * z).sqrt();
+        let phi = 2.0 * PI * u2;
+
+        Vec3 {
+            x: r * phi.cos(),
+            y: r * phi.sin(),
+            z,
         }
     }
 
-    pub fn random_on_hemisphere(normal: Vec3) -> Vec3 {
-        let unit_on_sphere = Vec3::rand_unit_vector();
+    pub fn random_on_hemisphere(normal: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let unit_on_sphere = Vec3::rand_unit_vector(sampler);
         if Vec3::dot(unit_on_sphere, normal) > 0.0 {
             unit_on_sphere
         } else {
@@ -83,19 +91,19 @@ impl Vec3 {
         }
     }
 
-    pub fn random() -> Vec3 {
+    pub fn random(sampler: &mut dyn Sampler) -> Vec3 {
         Vec3 {
-            x: rand_f64(),
-            y: rand_f64(),
-            z: rand_f64(),
+            x: sampler.next_f64(),
+            y: sampler.next_f64(),
+            z: sampler.next_f64(),
         }
     }
 
-    pub fn random_range(min: f64, max: f64) -> Vec3 {
+    pub fn random_range(min: f64, max: f64, sampler: &mut dyn Sampler) -> Vec3 {
         Vec3 {
-            x: rand_f64_range(min, max),
-            y: rand_f64_range(min, max),
-            z: rand_f64_range(min, max),
+            x: sampler.next_range(min, max),
+            y: sampler.next_range(min, max),
+            z: sampler.next_range(min, max),
         }
     }
 
@@ -129,16 +137,37 @@ impl Vec3 {
         }
     }
 
-    pub fn rand_in_unit_disc() -> Vec3 {
-        loop {
-            let p = Vec3 {
-                x: rand_f64_range(-1.0, 1.0),
-                y: rand_f64_range(-1.0, 1.0),
-                z: 0.0,
-            };
-            if p.length_squared() < 1.0 {
-                return p;
-            }
+    /// Cosine-weighted direction on the +z hemisphere (pdf `cos(theta) / pi`)
+    pub fn rand_cosine_direction(sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.next_2d();
+
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * r2.sqrt();
+        let y = phi.sin() * r2.sqrt();
+        let z = (1.0 - r2).sqrt();
+
+        Vec3 { x, y, z }
+    }
+
+    /// Uniform point in the unit disc, mapped from one 2D sample with Shirley's
+    /// concentric mapping so strata in the square stay compact in the disc
+    pub fn rand_in_unit_disc(sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.next_2d();
+        let a = 2.0 * u1 - 1.0;
+        let b = 2.0 * u2 - 1.0;
+        if a == 0.0 && b == 0.0 {
+            return Vec3::zeros();
+        }
+
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, (PI / 4.0) * (b / a))
+        } else {
+            (b, PI / 2.0 - (PI / 4.0) * (a / b))
+        };
+        Vec3 {
+            x: r * theta.cos(),
+            y: r * theta.sin(),
+            z: 0.0,
         }
     }
 }
@@ -302,3 +331,65 @@ pub const UNIVERSE: Interval = Interval {
     min: f64::NEG_INFINITY,
     max: f64::INFINITY,
 };
+
+// ==========================
+// ONB
+// ==========================
+
+/// Orthonormal basis, used to move directions between world and local shading space
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    /// Basis with `w` along `n` and an arbitrary perpendicular `u`
+    pub fn from_w(n: Vec3) -> Onb {
+        let w = Vec3::unit_vector(n);
+        let a = if w.x.abs() > 0.9 {
+            Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }
+        } else {
+            Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }
+        };
+        let v = Vec3::unit_vector(Vec3::cross(w, a));
+        let u = Vec3::cross(v, w);
+        Onb { u, v, w }
+    }
+
+    /// Basis with `w` along `n` and `u` as close to `tangent` as possible (Gram-Schmidt).
+    /// Falls back to an arbitrary `u` when the tangent is degenerate.
+    pub fn from_w_tangent(n: Vec3, tangent: Vec3) -> Onb {
+        let w = Vec3::unit_vector(n);
+        let t = tangent - Vec3::dot(tangent, w) * w;
+        if t.near_zero() {
+            return Onb::from_w(w);
+        }
+        let u = Vec3::unit_vector(t);
+        let v = Vec3::cross(w, u);
+        Onb { u, v, w }
+    }
+
+    /// Local coordinates `a` expressed in world space
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+
+    /// World space vector `a` expressed in local coordinates
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3 {
+            x: Vec3::dot(a, self.u),
+            y: Vec3::dot(a, self.v),
+            z: Vec3::dot(a, self.w),
+        }
+    }
+}
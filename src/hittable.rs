@@ -1,17 +1,29 @@
 use std::sync::Arc;
 
-use crate::geometry::{Interval, Point3, Ray, Vec3};
+use crate::geometry::{Interval, Onb, Point3, Ray, Vec3};
 use crate::material::Material;
+use crate::sampler::Sampler;
+use crate::util::hash_f64;
 
+#[derive(Clone)]
 pub struct HitRecord {
     pub p: Point3,
+    /// Shading normal, always facing against the incoming ray
     pub normal: Vec3,
+    /// Unit shading tangent along increasing `u`, perpendicular to `normal`
+    pub tangent: Vec3,
+    /// Surface partial derivatives with respect to (u,v)
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
     pub t: f64,
+    pub u: f64,
+    pub v: f64,
     pub front_face: bool,
     pub mat: Arc<dyn Material>,
 }
 
 impl HitRecord {
+    #[allow(clippy::needless_return)]
     pub fn new(mat: Arc<dyn Material>) -> HitRecord {
         return Self {
             p: Vec3 {
@@ -19,27 +31,134 @@ impl HitRecord {
                 y: 0.0,
                 z: 0.0,
             },
-            normal: Vec3 {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            normal: Vec3::zeros(),
+            tangent: Vec3::zeros(),
+            dpdu: Vec3::zeros(),
+            dpdv: Vec3::zeros(),
             t: 0.0,
+            u: 0.0,
+            v: 0.0,
             front_face: true,
             mat,
         };
     }
 
+    /// Orient the normal against `r` and derive the shading tangent from `dpdu`,
+    /// so primitives must fill in `dpdu` first.
     pub fn set_face_normal(&mut self, r: &Ray, outward_normal: Vec3) {
         self.front_face = Vec3::dot(r.dir, outward_normal) < 0.0;
         self.normal = if self.front_face {
             outward_normal
         } else {
             -outward_normal
+        };
+        self.tangent = Onb::from_w_tangent(self.normal, self.dpdu).u;
+    }
+
+    /// Replace the shading normal with a perturbed one (normal/bump mapping).
+    /// The result is kept at least slightly above the current surface so it can never
+    /// flip to the far side, and the tangent is re-orthogonalized against it.
+    pub fn set_shading_normal(&mut self, ns: Vec3) {
+        const MIN_COS: f64 = 0.05;
+
+        let ng = self.normal;
+        let mut n = Vec3::unit_vector(ns);
+        let cos = Vec3::dot(n, ng);
+        if cos.is_nan() {
+            n = ng;
+        } else if cos < MIN_COS {
+            let tangential = n - cos * ng;
+            n = if tangential.near_zero() {
+                ng
+            } else {
+                (1.0 - MIN_COS * MIN_COS).sqrt() * Vec3::unit_vector(tangential) + MIN_COS * ng
+            };
         }
+
+        self.normal = n;
+        self.tangent = Onb::from_w_tangent(n, self.tangent).u;
+    }
+
+    /// Stochastic alpha test against the material's coverage at this hit.
+    /// Returns `false` when the ray should pass through as if nothing was hit.
+    /// The decision is hashed from the ray and hit so it is repeatable.
+    pub fn alpha_test(&self, r: &Ray) -> bool {
+        let alpha = self.mat.alpha(self);
+        if alpha >= 1.0 {
+            return true;
+        }
+        if alpha <= 0.0 {
+            return false;
+        }
+        alpha
+            > hash_f64(&[
+                r.orig.x, r.orig.y, r.orig.z, r.dir.x, r.dir.y, r.dir.z, self.t,
+            ])
     }
 }
 
 pub trait Hittable: Send + Sync {
     fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord>;
+
+    /// Solid-angle pdf of `random` picking direction `dir` from `origin`
+    fn pdf_value(&self, _origin: Point3, _dir: Vec3) -> f64 {
+        0.0
+    }
+
+    /// Random direction from `origin` towards a point on this object, for light sampling
+    fn random(&self, _origin: Point3, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    /// Uniformly sampled point on the surface, as a hit record with the outward normal,
+    /// together with its area pdf. Used to start light paths from emitters.
+    fn sample_surface(&self, _sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        None
+    }
+
+    /// Area pdf of `sample_surface` picking the point `p` on the surface
+    fn surface_pdf(&self, _p: Point3) -> f64 {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{material::Lambertian, sampler::RandomSampler};
+
+    #[test]
+    fn shading_normal_stays_on_the_geometric_normal_side() {
+        let mut sampler = RandomSampler::new(3);
+        let mat: Arc<dyn Material> = Arc::new(Lambertian {
+            albedo: Vec3::zeros(),
+        });
+
+        for i in 0..10_000 {
+            let ng = Vec3::rand_unit_vector(&mut sampler);
+            let mut rec = HitRecord::new(Arc::clone(&mat));
+            rec.normal = ng;
+            rec.tangent = Onb::from_w(ng).u;
+
+            // Arbitrary perturbations, plus the worst cases: straight back, and degenerate
+            let ns = match i % 4 {
+                0 => -ng,
+                1 => Vec3::zeros(),
+                _ => 3.0 * Vec3::rand_unit_vector(&mut sampler),
+            };
+            rec.set_shading_normal(ns);
+
+            let n = rec.normal;
+            assert!((n.length() - 1.0).abs() < 1e-9, "not unit length: {n:?}");
+            assert!(Vec3::dot(n, ng) > 0.0, "{n:?} is behind {ng:?}");
+            assert!(
+                Vec3::dot(rec.tangent, n).abs() < 1e-9,
+                "tangent not perpendicular"
+            );
+        }
+    }
 }
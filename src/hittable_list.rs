@@ -1,23 +1,68 @@
 use std::sync::Arc;
 
 use crate::color::Color;
-use crate::geometry::{Interval, Ray};
+use crate::geometry::{Interval, Point3, Ray, Vec3};
 use crate::hittable::{HitRecord, Hittable};
 use crate::material::Lambertian;
+use crate::sampler::Sampler;
+
+/// What rays that escape the scene see
+#[derive(Debug, Clone, Copy)]
+pub enum Background {
+    /// White-to-blue vertical gradient
+    Sky,
+    Solid(Color),
+}
+
+impl Background {
+    pub fn value(&self, r: &Ray) -> Color {
+        match self {
+            Background::Sky => {
+                let unit_direction = Vec3::unit_vector(r.dir);
+                let a = 0.5 * (unit_direction.y + 1.0);
+
+                (1.0 - a)
+                    * Color {
+                        x: 1.0,
+                        y: 1.0,
+                        z: 1.0,
+                    }
+                    + a * Color {
+                        x: 0.5,
+                        y: 0.7,
+                        z: 1.0,
+                    }
+            }
+            Background::Solid(color) => *color,
+        }
+    }
+}
 
 pub struct HittableList {
-    pub objects: Vec<Box<dyn Hittable>>,
+    pub objects: Vec<Arc<dyn Hittable>>,
+    /// Emissive objects also present in `objects`, used for explicit light sampling
+    pub lights: Vec<Arc<dyn Hittable>>,
+    pub background: Background,
 }
 
 impl HittableList {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            lights: Vec::new(),
+            background: Background::Sky,
         }
     }
 
     pub fn add(&mut self, object: Box<dyn Hittable>) {
-        self.objects.push(object);
+        self.objects.push(object.into());
+    }
+
+    /// Add an emissive object that integrators may sample directly
+    pub fn add_light(&mut self, light: Arc<dyn Hittable>) {
+        self.objects.push(Arc::clone(&light));
+        self.lights.push(light);
     }
 }
 
@@ -56,4 +101,68 @@ impl Hittable for HittableList {
             None
         }
     }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f64 {
+        mixture_pdf_value(&self.objects, origin, dir)
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        mixture_random(&self.objects, origin, sampler)
+    }
+}
+
+impl HittableList {
+    /// Solid-angle pdf of `random_light_dir` picking `dir` from `origin`
+    pub fn light_pdf_value(&self, origin: Point3, dir: Vec3) -> f64 {
+        mixture_pdf_value(&self.lights, origin, dir)
+    }
+
+    /// Direction from `origin` towards a point on a uniformly chosen light
+    pub fn random_light_dir(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        mixture_random(&self.lights, origin, sampler)
+    }
+
+    /// Point on a uniformly chosen light, with the combined area pdf of both choices.
+    /// The record's normal faces outwards, the side emission leaves from. Cut-out parts of
+    /// lights are sampled too, so emission from the point is weighted by its alpha.
+    pub fn sample_light_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let n = self.lights.len();
+        let light = &self.lights[((sampler.next_f64() * n as f64) as usize).min(n - 1)];
+        let (rec, pdf_area) = light.sample_surface(sampler)?;
+        Some((rec, pdf_area / n as f64))
+    }
+}
+
+/// Average pdf over `objects`, matching `mixture_random`'s uniform choice of object
+fn mixture_pdf_value(objects: &[Arc<dyn Hittable>], origin: Point3, dir: Vec3) -> f64 {
+    if objects.is_empty() {
+        return 0.0;
+    }
+
+    let weight = 1.0 / objects.len() as f64;
+    objects
+        .iter()
+        .map(|object| weight * object.pdf_value(origin, dir))
+        .sum()
+}
+
+fn mixture_random(
+    objects: &[Arc<dyn Hittable>],
+    origin: Point3,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    if objects.is_empty() {
+        return Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+    }
+
+    let index = ((sampler.next_f64() * objects.len() as f64) as usize).min(objects.len() - 1);
+    objects[index].random(origin, sampler)
 }
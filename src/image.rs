@@ -0,0 +1,149 @@
+use std::io::{self, Write};
+
+use crate::{color::Color, util::replace_file};
+
+/// Rendered image held in memory as linear radiance, before gamma and quantization
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: i32,
+    pub height: i32,
+    /// Row by row from the top left
+    pub pixels: Vec<Color>,
+}
+
+impl Image {
+    /// Black image
+    pub fn new(width: i32, height: i32) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![Color::zeros(); (width.max(0) * height.max(0)) as usize],
+        }
+    }
+
+    pub fn get(&self, i: i32, j: i32) -> Color {
+        self.pixels[(j * self.width + i) as usize]
+    }
+
+    pub fn set(&mut self, i: i32, j: i32, c: Color) {
+        self.pixels[(j * self.width + i) as usize] = c;
+    }
+
+    /// Plain text PPM, as `Color::to_string` writes pixels
+    pub fn write_ppm(&self, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "P3\n{} {}\n255\n", self.width, self.height)?;
+        for pixel in &self.pixels {
+            write!(out, "{}", pixel.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// 8-bit RGB PNG with the same pixel values as `write_ppm`. The image data is stored
+    /// rather than compressed, which keeps the encoder small at the cost of file size.
+    pub fn write_png(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_png_chunk(out, b"IHDR", &header)?;
+
+        // Every row starts with filter type 0 (none)
+        let row_len = 1 + 3 * self.width.max(0) as usize;
+        let mut raw = Vec::with_capacity(row_len * self.height.max(0) as usize);
+        for row in self.pixels.chunks(self.width.max(1) as usize) {
+            raw.push(0);
+            for pixel in row {
+                raw.extend_from_slice(&pixel.to_rgb8());
+            }
+        }
+        write_png_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+
+        write_png_chunk(out, b"IEND", &[])
+    }
+
+    /// Write the image to `path`, as PNG if it ends in `.png` and as PPM otherwise. The file is
+    /// replaced in one step, so a viewer watching it never sees it half-written.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        replace_file(path, |file| {
+            if path.to_ascii_lowercase().ends_with(".png") {
+                self.write_png(file)
+            } else {
+                self.write_ppm(file)
+            }
+        })
+    }
+}
+
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(&[kind, data]);
+    out.write_all(&crc.to_be_bytes())
+}
+
+/// `data` in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const BLOCK: usize = 65535;
+    let mut out = Vec::with_capacity(data.len() + data.len() / BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = data.chunks(BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(u8::from(blocks.peek().is_none()));
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    // Adler-32 of the uncompressed data
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+/// CRC-32 (as used by PNG and zip) of `parts` one after another
+fn crc32(parts: &[&[u8]]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut n = 0;
+        while n < 256 {
+            let mut c = n as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 == 1 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[n] = c;
+            n += 1;
+        }
+        table
+    };
+
+    let mut crc = !0u32;
+    for part in parts {
+        for &byte in *part {
+            crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,292 @@
+use std::sync::Arc;
+
+use crate::{
+    bdpt::Bdpt,
+    camera::Camera,
+    checkpoint::{StateReader, StateWriter},
+    color::Color,
+    film::SplatFilm,
+    geometry::{Interval, Onb, Ray, Vec3},
+    hittable::{HitRecord, Hittable},
+    hittable_list::HittableList,
+    mlt::Mlt,
+    photon::{PhotonMapper, ProgressivePhotonMapper},
+    sampler::Sampler,
+};
+
+/// What an integrator can see of the render in progress besides the scene
+pub struct RenderContext<'a> {
+    pub camera: &'a Camera,
+    /// Contributions landing on arbitrary pixels, scaled like a camera sample
+    pub splats: &'a SplatFilm,
+}
+
+/// Light transport algorithm used by `Camera` to shade camera rays
+pub trait Integrator: Send + Sync {
+    /// Radiance arriving at the camera along `r`, drawing random numbers from `sampler`
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color;
+
+    /// Called before each pass in which every pixel takes one more sample, to build
+    /// data shared by the whole pass or to splat work of its own worth one sample per pixel
+    fn begin_pass(&self, _pass: u32, _world: &HittableList, _ctx: &RenderContext) {}
+
+    /// Save whatever later passes depend on, for a render checkpoint taken between passes
+    fn write_state(&self, _out: &mut StateWriter) {}
+
+    /// Restore what `write_state` saved before resuming a render. `begin_pass` is next
+    /// called with the pass after the checkpoint rather than with pass 0.
+    fn read_state(&self, _input: &mut StateReader) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the image can be rendered region by region, each region on its own, and still
+    /// come out the same: false if passes update shared state or light lands on other pixels
+    fn renders_regions(&self) -> bool {
+        true
+    }
+}
+
+/// Look up an integrator by its command line name
+pub fn by_name(name: &str, max_depth: i32) -> Option<Arc<dyn Integrator>> {
+    match name {
+        "path" => Some(Arc::new(PathTracer {
+            max_depth,
+            rr_min_depth: 5,
+        })),
+        "whitted" => Some(Arc::new(Whitted { max_depth })),
+        "bdpt" => Some(Arc::new(Bdpt {
+            max_depth,
+            rr_min_depth: 5,
+        })),
+        "photon" => Some(Arc::new(PhotonMapper::new(500_000, 100, max_depth))),
+        "sppm" => Some(Arc::new(ProgressivePhotonMapper::new(100_000, max_depth))),
+        "mlt" => Some(Arc::new(Mlt::new(max_depth))),
+        "ao" => Some(Arc::new(AmbientOcclusion {
+            samples: 16,
+            distance: 1.0,
+        })),
+        _ => None,
+    }
+}
+
+/// Closest hit along `r`, skipping self-intersections right at the origin
+pub(crate) fn trace(world: &HittableList, r: &Ray) -> Option<HitRecord> {
+    world.hit(
+        r,
+        &Interval {
+            min: 0.001,
+            max: f64::INFINITY,
+        },
+    )
+}
+
+/// Unidirectional path tracer with BSDF sampling only.
+/// Follows the path iteratively, tracking throughput, and terminates it with
+/// Russian roulette after `rr_min_depth` bounces or unconditionally at `max_depth`.
+pub struct PathTracer {
+    pub max_depth: i32,
+    pub rr_min_depth: i32,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        _ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut ray = *r;
+        let mut radiance = Color::zeros();
+        let mut throughput = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        for depth in 0..self.max_depth {
+            let Some(hit_record) = trace(world, &ray) else {
+                return radiance + throughput * world.background.value(&ray);
+            };
+
+            radiance += throughput * hit_record.mat.emitted(&hit_record);
+
+            let Some(scatter_result) = hit_record.mat.scatter(&ray, &hit_record, sampler) else {
+                return radiance;
+            };
+            throughput = throughput * scatter_result.attenuation;
+            ray = scatter_result.scattered;
+
+            if depth >= self.rr_min_depth {
+                let survive = f64::min(throughput.max_component(), 0.95);
+                if sampler.next_f64() >= survive {
+                    return radiance;
+                }
+                throughput /= survive;
+            }
+        }
+
+        radiance
+    }
+}
+
+/// Whitted-style tracer: follows specular bounces and shades the first non-specular hit
+/// with direct lighting from `world.lights` plus one sample of the background.
+/// Cheap and noise-free on diffuse surfaces, but has no indirect diffuse light.
+pub struct Whitted {
+    pub max_depth: i32,
+}
+
+impl Whitted {
+    /// Light from each of `world.lights` reaching `rec`, one shadow ray per light
+    fn direct_lighting(
+        world: &HittableList,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut radiance = Color::zeros();
+
+        for light in &world.lights {
+            let dir = light.random(rec.p, sampler);
+            let pdf = light.pdf_value(rec.p, dir);
+            if pdf <= 0.0 {
+                continue;
+            }
+
+            let f = rec.mat.eval(r_in, rec, dir);
+            if f.max_component() <= 0.0 {
+                continue;
+            }
+
+            let shadow_ray = Ray { orig: rec.p, dir };
+            let Some(light_rec) = light.hit(
+                &shadow_ray,
+                &Interval {
+                    min: 0.001,
+                    max: f64::INFINITY,
+                },
+            ) else {
+                continue;
+            };
+
+            // Anything strictly in front of the light blocks it
+            let occluded = world
+                .hit(
+                    &shadow_ray,
+                    &Interval {
+                        min: 0.001,
+                        max: light_rec.t * (1.0 - 1e-6),
+                    },
+                )
+                .is_some();
+            if !occluded {
+                radiance += f * light_rec.mat.emitted(&light_rec) / pdf;
+            }
+        }
+
+        radiance
+    }
+}
+
+impl Integrator for Whitted {
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        _ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut ray = *r;
+        let mut radiance = Color::zeros();
+        let mut throughput = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        for _ in 0..self.max_depth {
+            let Some(rec) = trace(world, &ray) else {
+                return radiance + throughput * world.background.value(&ray);
+            };
+
+            radiance += throughput * rec.mat.emitted(&rec);
+
+            let Some(scatter_result) = rec.mat.scatter(&ray, &rec, sampler) else {
+                return radiance;
+            };
+
+            if scatter_result.pdf > 0.0 {
+                radiance += throughput * Self::direct_lighting(world, &ray, &rec, sampler);
+
+                // The background acts as an extra light, estimated with the BSDF sample
+                if trace(world, &scatter_result.scattered).is_none() {
+                    radiance += throughput
+                        * scatter_result.attenuation
+                        * world.background.value(&scatter_result.scattered);
+                }
+                return radiance;
+            }
+
+            throughput = throughput * scatter_result.attenuation;
+            ray = scatter_result.scattered;
+        }
+
+        radiance
+    }
+}
+
+/// Ambient occlusion preview: the fraction of the cosine-weighted hemisphere at the first
+/// hit that is unoccluded within `distance`, as a gray value
+pub struct AmbientOcclusion {
+    pub samples: u32,
+    pub distance: f64,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        _ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let Some(rec) = trace(world, r) else {
+            return world.background.value(r);
+        };
+
+        let frame = Onb::from_w(rec.normal);
+        let mut unoccluded = 0;
+        for _ in 0..self.samples {
+            let probe = Ray {
+                orig: rec.p,
+                dir: frame.local(Vec3::rand_cosine_direction(sampler)),
+            };
+            let blocked = world
+                .hit(
+                    &probe,
+                    &Interval {
+                        min: 0.001,
+                        max: self.distance,
+                    },
+                )
+                .is_some();
+            if !blocked {
+                unoccluded += 1;
+            }
+        }
+
+        let visibility = unoccluded as f64 / self.samples.max(1) as f64;
+        Color {
+            x: visibility,
+            y: visibility,
+            z: visibility,
+        }
+    }
+}
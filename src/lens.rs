@@ -0,0 +1,746 @@
+use std::{
+    f64::consts::{PI, SQRT_2},
+    fmt, fs,
+    sync::Arc,
+};
+
+use crate::{
+    checkpoint::StateWriter,
+    geometry::{Interval, Point3, Vec3, EMPTY},
+    sampler::Sampler,
+};
+
+/// Shape of the lens opening, which is the shape out-of-focus highlights (bokeh) take.
+/// Apertures live in unit coordinates: x to the right and y up, within the unit circle.
+#[derive(Debug, Clone)]
+pub enum Aperture {
+    /// Round opening of a lens wide open
+    Circle,
+    /// Regular polygon left by `blades` straight diaphragm blades, turned `rotation` degrees
+    /// anticlockwise from having a corner straight up
+    Polygon { blades: u32, rotation: f64 },
+    /// Opening drawn in an image, its brightness the share of light let through
+    Image(Arc<ApertureImage>),
+}
+
+impl Aperture {
+    /// Uniform point in the opening, or in proportion to transmission for an image
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (f64, f64) {
+        match self {
+            Aperture::Circle => {
+                let p = Vec3::rand_in_unit_disc(sampler);
+                (p.x, p.y)
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // Pick one of the triangles fanning out from the center, then a point in it
+                let (u1, u2) = sampler.next_2d();
+                let n = *blades as f64;
+                let triangle = (u1 * n).floor().min(n - 1.0);
+                let s = (u1 * n - triangle).sqrt();
+                let corner = |k: f64| {
+                    let angle = rotation.to_radians() + PI / 2.0 + 2.0 * PI * k / n;
+                    (angle.cos(), angle.sin())
+                };
+                let (a, b) = (corner(triangle), corner(triangle + 1.0));
+                (
+                    s * ((1.0 - u2) * a.0 + u2 * b.0),
+                    s * ((1.0 - u2) * a.1 + u2 * b.1),
+                )
+            }
+            Aperture::Image(image) => image.sample(sampler),
+        }
+    }
+
+    /// Density of `sample` at (`x`,`y`) per unit area
+    pub fn pdf(&self, x: f64, y: f64) -> f64 {
+        match self {
+            Aperture::Circle => {
+                if x.hypot(y) <= 1.0 {
+                    1.0 / PI
+                } else {
+                    0.0
+                }
+            }
+            Aperture::Polygon { blades, .. } => {
+                if self.transmission(x, y) > 0.0 {
+                    let n = *blades as f64;
+                    1.0 / (0.5 * n * (2.0 * PI / n).sin())
+                } else {
+                    0.0
+                }
+            }
+            Aperture::Image(image) => image.pdf(x, y),
+        }
+    }
+
+    /// Share of light the opening lets through at (`x`,`y`): all or nothing for circles and
+    /// polygons, the brightness of an image. The thin lens samples points in proportion to
+    /// it; a lens system weights its rays by it.
+    pub fn transmission(&self, x: f64, y: f64) -> f64 {
+        let inside = match self {
+            Aperture::Circle => x.hypot(y) <= 1.0,
+            Aperture::Polygon { blades, rotation } => {
+                // Inside the edge facing the point's direction
+                let n = *blades as f64;
+                let sector = 2.0 * PI / n;
+                let angle = y.atan2(x) - rotation.to_radians() - PI / 2.0;
+                let offset = angle.rem_euclid(sector) - sector / 2.0;
+                x.hypot(y) * offset.cos() <= (sector / 2.0).cos()
+            }
+            Aperture::Image(image) => return image.transmission(x, y),
+        };
+        if inside {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        match self {
+            Aperture::Circle => out.write_u64(0),
+            Aperture::Polygon { blades, rotation } => {
+                out.write_u64(1);
+                out.write_u64(*blades as u64);
+                out.write_f64(*rotation);
+            }
+            Aperture::Image(image) => {
+                out.write_u64(2);
+                out.write_u64(image.width as u64);
+                out.write_u64(image.height as u64);
+                for &pixel in &image.pixels {
+                    out.write_f64(pixel);
+                }
+            }
+        }
+    }
+}
+
+/// Grayscale aperture image, ready to be sampled by transmission
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+    /// Transmission of each pixel in 0..=1, row by row from the top left
+    pixels: Vec<f64>,
+    /// Running sums of `pixels`
+    cdf: Vec<f64>,
+}
+
+impl ApertureImage {
+    /// Read a PGM or PPM image (plain or binary), taking the mean of the channels and
+    /// no gamma. The image's longer side spans the unit circle's diameter.
+    pub fn load(path: &str) -> Result<ApertureImage, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+        let (width, height, pixels) =
+            read_pnm(&bytes).ok_or(format!("{path}: not a PGM or PPM image"))?;
+        ApertureImage::new(width, height, pixels).ok_or(format!("{path}: aperture is all black"))
+    }
+
+    /// Aperture from transmissions in 0..=1, `None` if nothing is let through
+    pub fn new(width: usize, height: usize, pixels: Vec<f64>) -> Option<ApertureImage> {
+        let cdf: Vec<f64> = pixels
+            .iter()
+            .scan(0.0, |sum, &t| {
+                *sum += t;
+                Some(*sum)
+            })
+            .collect();
+        if cdf.last().copied().unwrap_or(0.0) <= 0.0 {
+            return None;
+        }
+        Some(ApertureImage {
+            width,
+            height,
+            pixels,
+            cdf,
+        })
+    }
+
+    fn sample(&self, sampler: &mut dyn Sampler) -> (f64, f64) {
+        let (u1, u2) = sampler.next_2d();
+        let total = self.cdf[self.cdf.len() - 1];
+        let target = u1 * total;
+        let index = self
+            .cdf
+            .partition_point(|&sum| sum <= target)
+            .min(self.pixels.len() - 1);
+        // Reuse where `u1` fell within the pixel's share across it
+        let before = self.cdf[index] - self.pixels[index];
+        let across = ((target - before) / self.pixels[index]).clamp(0.0, 1.0);
+        let (i, j) = (index % self.width, index / self.width);
+        self.to_unit(i as f64 + across, j as f64 + u2)
+    }
+
+    fn pdf(&self, x: f64, y: f64) -> f64 {
+        let size = self.width.max(self.height) as f64;
+        let pixel_area = (2.0 / size) * (2.0 / size);
+        self.transmission(x, y) / (self.cdf[self.cdf.len() - 1] * pixel_area)
+    }
+
+    fn transmission(&self, x: f64, y: f64) -> f64 {
+        let size = self.width.max(self.height) as f64;
+        let i = ((x * size + self.width as f64) / 2.0).floor();
+        let j = ((self.height as f64 - y * size) / 2.0).floor();
+        if i < 0.0 || j < 0.0 || i >= self.width as f64 || j >= self.height as f64 {
+            return 0.0;
+        }
+        self.pixels[j as usize * self.width + i as usize]
+    }
+
+    /// Unit coordinates of continuous pixel position (`i`,`j`)
+    fn to_unit(&self, i: f64, j: f64) -> (f64, f64) {
+        let size = self.width.max(self.height) as f64;
+        (
+            (2.0 * i - self.width as f64) / size,
+            (self.height as f64 - 2.0 * j) / size,
+        )
+    }
+}
+
+impl fmt::Debug for ApertureImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ApertureImage({}x{}, {})",
+            self.width,
+            self.height,
+            self.cdf[self.cdf.len() - 1]
+        )
+    }
+}
+
+/// Width, height and mean channel values in 0..=1 of a PGM or PPM image
+fn read_pnm(bytes: &[u8]) -> Option<(usize, usize, Vec<f64>)> {
+    let mut pos = 0;
+    // Next whitespace-separated header field, skipping comments
+    let mut field = || -> Option<&[u8]> {
+        loop {
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if bytes.get(pos) != Some(&b'#') {
+                break;
+            }
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+        }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        (pos > start).then(|| &bytes[start..pos])
+    };
+    let number =
+        |field: Option<&[u8]>| -> Option<usize> { std::str::from_utf8(field?).ok()?.parse().ok() };
+
+    let (channels, binary) = match field()? {
+        b"P2" => (1, false),
+        b"P3" => (3, false),
+        b"P5" => (1, true),
+        b"P6" => (3, true),
+        _ => return None,
+    };
+    let width = number(field())?;
+    let height = number(field())?;
+    let max = number(field())?;
+    if width == 0 || height == 0 || max == 0 || max > 65535 {
+        return None;
+    }
+
+    let count = width.checked_mul(height)?.checked_mul(channels)?;
+    let values: Vec<usize> = if binary {
+        // One whitespace byte ends the header
+        let data = bytes.get(pos + 1..)?;
+        if max < 256 {
+            data.get(..count)?.iter().map(|&v| v as usize).collect()
+        } else {
+            data.get(..2 * count)?
+                .chunks(2)
+                .map(|v| (v[0] as usize) << 8 | v[1] as usize)
+                .collect()
+        }
+    } else {
+        (0..count).map(|_| number(field())).collect::<Option<_>>()?
+    };
+
+    let pixels = values
+        .chunks(channels)
+        .map(|pixel| {
+            let sum: usize = pixel.iter().sum();
+            (sum as f64 / (channels * max) as f64).min(1.0)
+        })
+        .collect();
+    Some((width, height, pixels))
+}
+
+/// One refracting surface of a lens, or the aperture stop
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LensElement {
+    /// Radius of curvature in millimetres, positive when the surface bulges towards the
+    /// scene; 0 for the flat aperture stop
+    radius: f64,
+    /// Distance along the axis to the next surface towards the film
+    thickness: f64,
+    /// Refractive index of the glass between this surface and the next towards the film
+    ior: f64,
+    aperture_radius: f64,
+}
+
+/// Film radii, from the center to a corner, the exit pupil is bounded for
+const PUPIL_BOUNDS_RADII: usize = 32;
+/// Points tried along each side of the square on the back element's plane when bounding
+/// the exit pupil
+const PUPIL_BOUNDS_GRID: usize = 64;
+/// Radius of the stop, relative to the lens file's, the exit pupil bounds leave room for:
+/// enough for an aperture image's corners
+const PUPIL_STOP_REACH: f64 = SQRT_2;
+
+/// Camera lens as a stack of spherical elements, traced ray by ray, so it shows the
+/// distortion, aberrations and vignetting of the real lens.
+///
+/// Lens files list surfaces front (scene side) to back, one per line as
+/// `RADIUS THICKNESS IOR APERTURE_DIAMETER` in millimetres, with `#` starting a comment.
+/// Radius 0 marks the aperture stop; IOR 0 or 1 is air. The last thickness, to the film,
+/// is replaced by focusing.
+#[derive(Debug, Clone)]
+pub struct LensSystem {
+    elements: Vec<LensElement>,
+    /// Distance from the back surface to the film
+    film_distance: f64,
+    /// Film diagonal in millimetres
+    sensor_diagonal: f64,
+    /// Scene units per millimetre
+    scale: f64,
+    /// Box on the back element's plane around the rays reaching the scene from film points
+    /// on the positive x axis, for each of `PUPIL_BOUNDS_RADII` bands of distance from the
+    /// film's center
+    pupil_bounds: Vec<(Interval, Interval)>,
+}
+
+/// Where a ray ends after passing through a lens and the share of it the stop lets
+/// through, or `None` if something blocked it
+type Traced = Option<(Point3, Vec3, f64)>;
+
+/// Shape of the aperture stop a ray is traced through
+#[derive(Clone, Copy)]
+enum Stop<'a> {
+    /// Round, with this radius relative to the lens file's
+    Round(f64),
+    Shaped(&'a Aperture),
+}
+
+impl LensSystem {
+    pub fn load(path: &str, sensor_diagonal: f64, scale: f64) -> Result<LensSystem, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        LensSystem::parse(&text, sensor_diagonal, scale).map_err(|msg| format!("{path}: {msg}"))
+    }
+
+    pub fn parse(text: &str, sensor_diagonal: f64, scale: f64) -> Result<LensSystem, String> {
+        let mut elements = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let values = fields
+                .iter()
+                .map(|field| field.parse::<f64>().ok().filter(|v| v.is_finite()))
+                .collect::<Option<Vec<f64>>>();
+            let (Some(&[radius, thickness, ior, diameter]), 4) = (values.as_deref(), fields.len())
+            else {
+                return Err(format!(
+                    "line {}: expected radius, thickness, IOR and aperture diameter",
+                    number + 1
+                ));
+            };
+            if thickness < 0.0 || ior < 0.0 || diameter <= 0.0 {
+                return Err(format!("line {}: invalid lens element", number + 1));
+            }
+            elements.push(LensElement {
+                radius,
+                thickness,
+                ior: if ior == 0.0 { 1.0 } else { ior },
+                aperture_radius: diameter / 2.0,
+            });
+        }
+        let Some(last) = elements.last() else {
+            return Err("no lens elements".to_string());
+        };
+
+        let mut lens = LensSystem {
+            film_distance: last.thickness,
+            elements,
+            sensor_diagonal,
+            scale,
+            pupil_bounds: Vec::new(),
+        };
+        lens.bound_exit_pupil();
+        Ok(lens)
+    }
+
+    /// Scene units per millimetre
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Film width and height in millimetres for an image `width` by `height` pixels
+    pub fn film_size(&self, width: f64, height: f64) -> (f64, f64) {
+        let diagonal = width.hypot(height);
+        (
+            self.sensor_diagonal * width / diagonal,
+            self.sensor_diagonal * height / diagonal,
+        )
+    }
+
+    /// Move the film so that things `distance` millimetres in front of the lens are sharp,
+    /// using the lens's thick lens approximation. Returns whether the lens can focus there.
+    pub fn focus(&mut self, distance: f64) -> bool {
+        let Some(((_, front_principal), (back_focal, back_principal))) = self.cardinal_points()
+        else {
+            return false;
+        };
+        let focal_length = back_principal - back_focal;
+        let object = self.front_z() + distance - front_principal;
+        if focal_length <= 0.0 || object <= focal_length {
+            return false;
+        }
+        // Thin lens equation between the principal planes
+        let image = focal_length * object / (object - focal_length);
+        let film_distance = self.film_distance - (back_principal - image);
+        if film_distance <= 0.0 {
+            return false;
+        }
+        self.film_distance = film_distance;
+        self.bound_exit_pupil();
+        true
+    }
+
+    /// Find `pupil_bounds` by tracing rays from both ends of each band of film radii to a
+    /// grid of points around the back element, as pbrt's realistic camera does
+    fn bound_exit_pupil(&mut self) {
+        let back = self.elements[self.elements.len() - 1];
+        let half = 1.5 * back.aperture_radius;
+        let step = 2.0 * half / PUPIL_BOUNDS_GRID as f64;
+        let film_radius = 0.5 * self.sensor_diagonal;
+        let band = |k: usize| film_radius * k as f64 / PUPIL_BOUNDS_RADII as f64;
+
+        self.pupil_bounds = (0..PUPIL_BOUNDS_RADII)
+            .map(|k| {
+                let (mut bounds_x, mut bounds_y) = (EMPTY, EMPTY);
+                for r in [band(k), band(k + 1)] {
+                    let film = Point3 {
+                        x: r,
+                        y: 0.0,
+                        z: 0.0,
+                    };
+                    for i in 0..=PUPIL_BOUNDS_GRID {
+                        for j in 0..=PUPIL_BOUNDS_GRID {
+                            let target = Point3 {
+                                x: -half + i as f64 * step,
+                                y: -half + j as f64 * step,
+                                z: self.film_distance,
+                            };
+                            let stop = Stop::Round(PUPIL_STOP_REACH);
+                            if self.trace(film, target - film, stop).is_some() {
+                                bounds_x.min = bounds_x.min.min(target.x);
+                                bounds_x.max = bounds_x.max.max(target.x);
+                                bounds_y.min = bounds_y.min.min(target.y);
+                                bounds_y.max = bounds_y.max.max(target.y);
+                            }
+                        }
+                    }
+                }
+                // Widen by a grid step for the rays passing between the points tried
+                if bounds_x.size() >= 0.0 {
+                    for bounds in [&mut bounds_x, &mut bounds_y] {
+                        bounds.min -= step;
+                        bounds.max += step;
+                    }
+                }
+                (bounds_x, bounds_y)
+            })
+            .collect();
+    }
+
+    /// Ray leaving the front of the lens for film point (`x`,`y`) in millimetres, aimed at a
+    /// random point of the exit pupil's bounds, with its weight, or `None` if the lens blocks
+    /// it. The stop has the shape of `aperture`. Positions are relative to the center of the
+    /// front surface, with z along the view direction.
+    ///
+    /// The weight is pbrt's realistic camera's, cos⁴θ times the bounds' area over the squared
+    /// distance to the back element, times what the stop lets through; here it is divided by
+    /// the back element's area over that distance, so that a lens passing all the light
+    /// through its back element exposes the image's center as brightly as a thin lens.
+    pub fn camera_ray(
+        &self,
+        x: f64,
+        y: f64,
+        sampler: &mut dyn Sampler,
+        aperture: &Aperture,
+    ) -> Traced {
+        let r = x.hypot(y);
+        let band = (r / (0.5 * self.sensor_diagonal) * PUPIL_BOUNDS_RADII as f64) as usize;
+        let (bounds_x, bounds_y) = self.pupil_bounds[band.min(PUPIL_BOUNDS_RADII - 1)];
+        if bounds_x.size() < 0.0 {
+            return None;
+        }
+
+        // The bounds are for points on the positive x axis; turn them to the film point's angle
+        let (u1, u2) = sampler.next_2d();
+        let (px, py) = (
+            bounds_x.min + u1 * bounds_x.size(),
+            bounds_y.min + u2 * bounds_y.size(),
+        );
+        let (sin, cos) = if r > 0.0 { (y / r, x / r) } else { (0.0, 1.0) };
+        let target = Point3 {
+            x: px * cos - py * sin,
+            y: px * sin + py * cos,
+            z: self.film_distance,
+        };
+        let film = Point3 { x, y, z: 0.0 };
+        let to_target = target - film;
+        let (orig, dir, transmission) = self.trace(film, to_target, Stop::Shaped(aperture))?;
+
+        let back = self.elements[self.elements.len() - 1];
+        let cos_theta = to_target.z / to_target.length();
+        let area = bounds_x.size() * bounds_y.size();
+        let back_area = PI * back.aperture_radius * back.aperture_radius;
+        let weight = cos_theta.powi(4) * area / back_area * transmission;
+        let front_z = self.front_z();
+        Some((
+            orig - Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: front_z,
+            },
+            dir,
+            weight,
+        ))
+    }
+
+    /// Position along the axis of the front surface, with the film at 0
+    fn front_z(&self) -> f64 {
+        self.film_distance
+            + self.elements[..self.elements.len() - 1]
+                .iter()
+                .map(|element| element.thickness)
+                .sum::<f64>()
+    }
+
+    /// Trace a ray through every surface, from the film if it heads forwards (+z) and from
+    /// the scene otherwise, through a stop of the shape `stop`.
+    fn trace(&self, mut orig: Point3, mut dir: Vec3, stop: Stop) -> Traced {
+        let from_film = dir.z > 0.0;
+        let mut transmission = 1.0;
+        let mut z = self.front_z();
+        let mut surfaces: Vec<(f64, usize)> = Vec::with_capacity(self.elements.len());
+        for (k, element) in self.elements.iter().enumerate() {
+            surfaces.push((z, k));
+            z -= element.thickness;
+        }
+        if from_film {
+            surfaces.reverse();
+        }
+
+        for (z, k) in surfaces {
+            let element = self.elements[k];
+            let outside = if k == 0 {
+                1.0
+            } else {
+                self.elements[k - 1].ior
+            };
+            let t = if element.radius == 0.0 {
+                (z - orig.z) / dir.z
+            } else {
+                sphere_hit(orig, dir, z - element.radius, element.radius)?
+            };
+            if t <= 0.0 {
+                return None;
+            }
+            orig += t * dir;
+
+            let (x, y) = (
+                orig.x / element.aperture_radius,
+                orig.y / element.aperture_radius,
+            );
+            if element.radius == 0.0 {
+                transmission = match stop {
+                    Stop::Round(reach) if x.hypot(y) <= reach => 1.0,
+                    Stop::Round(_) => 0.0,
+                    Stop::Shaped(aperture) => aperture.transmission(x, y),
+                };
+                if transmission <= 0.0 {
+                    return None;
+                }
+                continue;
+            }
+            if x.hypot(y) > 1.0 {
+                return None;
+            }
+
+            let center = Point3 {
+                x: 0.0,
+                y: 0.0,
+                z: z - element.radius,
+            };
+            let mut normal = Vec3::unit_vector(orig - center);
+            if Vec3::dot(normal, dir) > 0.0 {
+                normal = -normal;
+            }
+            let eta = if from_film {
+                element.ior / outside
+            } else {
+                outside / element.ior
+            };
+            dir = refract(Vec3::unit_vector(dir), normal, eta)?;
+        }
+        Some((orig, dir, transmission))
+    }
+
+    /// Focal point and principal plane positions along the axis, for light from the film
+    /// (in front of the lens) and from the scene (behind it)
+    #[allow(clippy::type_complexity)]
+    fn cardinal_points(&self) -> Option<((f64, f64), (f64, f64))> {
+        let height = 0.001 * self.elements[0].aperture_radius;
+        let along = |z: f64| Point3 {
+            x: height,
+            y: 0.0,
+            z,
+        };
+        let forward = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+
+        let from_film = self.trace(along(-1.0), forward, Stop::Round(1.0))?;
+        let from_scene = self.trace(along(self.front_z() + 1.0), -forward, Stop::Round(1.0))?;
+        let points = |(orig, dir, _): (Point3, Vec3, f64)| {
+            if dir.x == 0.0 {
+                return None;
+            }
+            let focal = orig.z - orig.x / dir.x * dir.z;
+            let principal = orig.z + (height - orig.x) / dir.x * dir.z;
+            Some((focal, principal))
+        };
+        Some((points(from_film)?, points(from_scene)?))
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.write_u64(self.elements.len() as u64);
+        for element in &self.elements {
+            out.write_f64(element.radius);
+            out.write_f64(element.thickness);
+            out.write_f64(element.ior);
+            out.write_f64(element.aperture_radius);
+        }
+        out.write_f64(self.film_distance);
+        out.write_f64(self.sensor_diagonal);
+        out.write_f64(self.scale);
+    }
+}
+
+/// Distance along the ray to the sphere of radius `radius` centered on the axis at `center_z`,
+/// at the surface's vertex side
+fn sphere_hit(orig: Point3, dir: Vec3, center_z: f64, radius: f64) -> Option<f64> {
+    let oc = orig
+        - Point3 {
+            x: 0.0,
+            y: 0.0,
+            z: center_z,
+        };
+    let a = dir.length_squared();
+    let h = Vec3::dot(dir, oc);
+    let c = oc.length_squared() - radius * radius;
+    let discriminant = h * h - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // The vertex is the near side when the ray travels from the center's side of it
+    let closer = (dir.z > 0.0) == (radius < 0.0);
+    let root = discriminant.sqrt();
+    Some(if closer {
+        (-h - root) / a
+    } else {
+        (-h + root) / a
+    })
+}
+
+/// Refract unit direction `dir` through a surface with unit `normal` facing against it, or
+/// `None` on total internal reflection
+fn refract(dir: Vec3, normal: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = -Vec3::dot(dir, normal);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t > 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(eta * dir + (eta * cos_i - cos_t) * normal)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+
+    const LENS: &str = include_str!("../lenses/double-gauss-50mm.lens");
+
+    fn focused_lens() -> LensSystem {
+        let mut lens = LensSystem::parse(LENS, 43.3, 0.1).unwrap();
+        assert!(lens.focus(5000.0));
+        lens
+    }
+
+    #[test]
+    fn exit_pupil_rays_carry_what_the_back_element_lets_through() {
+        let lens = focused_lens();
+        let back = lens.elements[lens.elements.len() - 1];
+        let mut sampler = SamplerKind::Random.create(1, 1);
+        let n = 20_000;
+        for (x, y) in [(0.0, 0.0), (10.0, 0.0), (0.0, -10.0), (-12.0, 9.0)] {
+            let film = Point3 { x, y, z: 0.0 };
+            let (mut pupil, mut uniform) = (0.0, 0.0);
+            for k in 0..n {
+                sampler.start_pixel_sample(0, 0, k);
+                if let Some((_, _, weight)) =
+                    lens.camera_ray(x, y, sampler.as_mut(), &Aperture::Circle)
+                {
+                    pupil += weight;
+                }
+
+                // The same through uniform points of the back element
+                let p = Vec3::rand_in_unit_disc(sampler.as_mut());
+                let to_target = Point3 {
+                    x: p.x * back.aperture_radius,
+                    y: p.y * back.aperture_radius,
+                    z: lens.film_distance,
+                } - film;
+                if lens.trace(film, to_target, Stop::Round(1.0)).is_some() {
+                    uniform += (to_target.z / to_target.length()).powi(4);
+                }
+            }
+            let (pupil, uniform) = (pupil / n as f64, uniform / n as f64);
+            assert!(
+                (pupil - uniform).abs() < 0.03 * uniform,
+                "({x}, {y}): {pupil} {uniform}"
+            );
+        }
+    }
+
+    #[test]
+    fn image_stops_weight_rays_by_their_brightness() {
+        let lens = focused_lens();
+        let image =
+            |t: f64| Aperture::Image(Arc::new(ApertureImage::new(2, 2, vec![t; 4]).unwrap()));
+        let (clear, gray) = (image(1.0), image(0.5));
+        let mut sampler = SamplerKind::Random.create(1, 1);
+        for k in 0..100 {
+            sampler.start_pixel_sample(0, 0, k);
+            let clear = lens.camera_ray(3.0, 4.0, sampler.as_mut(), &clear);
+            sampler.start_pixel_sample(0, 0, k);
+            let gray = lens.camera_ray(3.0, 4.0, sampler.as_mut(), &gray);
+            assert_eq!(clear.map(|(.., w)| 0.5 * w), gray.map(|(.., w)| w));
+        }
+    }
+}
@@ -1,8 +1,25 @@
+pub mod animation;
+pub mod bdpt;
 pub mod camera;
+pub mod checkpoint;
 pub mod color;
+pub mod distributed;
+pub mod film;
+pub mod filter;
 pub mod geometry;
 pub mod hittable;
 pub mod hittable_list;
+pub mod image;
+pub mod integrator;
+pub mod lens;
 pub mod material;
+pub mod mlt;
+pub mod photon;
+pub mod progress;
+pub mod quad;
+pub mod sampler;
+pub mod scene;
 pub mod sphere;
+pub mod texture;
+pub mod tiles;
 pub mod util;
@@ -1,44 +1,546 @@
-use std::sync::Arc;
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
 use raytracing::{
-    camera::Camera,
+    animation::{self, Animation},
+    camera::{
+        AdaptiveSampling, Checkpoints, ExposureMode, FisheyeMapping, Projection, SnapshotInterval,
+        Stereo, StereoLayout, StereoRig, StopConditions,
+    },
     color::Color,
+    distributed,
     geometry::{Point3, Vec3},
-    hittable_list::HittableList,
-    material::{Dielectric, Lambertian, Material, Metal},
-    sphere::Sphere,
-    util::{rand_f64, rand_f64_range},
+    sampler::{self, RandomSampler, Sampler},
+    scene::{
+        ApertureDescription, CameraDescription, LensDescription, MaterialDescription,
+        ObjectDescription, RenderSettings, Scene, Shape,
+    },
+    tiles::{self, TileOrder},
 };
 
-fn main() -> std::io::Result<()> {
-    // Create world without Mutex
-    let mut world = HittableList::new();
-
-    let ground_mat = Arc::new(Lambertian {
-        albedo: Color {
-            x: 0.5,
-            y: 0.5,
-            z: 0.5,
+const MAX_DEPTH: i32 = 200;
+
+/// Command line options
+struct Options {
+    settings: RenderSettings,
+    scene: Option<String>,
+    adaptive: Option<AdaptiveSampling>,
+    snapshots: Option<SnapshotInterval>,
+    stop: StopConditions,
+    checkpoints: Option<Checkpoints>,
+    resume_from: Option<String>,
+    tile_size: i32,
+    tile_order: TileOrder,
+    quiet: bool,
+    coordinator: Option<String>,
+    worker: Option<String>,
+    projection: Option<Projection>,
+    stereo: Option<StereoOptions>,
+    aperture: Option<ApertureDescription>,
+    cat_eye: Option<f64>,
+    lens: Option<LensDescription>,
+    f_number: Option<f64>,
+    film_height: Option<f64>,
+    exposure: Option<ExposureMode>,
+    ev: Option<f64>,
+    animation: Option<String>,
+    frames: Option<(i32, i32)>,
+}
+
+/// Stereo as asked for on the command line, with defaults that depend on the scene left open
+struct StereoOptions {
+    interocular: f64,
+    /// The focus distance if not given
+    convergence: Option<f64>,
+    rig: StereoRig,
+    /// Top-bottom for panoramas and side-by-side otherwise if not given
+    layout: Option<StereoLayout>,
+}
+
+impl StereoOptions {
+    fn stereo(&self, camera: &CameraDescription) -> Stereo {
+        let layout = self.layout.unwrap_or(match camera.projection {
+            Projection::Perspective | Projection::Orthographic { .. } => StereoLayout::SideBySide,
+            _ => StereoLayout::TopBottom,
+        });
+        Stereo {
+            interocular: self.interocular,
+            convergence: self.convergence.unwrap_or(camera.focus_dist),
+            rig: self.rig,
+            layout,
+        }
+    }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        settings: RenderSettings {
+            max_depth: MAX_DEPTH,
+            ..RenderSettings::default()
         },
-    });
+        scene: None,
+        adaptive: None,
+        snapshots: None,
+        stop: StopConditions::default(),
+        checkpoints: None,
+        resume_from: None,
+        tile_size: 16,
+        tile_order: TileOrder::Spiral,
+        quiet: false,
+        coordinator: None,
+        worker: None,
+        projection: None,
+        stereo: None,
+        aperture: None,
+        cat_eye: None,
+        lens: None,
+        f_number: None,
+        film_height: None,
+        exposure: None,
+        ev: None,
+        animation: None,
+        frames: None,
+    };
 
-    world.add(Box::new(Sphere {
-        center: Point3 {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--integrator" => {
+                options.settings.integrator = args.next().ok_or(
+                    "--integrator needs a value (path, whitted, bdpt, photon, sppm, mlt or ao)",
+                )?;
+            }
+            "--seed" => {
+                let value = args.next().ok_or("--seed needs a value")?;
+                options.settings.seed = value
+                    .parse()
+                    .map_err(|_| format!("invalid seed `{value}`"))?;
+            }
+            "--sampler" => {
+                let value = args
+                    .next()
+                    .ok_or("--sampler needs a value (random, stratified, halton or sobol)")?;
+                sampler::by_name(&value).ok_or(format!("unknown sampler `{value}`"))?;
+                options.settings.sampler = value;
+            }
+            "--spp" => {
+                let value = args.next().ok_or("--spp needs a value")?;
+                options.settings.samples_per_pixel = value
+                    .parse()
+                    .map_err(|_| format!("invalid sample count `{value}`"))?;
+            }
+            "--adaptive" => {
+                let value = args
+                    .next()
+                    .ok_or("--adaptive needs a target relative error")?;
+                let target_error = value
+                    .parse()
+                    .map_err(|_| format!("invalid target error `{value}`"))?;
+                options.adaptive = Some(AdaptiveSampling {
+                    min_samples: 16,
+                    target_error,
+                    debug_image: None,
+                });
+            }
+            "--min-spp" => {
+                let value = args.next().ok_or("--min-spp needs a value")?;
+                let min_samples = value
+                    .parse()
+                    .map_err(|_| format!("invalid sample count `{value}`"))?;
+                options
+                    .adaptive
+                    .as_mut()
+                    .ok_or("--min-spp must follow --adaptive")?
+                    .min_samples = min_samples;
+            }
+            "--spp-image" => {
+                let path = args.next().ok_or("--spp-image needs a path")?;
+                options
+                    .adaptive
+                    .as_mut()
+                    .ok_or("--spp-image must follow --adaptive")?
+                    .debug_image = Some(path);
+            }
+            "--filter" => {
+                options.settings.filter = args
+                    .next()
+                    .ok_or("--filter needs a value (box, tent, gaussian, mitchell or lanczos)")?;
+            }
+            "--filter-radius" => {
+                let value = args.next().ok_or("--filter-radius needs a value")?;
+                let radius: f64 = value
+                    .parse()
+                    .map_err(|_| format!("invalid filter radius `{value}`"))?;
+                if radius <= 0.0 {
+                    return Err(format!("filter radius must be positive, got `{value}`"));
+                }
+                options.settings.filter_radius = Some(radius);
+            }
+            "--progressive" => {
+                let value = args.next().ok_or(
+                    "--progressive needs an interval (passes like `16`, or seconds like `5s`)",
+                )?;
+                options.snapshots = Some(parse_interval(&value)?);
+            }
+            "--checkpoint" => {
+                let path = args.next().ok_or("--checkpoint needs a path")?;
+                options.checkpoints = Some(Checkpoints {
+                    path,
+                    interval: SnapshotInterval::Seconds(60.0),
+                });
+            }
+            "--checkpoint-every" => {
+                let value = args.next().ok_or(
+                    "--checkpoint-every needs an interval (passes like `16`, or seconds like `5s`)",
+                )?;
+                options
+                    .checkpoints
+                    .as_mut()
+                    .ok_or("--checkpoint-every must follow --checkpoint")?
+                    .interval = parse_interval(&value)?;
+            }
+            "--resume" => {
+                options.resume_from = Some(args.next().ok_or("--resume needs a checkpoint path")?);
+            }
+            "--time-budget" => {
+                let value = args
+                    .next()
+                    .ok_or("--time-budget needs a value in seconds")?;
+                let seconds = value
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or(format!("invalid time budget `{value}`"))?;
+                options.stop.time_budget = Some(seconds);
+            }
+            "--target-mse" => {
+                let value = args.next().ok_or("--target-mse needs a value")?;
+                options.stop.target_mse = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid target error `{value}`"))?,
+                );
+            }
+            "--tile-size" => {
+                let value = args.next().ok_or("--tile-size needs a value")?;
+                options.tile_size = value
+                    .parse()
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or(format!("invalid tile size `{value}`"))?;
+            }
+            "--tile-order" => {
+                let value = args
+                    .next()
+                    .ok_or("--tile-order needs a value (scanline, spiral or hilbert)")?;
+                options.tile_order =
+                    tiles::by_name(&value).ok_or(format!("unknown tile order `{value}`"))?;
+            }
+            "--quiet" => options.quiet = true,
+            "--orthographic" => {
+                let value = args.next().ok_or("--orthographic needs a view height")?;
+                let view_height = value
+                    .parse()
+                    .ok()
+                    .filter(|&height: &f64| height > 0.0)
+                    .ok_or(format!("invalid view height `{value}`"))?;
+                options.projection = Some(Projection::Orthographic { view_height });
+            }
+            "--equirectangular" => options.projection = Some(Projection::Equirectangular),
+            "--cylindrical" => {
+                let value = args
+                    .next()
+                    .ok_or("--cylindrical needs a horizontal field of view in degrees")?;
+                let hfov = value
+                    .parse()
+                    .ok()
+                    .filter(|&hfov: &f64| hfov > 0.0)
+                    .ok_or(format!("invalid field of view `{value}`"))?;
+                options.projection = Some(Projection::Cylindrical { hfov });
+            }
+            "--fisheye" => {
+                let value = args
+                    .next()
+                    .ok_or("--fisheye needs a field of view in degrees")?;
+                let fov = value
+                    .parse()
+                    .ok()
+                    .filter(|&fov: &f64| fov > 0.0 && fov <= 360.0)
+                    .ok_or(format!("invalid field of view `{value}`"))?;
+                options.projection = Some(Projection::Fisheye {
+                    mapping: FisheyeMapping::Equidistant,
+                    fov,
+                });
+            }
+            "--fisheye-mapping" => {
+                let value = args
+                    .next()
+                    .ok_or("--fisheye-mapping needs a value (equidistant or equal-area)")?;
+                let Some(Projection::Fisheye { mapping, .. }) = &mut options.projection else {
+                    return Err("--fisheye-mapping must follow --fisheye".to_string());
+                };
+                *mapping = FisheyeMapping::by_name(&value)
+                    .ok_or(format!("unknown fisheye mapping `{value}`"))?;
+            }
+            "--stereo" => {
+                let value = args
+                    .next()
+                    .ok_or("--stereo needs an interocular distance")?;
+                let interocular = value
+                    .parse()
+                    .ok()
+                    .filter(|&distance: &f64| distance >= 0.0)
+                    .ok_or(format!("invalid interocular distance `{value}`"))?;
+                options.stereo = Some(StereoOptions {
+                    interocular,
+                    convergence: None,
+                    rig: StereoRig::Parallel,
+                    layout: None,
+                });
+            }
+            "--convergence" => {
+                let value = args.next().ok_or("--convergence needs a distance")?;
+                let Some(stereo) = &mut options.stereo else {
+                    return Err("--convergence must follow --stereo".to_string());
+                };
+                stereo.convergence = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&distance: &f64| distance > 0.0)
+                        .ok_or(format!("invalid convergence distance `{value}`"))?,
+                );
+            }
+            "--stereo-rig" => {
+                let value = args
+                    .next()
+                    .ok_or("--stereo-rig needs a value (parallel or toed-in)")?;
+                let Some(stereo) = &mut options.stereo else {
+                    return Err("--stereo-rig must follow --stereo".to_string());
+                };
+                stereo.rig =
+                    StereoRig::by_name(&value).ok_or(format!("unknown stereo rig `{value}`"))?;
+            }
+            "--stereo-layout" => {
+                let value = args
+                    .next()
+                    .ok_or("--stereo-layout needs a value (side-by-side or top-bottom)")?;
+                let Some(stereo) = &mut options.stereo else {
+                    return Err("--stereo-layout must follow --stereo".to_string());
+                };
+                stereo.layout = Some(
+                    StereoLayout::by_name(&value)
+                        .ok_or(format!("unknown stereo layout `{value}`"))?,
+                );
+            }
+            "--aperture-blades" => {
+                let value = args.next().ok_or("--aperture-blades needs a blade count")?;
+                let blades = value
+                    .parse()
+                    .ok()
+                    .filter(|&blades: &u32| blades >= 3)
+                    .ok_or(format!("invalid blade count `{value}`"))?;
+                options.aperture = Some(ApertureDescription::Polygon {
+                    blades,
+                    rotation: 0.0,
+                });
+            }
+            "--aperture-rotation" => {
+                let value = args
+                    .next()
+                    .ok_or("--aperture-rotation needs an angle in degrees")?;
+                let Some(ApertureDescription::Polygon { rotation, .. }) = &mut options.aperture
+                else {
+                    return Err("--aperture-rotation must follow --aperture-blades".to_string());
+                };
+                *rotation = value
+                    .parse()
+                    .map_err(|_| format!("invalid aperture rotation `{value}`"))?;
+            }
+            "--aperture-image" => {
+                let path = args.next().ok_or("--aperture-image needs a path")?;
+                options.aperture = Some(ApertureDescription::Image(path));
+            }
+            "--cat-eye" => {
+                let value = args.next().ok_or("--cat-eye needs a strength")?;
+                options.cat_eye = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&strength: &f64| strength >= 0.0)
+                        .ok_or(format!("invalid cat's-eye strength `{value}`"))?,
+                );
+            }
+            "--lens" => {
+                let path = args.next().ok_or("--lens needs the path of a lens file")?;
+                // A full-frame sensor in a scene measured in metres
+                options.lens = Some(LensDescription {
+                    path,
+                    sensor_diagonal: 43.27,
+                    scale: 0.001,
+                });
+            }
+            "--sensor" => {
+                let value = args
+                    .next()
+                    .ok_or("--sensor needs a diagonal in millimetres")?;
+                let Some(lens) = &mut options.lens else {
+                    return Err("--sensor must follow --lens".to_string());
+                };
+                lens.sensor_diagonal = value
+                    .parse()
+                    .ok()
+                    .filter(|&diagonal: &f64| diagonal > 0.0)
+                    .ok_or(format!("invalid sensor size `{value}`"))?;
+            }
+            "--lens-scale" => {
+                let value = args
+                    .next()
+                    .ok_or("--lens-scale needs scene units per millimetre")?;
+                let Some(lens) = &mut options.lens else {
+                    return Err("--lens-scale must follow --lens".to_string());
+                };
+                lens.scale = value
+                    .parse()
+                    .ok()
+                    .filter(|&scale: &f64| scale > 0.0)
+                    .ok_or(format!("invalid lens scale `{value}`"))?;
+            }
+            "--f-number" => {
+                let value = args.next().ok_or("--f-number needs a value")?;
+                options.f_number = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&f_number: &f64| f_number > 0.0)
+                        .ok_or(format!("invalid f-number `{value}`"))?,
+                );
+            }
+            "--film-height" => {
+                let value = args
+                    .next()
+                    .ok_or("--film-height needs a height in scene units")?;
+                options.film_height = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&height: &f64| height > 0.0)
+                        .ok_or(format!("invalid film height `{value}`"))?,
+                );
+            }
+            "--iso" | "--shutter" => {
+                let value = args.next().ok_or(format!("{arg} needs a value"))?;
+                let number = match value.split_once('/') {
+                    Some((a, b)) => a
+                        .parse::<f64>()
+                        .ok()
+                        .zip(b.parse::<f64>().ok())
+                        .map(|(a, b)| a / b),
+                    None => value.parse().ok(),
+                };
+                let number = number
+                    .filter(|&number: &f64| number > 0.0)
+                    .ok_or(format!("invalid {} `{value}`", &arg[2..]))?;
+                // ISO 100 at 1/60 s unless given
+                let (iso, shutter) = match options.exposure {
+                    Some(ExposureMode::Manual { iso, shutter }) => (iso, shutter),
+                    _ => (100.0, 1.0 / 60.0),
+                };
+                options.exposure = Some(if arg == "--iso" {
+                    ExposureMode::Manual {
+                        iso: number,
+                        shutter,
+                    }
+                } else {
+                    ExposureMode::Manual {
+                        iso,
+                        shutter: number,
+                    }
+                });
+            }
+            "--auto-exposure" => options.exposure = Some(ExposureMode::Auto),
+            "--ev" => {
+                let value = args.next().ok_or("--ev needs a number of stops")?;
+                options.ev = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid exposure compensation `{value}`"))?,
+                );
+            }
+            "--animation" => {
+                options.animation = Some(args.next().ok_or("--animation needs a path")?);
+            }
+            "--frames" => {
+                let value = args.next().ok_or("--frames needs a range like 1-48")?;
+                let frames = animation::parse_frame_range(&value)
+                    .ok_or(format!("invalid frame range `{value}`"))?;
+                options.frames = Some(frames);
+            }
+            "--scene" => options.scene = Some(args.next().ok_or("--scene needs a path")?),
+            "--coordinator" => {
+                options.coordinator = Some(
+                    args.next()
+                        .ok_or("--coordinator needs an address to listen on")?,
+                );
+            }
+            "--worker" => {
+                options.worker = Some(
+                    args.next()
+                        .ok_or("--worker needs the coordinator's address")?,
+                );
+            }
+            _ => return Err(format!("unknown argument `{arg}`")),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Parse a number of passes like `16`, or of seconds like `5s`
+fn parse_interval(value: &str) -> Result<SnapshotInterval, String> {
+    let interval = match value.strip_suffix('s') {
+        Some(seconds) => seconds.parse().ok().map(SnapshotInterval::Seconds),
+        None => value.parse().ok().map(SnapshotInterval::Passes),
+    };
+    interval.ok_or(format!("invalid interval `{value}`"))
+}
+
+/// The final scene of "Ray Tracing in One Weekend", seen from further back, with the small
+/// spheres placed by a sampler seeded with `seed`
+fn random_spheres(seed: u64) -> Scene {
+    let mut scene = Scene::default();
+    let mut sampler = RandomSampler::new(seed);
+
+    add_sphere(
+        &mut scene,
+        Point3 {
             x: 0.0,
             y: -1000.0,
             z: 0.0,
         },
-        radius: 1000.0,
-        mat: ground_mat,
-    }));
+        1000.0,
+        MaterialDescription::Lambertian {
+            albedo: Color {
+                x: 0.5,
+                y: 0.5,
+                z: 0.5,
+            },
+        },
+    );
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = rand_f64();
+            let choose_mat = sampler.next_f64();
             let center = Point3 {
-                x: a as f64 + 0.9 * rand_f64(),
+                x: a as f64 + 0.9 * sampler.next_f64(),
                 y: 0.2,
-                z: b as f64 + 0.9 * rand_f64(),
+                z: b as f64 + 0.9 * sampler.next_f64(),
             };
 
             if (center
@@ -50,123 +552,253 @@ fn main() -> std::io::Result<()> {
             .length()
                 > 0.9
             {
-                let mat: Arc<dyn Material> = if choose_mat < 0.8 {
-                    Arc::new(Lambertian {
-                        albedo: Color::rand() * Color::rand(),
-                    })
+                let mat = if choose_mat < 0.8 {
+                    MaterialDescription::Lambertian {
+                        albedo: Color::rand(&mut sampler) * Color::rand(&mut sampler),
+                    }
                 } else if choose_mat < 0.95 {
-                    Arc::new(Metal {
-                        albedo: Color::rand_range(0.5, 1.0),
-                        fuzz: rand_f64_range(0.0, 0.5),
-                    })
+                    MaterialDescription::Metal {
+                        albedo: Color::rand_range(0.5, 1.0, &mut sampler),
+                        fuzz: sampler.next_range(0.0, 0.5),
+                    }
                 } else {
-                    Arc::new(Dielectric {
+                    MaterialDescription::Dielectric {
                         refraction_index: 1.5,
-                    })
+                    }
                 };
 
-                world.add(Box::new(Sphere {
-                    center,
-                    radius: 0.2,
-                    mat,
-                }));
+                add_sphere(&mut scene, center, 0.2, mat);
             }
         }
     }
 
-    world.add(Box::new(Sphere {
-        center: Point3 {
-            x: 0.0,
-            y: 1.0,
-            z: 1.0,
-        },
-        radius: 1.0,
-        mat: Arc::new(Dielectric {
+    let center = Point3 {
+        x: 0.0,
+        y: 1.0,
+        z: 1.0,
+    };
+    add_sphere(
+        &mut scene,
+        center,
+        1.0,
+        MaterialDescription::Dielectric {
             refraction_index: 1.5,
-        }),
-    }));
-
-    world.add(Box::new(Sphere {
-        center: Point3 {
-            x: 0.0,
-            y: 1.0,
-            z: 1.0,
         },
-        radius: 0.85,
-        mat: Arc::new(Dielectric {
+    );
+    add_sphere(
+        &mut scene,
+        center,
+        0.85,
+        MaterialDescription::Dielectric {
             refraction_index: 1.0 / 1.5,
-        }),
-    }));
+        },
+    );
 
-    world.add(Box::new(Sphere {
-        center: Point3 {
+    add_sphere(
+        &mut scene,
+        Point3 {
             x: -2.0,
             y: 1.0,
             z: 0.0,
         },
-        radius: 1.0,
-        mat: Arc::new(Lambertian {
+        1.0,
+        MaterialDescription::Lambertian {
             albedo: Color {
                 x: 0.4,
                 y: 0.2,
                 z: 0.1,
             },
-        }),
-    }));
+        },
+    );
 
-    world.add(Box::new(Sphere {
-        center: Point3 {
+    add_sphere(
+        &mut scene,
+        Point3 {
             x: 2.0,
             y: 1.0,
             z: 0.0,
         },
-        radius: 1.0,
-        mat: Arc::new(Metal {
+        1.0,
+        MaterialDescription::Metal {
             albedo: Color {
                 x: 0.7,
                 y: 0.6,
                 z: 0.5,
             },
             fuzz: 0.0,
-        }),
-    }));
+        },
+    );
 
     let look_from = Point3 {
         x: -8.0,
         y: 1.8,
         z: 12.0,
     };
-    let look_at = Point3 {
-        x: -1.0,
-        y: 1.0,
-        z: 1.0,
-    };
-
-    let cam = Camera::new(
-        16.0 / 9.0,
-        1920,
-        50,
-        20.0,
+    scene.camera = CameraDescription {
         look_from,
-        look_at,
-        Vec3 {
+        look_at: Point3 {
+            x: -1.0,
+            y: 1.0,
+            z: 1.0,
+        },
+        vup: Vec3 {
             x: 0.0,
             y: 1.0,
             z: 0.0,
         },
-        1.0,
-        (look_from
-            - Point3 {
-                x: 0.0,
-                y: 1.0,
-                z: 1.0,
-            })
-        .length(),
-    );
+        vfov: 20.0,
+        aspect_ratio: 16.0 / 9.0,
+        image_width: 1920,
+        defocus_angle: 1.0,
+        focus_dist: (look_from - center).length(),
+        ..CameraDescription::default()
+    };
+
+    scene
+}
+
+/// Add a sphere to `scene` with a material of its own
+fn add_sphere(scene: &mut Scene, center: Point3, radius: f64, material: MaterialDescription) {
+    let name = format!("m{}", scene.materials.len());
+    scene.materials.push((name.clone(), material));
+    scene.objects.push(ObjectDescription {
+        shape: Shape::Sphere { center, radius },
+        material: name,
+    });
+}
+
+/// Connect to the coordinator at `addr`, waiting a while for it to start listening
+fn connect(addr: &str) -> std::io::Result<TcpStream> {
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                if start.elapsed() > Duration::from_secs(30) {
+                    return Err(e);
+                }
+                thread::sleep(Duration::from_millis(500));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let options = parse_args().map_err(|msg| Error::new(ErrorKind::InvalidInput, msg))?;
+    let invalid_input = |msg| Error::new(ErrorKind::InvalidInput, msg);
+
+    if let Some(addr) = &options.worker {
+        return distributed::work(connect(addr)?);
+    }
+
+    let mut scene = match &options.scene {
+        Some(path) => Scene::parse(&fs::read_to_string(path)?)
+            .map_err(|msg| invalid_input(format!("{path}: {msg}")))?,
+        None => random_spheres(options.settings.seed),
+    };
+    if let Some(projection) = options.projection {
+        scene.camera.projection = projection;
+    }
+    if let Some(stereo) = &options.stereo {
+        scene.camera.stereo = Some(stereo.stereo(&scene.camera));
+    }
+    if let Some(aperture) = &options.aperture {
+        scene.camera.aperture = aperture.clone();
+    }
+    if let Some(strength) = options.cat_eye {
+        scene.camera.cat_eye = strength;
+    }
+    if let Some(lens) = &options.lens {
+        scene.camera.lens = Some(lens.clone());
+    }
+    if let Some(f_number) = options.f_number {
+        scene.camera.f_number = Some(f_number);
+    }
+    if let Some(height) = options.film_height {
+        scene.camera.film_height = height;
+    }
+    if let Some(mode) = options.exposure {
+        scene.camera.exposure.mode = mode;
+    }
+    if let Some(stops) = options.ev {
+        scene.camera.exposure.compensation = stops;
+    }
+
+    if let Some(addr) = &options.coordinator {
+        if options.animation.is_some() {
+            return Err(invalid_input(
+                "--animation cannot be used with --coordinator".to_string(),
+            ));
+        }
+        if options.adaptive.is_some()
+            || options.snapshots.is_some()
+            || options.checkpoints.is_some()
+            || options.resume_from.is_some()
+            || options.stop.time_budget.is_some()
+            || options.stop.target_mse.is_some()
+        {
+            return Err(invalid_input(
+                "adaptive sampling, snapshots, checkpoints and stop conditions cannot be used with --coordinator".to_string(),
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        let tile_size = options.tile_size;
+        let image = distributed::coordinate(
+            listener,
+            &scene,
+            &options.settings,
+            tile_size,
+            options.quiet,
+        )?;
+        return image.save("out.ppm");
+    }
+
+    if let Some(path) = &options.animation {
+        if options.checkpoints.is_some() || options.resume_from.is_some() {
+            return Err(invalid_input(
+                "checkpoints cannot be used with --animation".to_string(),
+            ));
+        }
+        let animation = Animation::parse(&fs::read_to_string(path)?)
+            .map_err(|msg| invalid_input(format!("{path}: {msg}")))?;
+        let frames = match options.frames {
+            Some((first, last)) => first..=last,
+            None => animation.frame_range(),
+        };
+        for frame in frames {
+            if !options.quiet {
+                eprintln!("Frame {frame}");
+            }
+            let frame_scene = animation
+                .apply(&scene, frame as f64)
+                .map_err(|msg| invalid_input(format!("{path}: {msg}")))?;
+            render(&frame_scene, &options, &format!("out_{frame:04}.png"))?;
+        }
+        return Ok(());
+    }
+
+    render(&scene, &options, "out.ppm")
+}
+
+/// Render `scene` locally as `options` say, writing the image to `path`
+fn render(scene: &Scene, options: &Options, path: &str) -> std::io::Result<()> {
+    let invalid_input = |msg| Error::new(ErrorKind::InvalidInput, msg);
+    let world = scene.build_world().map_err(invalid_input)?;
+    let mut cam = scene
+        .build_camera(&options.settings)
+        .map_err(invalid_input)?;
+    cam.set_adaptive_sampling(options.adaptive.clone());
+    cam.set_progressive(options.snapshots);
+    cam.set_stop_conditions(options.stop);
+    cam.set_checkpoints(options.checkpoints.clone());
+    cam.set_resume_from(options.resume_from.clone());
+    cam.set_tiles(options.tile_size, options.tile_order);
+    cam.set_quiet(options.quiet);
 
     // Wrap world in Arc before passing it
     let world = Arc::new(world);
-    cam.render(world, "out.ppm")?;
+    cam.render(world, path)?;
 
     Ok(())
 }
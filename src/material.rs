@@ -1,17 +1,57 @@
+use std::{f64::consts::PI, sync::Arc};
+
 use crate::{
     color::Color,
-    geometry::{Ray, Vec3},
-    hittable::HitRecord,
-    util::rand_f64,
+    geometry::{Interval, Onb, Ray, Vec3},
+    hittable::{HitRecord, Hittable},
+    sampler::Sampler,
+    texture::{SolidColor, Texture},
 };
 
 pub struct ScatterResult {
     pub attenuation: Color,
     pub scattered: Ray,
+    /// Solid-angle pdf of `scattered.dir`, or 0 for specular scattering that `eval`
+    /// and `pdf` cannot reproduce
+    pub pdf: f64,
 }
 
 pub trait Material: Send + Sync {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult>;
+    /// Sample a scattered ray. `attenuation` is the BSDF times cosine over the pdf.
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult>;
+
+    /// BSDF times the cosine at the shading normal for light arriving from direction `wi`.
+    /// Specular materials return zero; used for light sampling and MIS.
+    fn eval(&self, _r_in: &Ray, _rec: &HitRecord, _wi: Vec3) -> Color {
+        Color::zeros()
+    }
+
+    /// Solid-angle pdf with which `scatter` picks direction `wi`
+    fn pdf(&self, _r_in: &Ray, _rec: &HitRecord, _wi: Vec3) -> f64 {
+        0.0
+    }
+
+    /// Radiance emitted from the surface towards the ray that hit it
+    fn emitted(&self, _rec: &HitRecord) -> Color {
+        Color::zeros()
+    }
+
+    /// Coverage of the surface at the hit, in \[0,1\]. Primitives treat the hit as a
+    /// miss with probability `1 - alpha`.
+    fn alpha(&self, _rec: &HitRecord) -> f64 {
+        1.0
+    }
+
+    /// Whether the BSDF at `rec` has a diffuse lobe, whose light density estimates such as
+    /// photon maps can shade. Glossy and specular lobes are traced instead.
+    fn has_diffuse_component(&self, _rec: &HitRecord) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian {
@@ -19,8 +59,13 @@ pub struct Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult> {
-        let mut scatter_direction = rec.normal + Vec3::rand_unit_vector();
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        let mut scatter_direction = rec.normal + Vec3::rand_unit_vector(sampler);
         if scatter_direction.near_zero() {
             scatter_direction = rec.normal;
         }
@@ -30,12 +75,51 @@ impl Material for Lambertian {
             dir: scatter_direction,
         };
         let attenuation = self.albedo;
+        let pdf = self.pdf(r_in, rec, scatter_direction);
 
         Some(ScatterResult {
             attenuation,
             scattered,
+            pdf,
         })
     }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        self.albedo * self.pdf(r_in, rec, wi)
+    }
+
+    fn pdf(&self, _r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        let cos_theta = Vec3::dot(Vec3::unit_vector(wi), rec.normal);
+        f64::max(cos_theta, 0.0) / PI
+    }
+
+    fn has_diffuse_component(&self, _rec: &HitRecord) -> bool {
+        true
+    }
+}
+
+/// One-sided area light: emits `emit` from the front face and absorbs everything
+pub struct DiffuseLight {
+    pub emit: Color,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(
+        &self,
+        _r_in: &Ray,
+        _rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        None
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        if rec.front_face {
+            self.emit
+        } else {
+            Color::zeros()
+        }
+    }
 }
 
 pub struct Metal {
@@ -44,10 +128,16 @@ pub struct Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult> {
+    #[allow(clippy::needless_return)]
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
         let mut reflected = Vec3::reflect(r_in.dir, rec.normal);
-        reflected =
-            Vec3::unit_vector(reflected) + (f64::min(self.fuzz, 1.0) * Vec3::rand_unit_vector());
+        reflected = Vec3::unit_vector(reflected)
+            + (f64::min(self.fuzz, 1.0) * Vec3::rand_unit_vector(sampler));
         let scattered = Ray {
             orig: rec.p,
             dir: reflected,
@@ -58,6 +148,7 @@ impl Material for Metal {
             return Some(ScatterResult {
                 attenuation,
                 scattered,
+                pdf: 0.0,
             });
         } else {
             None
@@ -70,7 +161,13 @@ pub struct Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult> {
+    #[allow(clippy::needless_late_init)]
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
         let attenuation = Color {
             x: 1.0,
             y: 1.0,
@@ -87,9 +184,10 @@ impl Material for Dielectric {
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
         let cannot_refract = ri * sin_theta > 1.0;
+
         let direction: Vec3;
 
-        if cannot_refract || Self::schlick_reflectance(cos_theta, ri) > rand_f64() {
+        if cannot_refract || Self::schlick_reflectance(cos_theta, ri) > sampler.next_f64() {
             direction = Vec3::reflect(unit_dir, rec.normal)
         } else {
             direction = Vec3::refract(unit_dir, rec.normal, ri);
@@ -103,6 +201,7 @@ impl Material for Dielectric {
         Some(ScatterResult {
             attenuation,
             scattered,
+            pdf: 0.0,
         })
     }
 }
@@ -114,3 +213,760 @@ impl Dielectric {
         r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
     }
 }
+
+/// Stochastically picks `b` with probability `weight` (luminance of the texture) and `a` otherwise
+pub struct Mix {
+    pub a: Arc<dyn Material>,
+    pub b: Arc<dyn Material>,
+    pub weight: Arc<dyn Texture>,
+}
+
+impl Mix {
+    /// Blend with a constant scalar weight
+    pub fn new(a: Arc<dyn Material>, b: Arc<dyn Material>, weight: f64) -> Mix {
+        Mix {
+            a,
+            b,
+            weight: Arc::new(SolidColor::scalar(weight)),
+        }
+    }
+
+    fn weight_at(&self, rec: &HitRecord) -> f64 {
+        self.weight
+            .value(rec.u, rec.v, rec.p)
+            .luminance()
+            .clamp(0.0, 1.0)
+    }
+}
+
+impl Material for Mix {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        let w = self.weight_at(rec);
+        let chosen = if sampler.next_f64() < w {
+            &self.b
+        } else {
+            &self.a
+        };
+        let mut result = chosen.scatter(r_in, rec, sampler)?;
+
+        // A non-specular sample could also have come from the other material's lobe,
+        // so weight it against the full mixture rather than the chosen material alone
+        if result.pdf > 0.0 {
+            let dir = result.scattered.dir;
+            let pdf = self.pdf(r_in, rec, dir);
+            if pdf <= 0.0 {
+                return None;
+            }
+            result.attenuation = self.eval(r_in, rec, dir) / pdf;
+            result.pdf = pdf;
+        }
+
+        Some(result)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        let w = self.weight_at(rec);
+        (1.0 - w) * self.a.eval(r_in, rec, wi) + w * self.b.eval(r_in, rec, wi)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        let w = self.weight_at(rec);
+        (1.0 - w) * self.a.pdf(r_in, rec, wi) + w * self.b.pdf(r_in, rec, wi)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        let w = self.weight_at(rec);
+        (1.0 - w) * self.a.emitted(rec) + w * self.b.emitted(rec)
+    }
+
+    fn alpha(&self, rec: &HitRecord) -> f64 {
+        // Coverage is blended deterministically so the alpha test stays repeatable
+        let w = self.weight_at(rec);
+        (1.0 - w) * self.a.alpha(rec) + w * self.b.alpha(rec)
+    }
+
+    fn has_diffuse_component(&self, rec: &HitRecord) -> bool {
+        let w = self.weight_at(rec);
+        (w < 1.0 && self.a.has_diffuse_component(rec))
+            || (w > 0.0 && self.b.has_diffuse_component(rec))
+    }
+}
+
+/// Wraps `mat` with an alpha mask; rays pass straight through where the mask is dark
+pub struct Cutout {
+    pub mat: Arc<dyn Material>,
+    pub alpha: Arc<dyn Texture>,
+}
+
+impl Material for Cutout {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        self.mat.scatter(r_in, rec, sampler)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        self.mat.eval(r_in, rec, wi)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        self.mat.pdf(r_in, rec, wi)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        self.mat.emitted(rec)
+    }
+
+    fn alpha(&self, rec: &HitRecord) -> f64 {
+        self.alpha.value(rec.u, rec.v, rec.p).luminance() * self.mat.alpha(rec)
+    }
+
+    fn has_diffuse_component(&self, rec: &HitRecord) -> bool {
+        self.mat.has_diffuse_component(rec)
+    }
+}
+
+/// Perturbs the shading normal of `mat` with a tangent-space normal map,
+/// where a texel color `c` encodes the normal `2c - 1` in (tangent, bitangent, normal) space
+pub struct NormalMap {
+    pub mat: Arc<dyn Material>,
+    pub map: Arc<dyn Texture>,
+}
+
+impl NormalMap {
+    fn shading(&self, rec: &HitRecord) -> HitRecord {
+        let c = self.map.value(rec.u, rec.v, rec.p);
+        let mut local = 2.0 * c
+            - Vec3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            };
+
+        // Keep the bitangent running along increasing v, whichever way the surface faces
+        let frame = Onb::from_w_tangent(rec.normal, rec.tangent);
+        if Vec3::dot(frame.v, rec.dpdv) < 0.0 {
+            local.y = -local.y;
+        }
+
+        let mut shading = rec.clone();
+        shading.set_shading_normal(frame.local(local));
+        shading
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        self.mat.scatter(r_in, &self.shading(rec), sampler)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        self.mat.eval(r_in, &self.shading(rec), wi)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        self.mat.pdf(r_in, &self.shading(rec), wi)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        self.mat.emitted(rec)
+    }
+
+    fn alpha(&self, rec: &HitRecord) -> f64 {
+        self.mat.alpha(rec)
+    }
+
+    fn has_diffuse_component(&self, rec: &HitRecord) -> bool {
+        self.mat.has_diffuse_component(rec)
+    }
+}
+
+/// Perturbs the shading normal of `mat` as if the surface were displaced along its normal
+/// by `scale` times the luminance of `height`
+pub struct BumpMap {
+    pub mat: Arc<dyn Material>,
+    pub height: Arc<dyn Texture>,
+    pub scale: f64,
+    /// Finite difference step in (u,v) used to estimate height derivatives: about one
+    /// texel of `height`, or less than the size of its smallest features
+    pub step: f64,
+}
+
+impl BumpMap {
+    fn shading(&self, rec: &HitRecord) -> HitRecord {
+        let du = self.step;
+        let dv = self.step;
+
+        let h = |u: f64, v: f64, p| self.scale * self.height.value(u, v, p).luminance();
+        let h0 = h(rec.u, rec.v, rec.p);
+        let dhdu = (h(rec.u + du, rec.v, rec.p + du * rec.dpdu) - h0) / du;
+        let dhdv = (h(rec.u, rec.v + dv, rec.p + dv * rec.dpdv) - h0) / dv;
+
+        let dpdu = rec.dpdu + dhdu * rec.normal;
+        let dpdv = rec.dpdv + dhdv * rec.normal;
+        let mut n = Vec3::cross(dpdu, dpdv);
+
+        let mut shading = rec.clone();
+        if n.near_zero() {
+            return shading;
+        }
+
+        // The cross product's orientation depends on the parameterization, not the ray
+        if Vec3::dot(n, rec.normal) < 0.0 {
+            n = -n;
+        }
+        shading.set_shading_normal(n);
+        shading
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        self.mat.scatter(r_in, &self.shading(rec), sampler)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        self.mat.eval(r_in, &self.shading(rec), wi)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        self.mat.pdf(r_in, &self.shading(rec), wi)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        self.mat.emitted(rec)
+    }
+
+    fn alpha(&self, rec: &HitRecord) -> f64 {
+        self.mat.alpha(rec)
+    }
+
+    fn has_diffuse_component(&self, rec: &HitRecord) -> bool {
+        self.mat.has_diffuse_component(rec)
+    }
+}
+
+/// Random-walk subsurface scattering inside a closed `boundary`.
+///
+/// Rays refract into the surface, then take exponentially distributed steps with a
+/// per-channel `mean_free_path`, scattering isotropically with single-scattering `albedo`
+/// until they cross `boundary` again. `boundary` must be the same closed shape this
+/// material is attached to (its own material is ignored); scenes build both from the
+/// object's shape description.
+pub struct Subsurface {
+    pub boundary: Arc<dyn Hittable>,
+    pub albedo: Color,
+    pub mean_free_path: Color,
+    pub refraction_index: f64,
+    pub max_steps: u32,
+}
+
+impl Subsurface {
+    pub fn new(
+        boundary: Arc<dyn Hittable>,
+        albedo: Color,
+        mean_free_path: Color,
+        refraction_index: f64,
+    ) -> Subsurface {
+        Subsurface {
+            boundary,
+            albedo,
+            mean_free_path,
+            refraction_index,
+            max_steps: 256,
+        }
+    }
+
+    /// Reflect or refract `dir` at a boundary with normal `n` facing `dir`'s origin side.
+    /// Returns the new direction and whether it crossed the boundary.
+    fn fresnel_bounce(dir: Vec3, n: Vec3, ri: f64, sampler: &mut dyn Sampler) -> (Vec3, bool) {
+        let unit_dir = Vec3::unit_vector(dir);
+        let cos_theta = f64::min(Vec3::dot(-unit_dir, n), 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+        if ri * sin_theta > 1.0
+            || Dielectric::schlick_reflectance(cos_theta, ri) > sampler.next_f64()
+        {
+            (Vec3::reflect(unit_dir, n), false)
+        } else {
+            (Vec3::refract(unit_dir, n, ri), true)
+        }
+    }
+
+    /// Walk from `orig` along `dir` inside the medium. Returns the path throughput and
+    /// the ray leaving the surface, or `None` if the walk was absorbed or lost.
+    fn walk(
+        &self,
+        mut orig: Vec3,
+        mut dir: Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        let sigma_t = [
+            1.0 / self.mean_free_path.x,
+            1.0 / self.mean_free_path.y,
+            1.0 / self.mean_free_path.z,
+        ];
+        let albedo = [self.albedo.x, self.albedo.y, self.albedo.z];
+        let mut throughput = [1.0; 3];
+
+        for _ in 0..self.max_steps {
+            // Sample a free-flight distance from one channel chosen uniformly, and weight
+            // by the one-sample MIS (average) pdf over all channels
+            let channel = ((sampler.next_f64() * 3.0) as usize).min(2);
+            let dist = -(1.0 - sampler.next_f64()).ln() / sigma_t[channel];
+
+            let ray = Ray { orig, dir };
+            let len = dir.length();
+            let exit = self.boundary.hit(
+                &ray,
+                &Interval {
+                    min: 1e-4 / len,
+                    max: dist / len,
+                },
+            );
+
+            match exit {
+                Some(rec) => {
+                    // Leaves the medium before the next scattering event
+                    let t = rec.t * len;
+                    let tr = sigma_t.map(|s| (-s * t).exp());
+                    let pdf = (tr[0] + tr[1] + tr[2]) / 3.0;
+                    for c in 0..3 {
+                        throughput[c] *= tr[c] / pdf;
+                    }
+
+                    // The record's normal faces us, so inside-to-outside uses ri directly
+                    let (new_dir, crossed) =
+                        Self::fresnel_bounce(dir, rec.normal, self.refraction_index, sampler);
+                    if crossed {
+                        return Some(ScatterResult {
+                            attenuation: Color {
+                                x: throughput[0],
+                                y: throughput[1],
+                                z: throughput[2],
+                            },
+                            scattered: Ray {
+                                orig: rec.p,
+                                dir: new_dir,
+                            },
+                            pdf: 0.0,
+                        });
+                    }
+                    orig = rec.p;
+                    dir = new_dir;
+                }
+                None => {
+                    let tr = sigma_t.map(|s| (-s * dist).exp());
+                    let pdf = (0..3).map(|c| sigma_t[c] * tr[c]).sum::<f64>() / 3.0;
+                    for c in 0..3 {
+                        throughput[c] *= albedo[c] * sigma_t[c] * tr[c] / pdf;
+                    }
+
+                    if throughput.iter().all(|&t| t <= 0.0) {
+                        return None;
+                    }
+
+                    orig = ray.at(dist / len);
+                    dir = Vec3::rand_unit_vector(sampler);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Material for Subsurface {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        let ri = if rec.front_face {
+            1.0 / self.refraction_index
+        } else {
+            self.refraction_index
+        };
+        let (dir, crossed) = Self::fresnel_bounce(r_in.dir, rec.normal, ri, sampler);
+
+        // Entering from outside, or bouncing back in when reaching the surface from inside
+        // without a walk (e.g. camera inside the shape)
+        if crossed == rec.front_face {
+            return self.walk(rec.p, dir, sampler);
+        }
+
+        Some(ScatterResult {
+            attenuation: Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            scattered: Ray { orig: rec.p, dir },
+            pdf: 0.0,
+        })
+    }
+}
+
+/// Anisotropic GGX microfacet conductor (brushed metal).
+///
+/// `alpha_x` is the roughness along the hit record's tangent (the brushing direction),
+/// `alpha_y` across it. `albedo` is the reflectance at normal incidence for Schlick's Fresnel.
+pub struct AnisotropicMetal {
+    pub albedo: Color,
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl AnisotropicMetal {
+    /// Perceptual `roughness` in \[0,1\]; `anisotropy` in \[0,1\) stretches highlights along the tangent
+    pub fn new(albedo: Color, roughness: f64, anisotropy: f64) -> AnisotropicMetal {
+        let alpha = roughness * roughness;
+        let aspect = (1.0 - 0.9 * anisotropy).sqrt();
+        AnisotropicMetal {
+            albedo,
+            alpha_x: f64::max(alpha / aspect, 1e-3),
+            alpha_y: f64::max(alpha * aspect, 1e-3),
+        }
+    }
+
+    /// GGX normal distribution for microfacet normal `m` in the local shading frame
+    fn d(&self, m: Vec3) -> f64 {
+        let x = m.x / self.alpha_x;
+        let y = m.y / self.alpha_y;
+        let e = x * x + y * y + m.z * m.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    /// Smith masking auxiliary function
+    fn lambda(&self, w: Vec3) -> f64 {
+        let a2_tan2 = (self.alpha_x * self.alpha_x * w.x * w.x
+            + self.alpha_y * self.alpha_y * w.y * w.y)
+            / (w.z * w.z);
+        0.5 * (-1.0 + (1.0 + a2_tan2).sqrt())
+    }
+
+    fn fresnel(&self, cos_theta: f64) -> Color {
+        let f0 = self.albedo;
+        f0 + (1.0 - cos_theta).powi(5)
+            * (Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            } - f0)
+    }
+
+    /// Sample a visible microfacet normal for outgoing direction `wo` (Heitz 2018)
+    fn sample_visible_normal(&self, wo: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let vh = Vec3::unit_vector(Vec3 {
+            x: self.alpha_x * wo.x,
+            y: self.alpha_y * wo.y,
+            z: wo.z,
+        });
+
+        let lensq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if lensq > 0.0 {
+            Vec3 {
+                x: -vh.y,
+                y: vh.x,
+                z: 0.0,
+            } / lensq.sqrt()
+        } else {
+            Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }
+        };
+        let t2 = Vec3::cross(vh, t1);
+
+        let (u1, u2) = sampler.next_2d();
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+
+        let nh = p1 * t1 + p2 * t2 + f64::max(0.0, 1.0 - p1 * p1 - p2 * p2).sqrt() * vh;
+        Vec3::unit_vector(Vec3 {
+            x: self.alpha_x * nh.x,
+            y: self.alpha_y * nh.y,
+            z: f64::max(nh.z, 1e-6),
+        })
+    }
+
+    /// Local frame plus the outgoing direction in it, or `None` below the surface
+    fn local_wo(r_in: &Ray, rec: &HitRecord) -> Option<(Onb, Vec3)> {
+        let frame = Onb::from_w_tangent(rec.normal, rec.tangent);
+        let wo = frame.to_local(-Vec3::unit_vector(r_in.dir));
+        if wo.z <= 0.0 {
+            None
+        } else {
+            Some((frame, wo))
+        }
+    }
+}
+
+impl Material for AnisotropicMetal {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        let (frame, wo) = Self::local_wo(r_in, rec)?;
+
+        let m = self.sample_visible_normal(wo, sampler);
+        let wo_m = Vec3::dot(wo, m);
+        let wi = 2.0 * wo_m * m - wo;
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        // f * cos / pdf reduces to F * G2 / G1 for visible normal sampling
+        let g1 = 1.0 / (1.0 + self.lambda(wo));
+        let g2 = 1.0 / (1.0 + self.lambda(wo) + self.lambda(wi));
+        let attenuation = (g2 / g1) * self.fresnel(wo_m);
+        let pdf = g1 * self.d(m) / (4.0 * wo.z);
+
+        Some(ScatterResult {
+            attenuation,
+            scattered: Ray {
+                orig: rec.p,
+                dir: frame.local(wi),
+            },
+            pdf,
+        })
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        let Some((frame, wo)) = Self::local_wo(r_in, rec) else {
+            return Color::zeros();
+        };
+        let wi = frame.to_local(Vec3::unit_vector(wi));
+        if wi.z <= 0.0 {
+            return Color::zeros();
+        }
+
+        let m = Vec3::unit_vector(wo + wi);
+        let g2 = 1.0 / (1.0 + self.lambda(wo) + self.lambda(wi));
+        (self.d(m) * g2 / (4.0 * wo.z)) * self.fresnel(Vec3::dot(wo, m))
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        let Some((frame, wo)) = Self::local_wo(r_in, rec) else {
+            return 0.0;
+        };
+        let wi = frame.to_local(Vec3::unit_vector(wi));
+        if wi.z <= 0.0 {
+            return 0.0;
+        }
+
+        let m = Vec3::unit_vector(wo + wi);
+        let g1 = 1.0 / (1.0 + self.lambda(wo));
+        g1 * self.d(m) / (4.0 * wo.z)
+    }
+}
+
+/// Cloth and velvet: a diffuse base plus a Charlie sheen lobe (Estevez & Kulla 2017)
+/// with Ashikhmin-style visibility, giving bright grazing retro-reflection.
+pub struct Sheen {
+    pub albedo: Color,
+    pub sheen: Color,
+    pub roughness: f64,
+}
+
+impl Sheen {
+    fn charlie_d(&self, cos_h: f64) -> f64 {
+        let inv_r = 1.0 / f64::max(self.roughness, 1e-3);
+        let sin2_h = f64::max(1.0 - cos_h * cos_h, 0.0);
+        (2.0 + inv_r) * sin2_h.powf(0.5 * inv_r) / (2.0 * PI)
+    }
+
+    fn brdf_cos(&self, n: Vec3, wo: Vec3, wi: Vec3) -> Color {
+        let cos_o = Vec3::dot(n, wo);
+        let cos_i = Vec3::dot(n, wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Color::zeros();
+        }
+
+        let h = Vec3::unit_vector(wo + wi);
+        let visibility = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+        let sheen = self.charlie_d(Vec3::dot(n, h)) * visibility;
+
+        cos_i * (self.albedo / PI + sheen * self.sheen)
+    }
+}
+
+impl Material for Sheen {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterResult> {
+        let dir = Onb::from_w(rec.normal).local(Vec3::rand_cosine_direction(sampler));
+        let pdf = self.pdf(r_in, rec, dir);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(ScatterResult {
+            attenuation: self.eval(r_in, rec, dir) / pdf,
+            scattered: Ray { orig: rec.p, dir },
+            pdf,
+        })
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, wi: Vec3) -> Color {
+        let wo = -Vec3::unit_vector(r_in.dir);
+        self.brdf_cos(rec.normal, wo, Vec3::unit_vector(wi))
+    }
+
+    fn pdf(&self, _r_in: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        let cos_theta = Vec3::dot(Vec3::unit_vector(wi), rec.normal);
+        f64::max(cos_theta, 0.0) / PI
+    }
+
+    fn has_diffuse_component(&self, _rec: &HitRecord) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{quad::Quad, sampler::RandomSampler, texture::UvCheckerTexture};
+
+    fn color(x: f64, y: f64, z: f64) -> Color {
+        Color { x, y, z }
+    }
+
+    /// Hit at the origin of a surface facing +y
+    fn upward_hit(mat: Arc<dyn Material>) -> HitRecord {
+        let mut rec = HitRecord::new(mat);
+        rec.normal = color(0.0, 1.0, 0.0);
+        rec.tangent = color(1.0, 0.0, 0.0);
+        rec.dpdu = color(1.0, 0.0, 0.0);
+        rec.dpdv = color(0.0, 0.0, -1.0);
+        rec
+    }
+
+    #[test]
+    fn mix_picks_each_side_in_proportion_to_its_weight() {
+        let red: Arc<dyn Material> = Arc::new(Metal {
+            albedo: color(1.0, 0.0, 0.0),
+            fuzz: 0.0,
+        });
+        let blue: Arc<dyn Material> = Arc::new(Metal {
+            albedo: color(0.0, 0.0, 1.0),
+            fuzz: 0.0,
+        });
+        let r_in = Ray {
+            orig: color(-1.0, 1.0, 0.0),
+            dir: color(1.0, -1.0, 0.0),
+        };
+        let rec = upward_hit(Arc::clone(&red));
+        let mut sampler = RandomSampler::new(1);
+
+        for weight in [0.0, 0.3, 0.8, 1.0] {
+            let mix = Mix::new(Arc::clone(&red), Arc::clone(&blue), weight);
+            let n = 20_000;
+            let blue_picks = (0..n)
+                .filter(|_| {
+                    let result = mix.scatter(&r_in, &rec, &mut sampler).unwrap();
+                    result.attenuation.z > 0.5
+                })
+                .count();
+            let fraction = blue_picks as f64 / n as f64;
+            assert!(
+                (fraction - weight).abs() < 0.015,
+                "weight {weight} picked b {fraction} of the time"
+            );
+        }
+    }
+
+    #[test]
+    fn cutout_lets_rays_through_where_alpha_is_zero() {
+        // Transparent over u < 0.5, opaque over u >= 0.5
+        let mask = Arc::new(UvCheckerTexture {
+            cells_u: 2.0,
+            cells_v: 1.0,
+            even: Arc::new(SolidColor::scalar(0.0)),
+            odd: Arc::new(SolidColor::scalar(1.0)),
+        });
+        let cutout = Arc::new(Cutout {
+            mat: Arc::new(Lambertian {
+                albedo: color(0.5, 0.5, 0.5),
+            }),
+            alpha: mask,
+        });
+        let quad = Quad::new(
+            color(0.0, 0.0, 0.0),
+            color(1.0, 0.0, 0.0),
+            color(0.0, 1.0, 0.0),
+            cutout,
+        );
+        let ray_t = Interval {
+            min: 0.001,
+            max: f64::INFINITY,
+        };
+        let ray_at = |x: f64| Ray {
+            orig: color(x, 0.5, 1.0),
+            dir: color(0.0, 0.0, -1.0),
+        };
+
+        for x in [0.05, 0.25, 0.45] {
+            assert!(
+                quad.hit(&ray_at(x), &ray_t).is_none(),
+                "hit the hole at x = {x}"
+            );
+        }
+        for x in [0.55, 0.75, 0.95] {
+            assert!(
+                quad.hit(&ray_at(x), &ray_t).is_some(),
+                "missed the surface at x = {x}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_diffuse_lobes_count_as_diffuse() {
+        let lambertian: Arc<dyn Material> = Arc::new(Lambertian {
+            albedo: color(0.5, 0.5, 0.5),
+        });
+        let brushed: Arc<dyn Material> =
+            Arc::new(AnisotropicMetal::new(color(0.9, 0.9, 0.9), 0.4, 0.8));
+        let rec = upward_hit(lambertian.clone());
+        assert!(lambertian.has_diffuse_component(&rec));
+        assert!(!brushed.has_diffuse_component(&rec));
+        assert!(!Dielectric {
+            refraction_index: 1.5
+        }
+        .has_diffuse_component(&rec));
+
+        // A mix is diffuse wherever its diffuse side has any weight
+        assert!(!Mix::new(lambertian.clone(), brushed.clone(), 1.0).has_diffuse_component(&rec));
+        assert!(Mix::new(lambertian.clone(), brushed.clone(), 0.9).has_diffuse_component(&rec));
+        assert!(Mix::new(brushed.clone(), lambertian.clone(), 0.1).has_diffuse_component(&rec));
+        assert!(!Mix::new(brushed, lambertian, 0.0).has_diffuse_component(&rec));
+    }
+}
@@ -0,0 +1,441 @@
+use std::{f64::consts::PI, sync::Mutex};
+
+use rayon::prelude::*;
+
+use crate::{
+    checkpoint::{StateReader, StateWriter},
+    color::Color,
+    geometry::Ray,
+    hittable_list::HittableList,
+    integrator::{Integrator, PathTracer, RenderContext},
+    sampler::{hash_u64, RandomSampler, Sampler},
+};
+
+/// One coordinate of a point in primary sample space, with enough history to be
+/// mutated lazily and rolled back on rejection
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    last_modified: u64,
+    value_backup: f64,
+    modify_backup: u64,
+}
+
+/// Hands out the coordinates of the current point in primary sample space and mutates
+/// them on demand, after Kelemen et al. and pbrt-v3's `MLTSampler`.
+/// Coordinates are only touched when a path asks for them, so paths of any length work.
+struct PssSampler {
+    rng: RandomSampler,
+    sigma: f64,
+    large_step_probability: f64,
+    x: Vec<PrimarySample>,
+    current_iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+    index: usize,
+}
+
+impl PssSampler {
+    fn new(seed: u64, sigma: f64, large_step_probability: f64) -> PssSampler {
+        PssSampler {
+            rng: RandomSampler::new(seed),
+            sigma,
+            large_step_probability,
+            x: Vec::new(),
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            index: 0,
+        }
+    }
+
+    /// Propose a new point; coordinates are mutated as they are requested
+    fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.next_f64() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn ensure_ready(&mut self, i: usize) {
+        // Coordinates no path has asked for yet start out uniform, like every unused one
+        // under the chain's stationary distribution; mutating them from a fixed value would
+        // leave rejection sampling loops stuck
+        if i == self.x.len() {
+            let value = self.rng.next_f64();
+            self.x.push(PrimarySample {
+                value,
+                last_modified: self.current_iteration,
+                value_backup: value,
+                modify_backup: self.current_iteration.saturating_sub(1),
+            });
+            return;
+        }
+        let xi = &mut self.x[i];
+
+        // Catch up on a large step this coordinate missed while it was unused
+        if xi.last_modified < self.last_large_step_iteration {
+            xi.value = self.rng.next_f64();
+            xi.last_modified = self.last_large_step_iteration;
+        }
+
+        xi.value_backup = xi.value;
+        xi.modify_backup = xi.last_modified;
+        if self.large_step {
+            xi.value = self.rng.next_f64();
+        } else {
+            // Every small step missed adds its own perturbation, so the variance grows with the gap
+            let missed = (self.current_iteration - xi.last_modified) as f64;
+            let u1 = 1.0 - self.rng.next_f64();
+            let u2 = self.rng.next_f64();
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            xi.value += normal * self.sigma * missed.sqrt();
+            xi.value -= xi.value.floor();
+        }
+        xi.last_modified = self.current_iteration;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for xi in &mut self.x {
+            if xi.last_modified == self.current_iteration {
+                xi.value = xi.value_backup;
+                xi.last_modified = xi.modify_backup;
+            }
+        }
+        self.current_iteration -= 1;
+    }
+
+    fn write_state(&self, out: &mut StateWriter) {
+        self.rng.write_state(out);
+        out.write_u64(self.x.len() as u64);
+        for xi in &self.x {
+            out.write_f64(xi.value);
+            out.write_u64(xi.last_modified);
+            out.write_f64(xi.value_backup);
+            out.write_u64(xi.modify_backup);
+        }
+        out.write_u64(self.current_iteration);
+        out.write_bool(self.large_step);
+        out.write_u64(self.last_large_step_iteration);
+        out.write_u64(self.index as u64);
+    }
+
+    fn read_state(
+        input: &mut StateReader,
+        sigma: f64,
+        large_step_probability: f64,
+    ) -> std::io::Result<PssSampler> {
+        let rng = RandomSampler::read_state(input)?;
+        let x = (0..input.read_len()?)
+            .map(|_| {
+                Ok(PrimarySample {
+                    value: input.read_f64()?,
+                    last_modified: input.read_u64()?,
+                    value_backup: input.read_f64()?,
+                    modify_backup: input.read_u64()?,
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(PssSampler {
+            rng,
+            sigma,
+            large_step_probability,
+            x,
+            current_iteration: input.read_u64()?,
+            large_step: input.read_bool()?,
+            last_large_step_iteration: input.read_u64()?,
+            index: input.read_u64()? as usize,
+        })
+    }
+}
+
+impl Sampler for PssSampler {
+    /// Chains choose their own pixels, so there is nothing to restart
+    fn start_pixel_sample(&mut self, _i: i32, _j: i32, _index: u32) {}
+
+    fn next_f64(&mut self) -> f64 {
+        let i = self.index;
+        self.index += 1;
+        self.ensure_ready(i);
+        self.x[i].value
+    }
+}
+
+/// A path tracer sample at one point of primary sample space
+#[derive(Clone, Copy)]
+struct PathSample {
+    raster: (f64, f64),
+    radiance: Color,
+    /// Scalar contribution the chain's stationary distribution is proportional to
+    importance: f64,
+}
+
+struct Chain {
+    sampler: PssSampler,
+    current: PathSample,
+    rng: RandomSampler,
+}
+
+impl Chain {
+    fn write_state(&self, out: &mut StateWriter) {
+        self.sampler.write_state(out);
+        let current = &self.current;
+        for v in [
+            current.raster.0,
+            current.raster.1,
+            current.radiance.x,
+            current.radiance.y,
+            current.radiance.z,
+            current.importance,
+        ] {
+            out.write_f64(v);
+        }
+        self.rng.write_state(out);
+    }
+
+    fn read_state(input: &mut StateReader, mlt: &Mlt) -> std::io::Result<Chain> {
+        let sampler = PssSampler::read_state(input, mlt.sigma, mlt.large_step_probability)?;
+        let current = PathSample {
+            raster: (input.read_f64()?, input.read_f64()?),
+            radiance: Color {
+                x: input.read_f64()?,
+                y: input.read_f64()?,
+                z: input.read_f64()?,
+            },
+            importance: input.read_f64()?,
+        };
+        Ok(Chain {
+            sampler,
+            current,
+            rng: RandomSampler::read_state(input)?,
+        })
+    }
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.) around the path tracer.
+/// Independent Markov chains wander the unit hypercube of random numbers the path tracer
+/// consumes, visiting paths in proportion to their luminance, and splat every mutation.
+/// The normalization comes from `bootstrap_samples` independent paths traced up front.
+///
+/// Good at finding narrow light paths the path tracer rarely samples; images are unbiased
+/// in expectation but noise is correlated and converges unevenly over the image.
+pub struct Mlt {
+    pub path_tracer: PathTracer,
+    pub bootstrap_samples: usize,
+    pub chains: usize,
+    /// Standard deviation of small step perturbations
+    pub sigma: f64,
+    /// Probability of replacing the whole sample point instead of perturbing it
+    pub large_step_probability: f64,
+    state: Mutex<Option<(f64, Vec<Chain>)>>,
+}
+
+impl Mlt {
+    pub fn new(max_depth: i32) -> Mlt {
+        Mlt {
+            path_tracer: PathTracer {
+                max_depth,
+                rr_min_depth: 5,
+            },
+            bootstrap_samples: 100_000,
+            chains: 1000,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Trace the path the sampler's current point in primary sample space stands for
+    fn evaluate(
+        &self,
+        sampler: &mut PssSampler,
+        world: &HittableList,
+        ctx: &RenderContext,
+    ) -> PathSample {
+        let camera = ctx.camera;
+        let x = sampler.next_f64() * camera.image_width() as f64;
+        let y = sampler.next_f64() * camera.image_height() as f64;
+        let radiance = match camera.get_ray_at(x, y, sampler) {
+            Some((r, weight)) => weight * self.path_tracer.radiance(&r, world, ctx, sampler),
+            None => Color::zeros(),
+        };
+        let importance = radiance.luminance();
+        PathSample {
+            raster: (x, y),
+            radiance,
+            // Negative or NaN luminance would break the acceptance ratio
+            importance: if importance > 0.0 { importance } else { 0.0 },
+        }
+    }
+
+    fn new_sampler(&self, seed: u64) -> PssSampler {
+        PssSampler::new(seed, self.sigma, self.large_step_probability)
+    }
+
+    /// Estimate the image's mean luminance and start each chain at a bootstrap path
+    /// picked in proportion to its luminance
+    fn bootstrap(&self, world: &HittableList, ctx: &RenderContext) -> (f64, Vec<Chain>) {
+        let seed = ctx.camera.seed();
+        let weights: Vec<f64> = (0..self.bootstrap_samples)
+            .into_par_iter()
+            .map(|index| {
+                let mut sampler = self.new_sampler(hash_u64(&[seed, index as u64]));
+                self.evaluate(&mut sampler, world, ctx).importance
+            })
+            .collect();
+
+        let total: f64 = weights.iter().sum();
+        let b = total / self.bootstrap_samples.max(1) as f64;
+        if total <= 0.0 {
+            return (0.0, Vec::new());
+        }
+
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut running = 0.0;
+        for w in &weights {
+            running += w;
+            cdf.push(running / total);
+        }
+
+        let mut rng = RandomSampler::with_stream(seed, 1);
+        let picks: Vec<usize> = (0..self.chains)
+            .map(|_| {
+                let u = rng.next_f64();
+                cdf.partition_point(|&c| c <= u).min(cdf.len() - 1)
+            })
+            .collect();
+
+        let chains = picks
+            .into_par_iter()
+            .enumerate()
+            .map(|(chain, index)| {
+                // Same seed, same first point: the chain starts on the bootstrap path
+                let mut sampler = self.new_sampler(hash_u64(&[seed, index as u64]));
+                let current = self.evaluate(&mut sampler, world, ctx);
+                Chain {
+                    sampler,
+                    current,
+                    rng: RandomSampler::with_stream(seed, 2 + chain as u64),
+                }
+            })
+            .collect();
+
+        (b, chains)
+    }
+
+    /// Run `mutations` steps of `chain`, splatting every proposal and current state with
+    /// their expected weights so rejected proposals still contribute
+    fn run_chain(
+        &self,
+        mut chain: Chain,
+        mutations: usize,
+        b: f64,
+        world: &HittableList,
+        ctx: &RenderContext,
+    ) -> Chain {
+        let splat = |sample: &PathSample, weight: f64| {
+            if weight > 0.0 && sample.importance > 0.0 {
+                let (x, y) = sample.raster;
+                ctx.splats.add(
+                    x.floor() as i32,
+                    y.floor() as i32,
+                    (weight * b / sample.importance) * sample.radiance,
+                );
+            }
+        };
+
+        for _ in 0..mutations {
+            chain.sampler.start_iteration();
+            let proposed = self.evaluate(&mut chain.sampler, world, ctx);
+
+            let accept = if chain.current.importance > 0.0 {
+                f64::min(1.0, proposed.importance / chain.current.importance)
+            } else {
+                1.0
+            };
+            splat(&proposed, accept);
+            splat(&chain.current, 1.0 - accept);
+
+            if chain.rng.next_f64() < accept {
+                chain.current = proposed;
+                chain.sampler.accept();
+            } else {
+                chain.sampler.reject();
+            }
+        }
+
+        chain
+    }
+}
+
+impl Integrator for Mlt {
+    fn renders_regions(&self) -> bool {
+        false
+    }
+
+    /// Camera rays contribute nothing: all the work happens in `begin_pass`
+    fn radiance(
+        &self,
+        _r: &Ray,
+        _world: &HittableList,
+        _ctx: &RenderContext,
+        _sampler: &mut dyn Sampler,
+    ) -> Color {
+        Color::zeros()
+    }
+
+    /// Bootstrap on the first pass, then advance the chains by one mutation per pixel
+    fn begin_pass(&self, _pass: u32, world: &HittableList, ctx: &RenderContext) {
+        let mut state = self.state.lock().unwrap();
+        let (b, chains) = state.get_or_insert_with(|| self.bootstrap(world, ctx));
+        if chains.is_empty() {
+            return;
+        }
+
+        let mutations = (ctx.camera.image_width() * ctx.camera.image_height()).max(0) as usize;
+        let per_chain = mutations / chains.len();
+        let remainder = mutations % chains.len();
+        let b = *b;
+
+        *chains = std::mem::take(chains)
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, chain)| {
+                let count = per_chain + usize::from(index < remainder);
+                self.run_chain(chain, count, b, world, ctx)
+            })
+            .collect();
+    }
+
+    fn write_state(&self, out: &mut StateWriter) {
+        let state = self.state.lock().unwrap();
+        out.write_bool(state.is_some());
+        if let Some((b, chains)) = state.as_ref() {
+            out.write_f64(*b);
+            out.write_u64(chains.len() as u64);
+            for chain in chains {
+                chain.write_state(out);
+            }
+        }
+    }
+
+    fn read_state(&self, input: &mut StateReader) -> std::io::Result<()> {
+        let state = if input.read_bool()? {
+            let b = input.read_f64()?;
+            let chains = (0..input.read_len()?)
+                .map(|_| Chain::read_state(input, self))
+                .collect::<std::io::Result<_>>()?;
+            Some((b, chains))
+        } else {
+            None
+        };
+        *self.state.lock().unwrap() = state;
+        Ok(())
+    }
+}
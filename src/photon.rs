@@ -0,0 +1,465 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    f64::consts::PI,
+    sync::{Arc, RwLock},
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    checkpoint::{StateReader, StateWriter},
+    color::Color,
+    geometry::{Onb, Point3, Ray, Vec3},
+    hittable::HitRecord,
+    hittable_list::HittableList,
+    integrator::{trace, Integrator, RenderContext},
+    sampler::{hash_u64, RandomSampler, Sampler},
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Photon {
+    p: Point3,
+    /// Unit direction the photon arrived from
+    wi: Vec3,
+    /// Surface normal at the photon, facing the side it arrived on
+    normal: Vec3,
+    power: Color,
+}
+
+/// Photons stored as an implicit balanced kd-tree: each subslice is split at its
+/// middle element along the axis recorded for that element
+struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+}
+
+impl PhotonMap {
+    fn build(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        Self::build_node(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    fn build_node(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.len() <= 1 {
+            return;
+        }
+
+        // Split along the widest extent of this node's photons
+        let mut min = photons[0].p;
+        let mut max = photons[0].p;
+        for photon in photons.iter() {
+            min = Vec3 {
+                x: min.x.min(photon.p.x),
+                y: min.y.min(photon.p.y),
+                z: min.z.min(photon.p.z),
+            };
+            max = Vec3 {
+                x: max.x.max(photon.p.x),
+                y: max.y.max(photon.p.y),
+                z: max.z.max(photon.p.z),
+            };
+        }
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| coord(a.p, axis).total_cmp(&coord(b.p, axis)));
+        axes[mid] = axis;
+
+        let (left, rest) = photons.split_at_mut(mid);
+        let (left_axes, rest_axes) = axes.split_at_mut(mid);
+        Self::build_node(left, left_axes);
+        Self::build_node(&mut rest[1..], &mut rest_axes[1..]);
+    }
+
+    fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    /// Visit every photon within `radius` of `p`
+    fn for_each_within(&self, p: Point3, radius: f64, mut visit: impl FnMut(&Photon)) {
+        self.visit_node(0, self.photons.len(), p, radius * radius, &mut visit);
+    }
+
+    fn visit_node(
+        &self,
+        lo: usize,
+        hi: usize,
+        p: Point3,
+        radius_squared: f64,
+        visit: &mut impl FnMut(&Photon),
+    ) {
+        if lo >= hi {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid];
+        let delta = coord(p, axis) - coord(photon.p, axis);
+
+        if (photon.p - p).length_squared() <= radius_squared {
+            visit(photon);
+        }
+
+        // Near side first; the far side only if the splitting plane is within reach
+        let (near, far) = if delta < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.visit_node(near.0, near.1, p, radius_squared, visit);
+        if delta * delta <= radius_squared {
+            self.visit_node(far.0, far.1, p, radius_squared, visit);
+        }
+    }
+
+    /// Squared distance from `p` to its `k`-th nearest photon, or `None` with fewer photons
+    fn kth_nearest_distance_squared(&self, p: Point3, k: usize) -> Option<f64> {
+        if k == 0 || self.photons.len() < k {
+            return None;
+        }
+
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        self.knn_node(0, self.photons.len(), p, k, &mut heap);
+        heap.peek().map(|d: &Distance| d.0)
+    }
+
+    fn knn_node(&self, lo: usize, hi: usize, p: Point3, k: usize, heap: &mut BinaryHeap<Distance>) {
+        if lo >= hi {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid];
+        let delta = coord(p, axis) - coord(photon.p, axis);
+
+        heap.push(Distance((photon.p - p).length_squared()));
+        if heap.len() > k {
+            heap.pop();
+        }
+
+        let (near, far) = if delta < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.knn_node(near.0, near.1, p, k, heap);
+        let worst = heap.peek().map_or(f64::INFINITY, |d| d.0);
+        if heap.len() < k || delta * delta <= worst {
+            self.knn_node(far.0, far.1, p, k, heap);
+        }
+    }
+}
+
+/// Max-heap entry for squared distances
+#[derive(PartialEq)]
+struct Distance(f64);
+
+impl Eq for Distance {}
+
+impl PartialOrd for Distance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Distance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+fn coord(p: Point3, axis: u8) -> f64 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+/// Emit `count` photons from `world.lights` and return those stored on diffuse surfaces.
+/// Powers are scaled so the stored photons represent the flux of one emission pass.
+/// Photon `i` draws from stream `i` of `seed`, so the result does not depend on threading.
+fn trace_photons(world: &HittableList, count: usize, max_depth: i32, seed: u64) -> Vec<Photon> {
+    if world.lights.is_empty() || count == 0 {
+        return Vec::new();
+    }
+
+    (0..count)
+        .into_par_iter()
+        .flat_map_iter(|index| {
+            let mut sampler = RandomSampler::with_stream(seed, index as u64);
+            let mut stored = Vec::new();
+            let Some((rec, pdf_pos)) = world.sample_light_surface(&mut sampler) else {
+                return stored;
+            };
+            if pdf_pos <= 0.0 {
+                return stored;
+            }
+
+            // Cosine-weighted emission: cos / (pdf_pos * cos / pi) leaves pi / pdf_pos
+            let dir = Onb::from_w(rec.normal).local(Vec3::rand_cosine_direction(&mut sampler));
+            let coverage = rec.mat.alpha(&rec);
+            let mut power = (coverage * PI / (pdf_pos * count as f64)) * rec.mat.emitted(&rec);
+            let mut ray = Ray { orig: rec.p, dir };
+
+            for _ in 0..max_depth {
+                let Some(hit) = trace(world, &ray) else {
+                    break;
+                };
+
+                if hit.mat.has_diffuse_component(&hit) {
+                    stored.push(Photon {
+                        p: hit.p,
+                        wi: -Vec3::unit_vector(ray.dir),
+                        normal: hit.normal,
+                        power,
+                    });
+                }
+
+                let Some(scatter_result) = hit.mat.scatter(&ray, &hit, &mut sampler) else {
+                    break;
+                };
+
+                // Russian roulette keeps photon powers roughly constant
+                let next = power * scatter_result.attenuation;
+                let survive = f64::min(next.max_component() / power.max_component(), 0.95);
+                if survive.is_nan() || sampler.next_f64() >= survive {
+                    break;
+                }
+                power = next / survive;
+                ray = scatter_result.scattered;
+            }
+
+            stored
+        })
+        .collect()
+}
+
+/// Density estimate of radiance leaving `rec` towards `r_in`'s origin from photons within `radius`
+fn estimate(map: &PhotonMap, r_in: &Ray, rec: &HitRecord, radius: f64) -> Color {
+    let mut sum = Color::zeros();
+    map.for_each_within(rec.p, radius, |photon| {
+        // Photons on the other side of a thin surface do not light this one
+        if Vec3::dot(photon.normal, rec.normal) <= 0.0 {
+            return;
+        }
+        let cos_theta = Vec3::dot(rec.normal, photon.wi);
+        if cos_theta <= 1e-6 {
+            return;
+        }
+        let f = rec.mat.eval(r_in, rec, photon.wi) / cos_theta;
+        sum += f * photon.power;
+    });
+
+    sum / (PI * radius * radius)
+}
+
+/// Follow specular and glossy bounces from `r` and add `shade` of the first diffuse hit.
+/// Shared by both photon mappers, which only differ in how they estimate density.
+///
+/// Photons only leave `world.lights`, so the background is added at that hit as an extra
+/// light, estimated with a BSDF sample like `Whitted` does. It lights surfaces directly only.
+fn gather(
+    world: &HittableList,
+    r: &Ray,
+    max_depth: i32,
+    sampler: &mut dyn Sampler,
+    shade: impl Fn(&Ray, &HitRecord) -> Color,
+) -> Color {
+    let mut ray = *r;
+    let mut radiance = Color::zeros();
+    let mut throughput = Color {
+        x: 1.0,
+        y: 1.0,
+        z: 1.0,
+    };
+
+    for _ in 0..max_depth {
+        let Some(rec) = trace(world, &ray) else {
+            return radiance + throughput * world.background.value(&ray);
+        };
+
+        radiance += throughput * rec.mat.emitted(&rec);
+        let diffuse = rec.mat.has_diffuse_component(&rec);
+        if diffuse {
+            radiance += throughput * shade(&ray, &rec);
+        }
+
+        // Specular and glossy scattering continue; the density estimate covers the rest
+        let Some(scatter_result) = rec.mat.scatter(&ray, &rec, sampler) else {
+            return radiance;
+        };
+        if diffuse && scatter_result.pdf > 0.0 {
+            if trace(world, &scatter_result.scattered).is_none() {
+                radiance += throughput
+                    * scatter_result.attenuation
+                    * world.background.value(&scatter_result.scattered);
+            }
+            return radiance;
+        }
+        throughput = throughput * scatter_result.attenuation;
+        ray = scatter_result.scattered;
+    }
+
+    radiance
+}
+
+/// Classic photon mapping: one photon map built before the first pass, shaded with a
+/// k-nearest-neighbour density estimate at the first diffuse hit after specular and glossy
+/// bounces.
+/// Resolves caustics well, but the result is biased (blurry) and does not converge with spp.
+/// The background only lights surfaces directly; see `gather`.
+pub struct PhotonMapper {
+    pub photons: usize,
+    pub k_nearest: usize,
+    pub max_depth: i32,
+    map: RwLock<Option<Arc<PhotonMap>>>,
+}
+
+impl PhotonMapper {
+    pub fn new(photons: usize, k_nearest: usize, max_depth: i32) -> PhotonMapper {
+        PhotonMapper {
+            photons,
+            k_nearest,
+            max_depth,
+            map: RwLock::new(None),
+        }
+    }
+}
+
+impl Integrator for PhotonMapper {
+    /// Trace the map on the first pass of a render, or of a resumed one
+    fn begin_pass(&self, pass: u32, world: &HittableList, ctx: &RenderContext) {
+        let mut map = self.map.write().unwrap();
+        if pass == 0 || map.is_none() {
+            let seed = hash_u64(&[ctx.camera.seed(), 0]);
+            let photons = trace_photons(world, self.photons, self.max_depth, seed);
+            *map = Some(Arc::new(PhotonMap::build(photons)));
+        }
+    }
+
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        _ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let Some(map) = self.map.read().unwrap().clone() else {
+            return Color::zeros();
+        };
+        gather(world, r, self.max_depth, sampler, |ray, rec| {
+            match map.kth_nearest_distance_squared(rec.p, self.k_nearest) {
+                Some(d2) if d2 > 0.0 => estimate(&map, ray, rec, d2.sqrt()),
+                _ => Color::zeros(),
+            }
+        })
+    }
+}
+
+/// Progressive photon mapping in the probabilistic formulation of Knaus and Zwicker:
+/// every pass traces a fresh photon map and shades with a global radius that shrinks as
+/// r²(i+1) = r²(i) (i + alpha) / (i + 1), so the average over passes converges to the
+/// correct image, caustics included, apart from background light bouncing between surfaces.
+///
+/// Without an `initial_radius`, one is picked from the first pass's photon density.
+pub struct ProgressivePhotonMapper {
+    pub photons_per_pass: usize,
+    pub initial_radius: Option<f64>,
+    /// Fraction of photons kept per pass, in (0,1); lower shrinks the radius faster
+    pub alpha: f64,
+    pub max_depth: i32,
+    state: RwLock<(Arc<PhotonMap>, f64)>,
+}
+
+impl ProgressivePhotonMapper {
+    pub fn new(photons_per_pass: usize, max_depth: i32) -> ProgressivePhotonMapper {
+        ProgressivePhotonMapper {
+            photons_per_pass,
+            initial_radius: None,
+            alpha: 2.0 / 3.0,
+            max_depth,
+            state: RwLock::new((Arc::new(PhotonMap::build(Vec::new())), 0.0)),
+        }
+    }
+
+    /// Mean distance to the 16th nearest photon around a subset of the photons
+    fn auto_radius(map: &PhotonMap) -> f64 {
+        let step = (map.len() / 256).max(1);
+        let distances: Vec<f64> = map
+            .photons
+            .iter()
+            .step_by(step)
+            .filter_map(|photon| map.kth_nearest_distance_squared(photon.p, 16))
+            .map(f64::sqrt)
+            .collect();
+
+        if distances.is_empty() {
+            return 0.0;
+        }
+        distances.iter().sum::<f64>() / distances.len() as f64
+    }
+}
+
+impl Integrator for ProgressivePhotonMapper {
+    fn renders_regions(&self) -> bool {
+        false
+    }
+
+    fn begin_pass(&self, pass: u32, world: &HittableList, ctx: &RenderContext) {
+        let seed = hash_u64(&[ctx.camera.seed(), pass as u64]);
+        let photons = trace_photons(world, self.photons_per_pass, self.max_depth, seed);
+        let map = PhotonMap::build(photons);
+
+        let mut state = self.state.write().unwrap();
+        let radius = if pass == 0 {
+            self.initial_radius
+                .unwrap_or_else(|| Self::auto_radius(&map))
+        } else {
+            let i = pass as f64;
+            state.1 * ((i - 1.0 + self.alpha) / i).sqrt()
+        };
+        *state = (Arc::new(map), radius);
+    }
+
+    fn radiance(
+        &self,
+        r: &Ray,
+        world: &HittableList,
+        _ctx: &RenderContext,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let (map, radius) = {
+            let state = self.state.read().unwrap();
+            (Arc::clone(&state.0), state.1)
+        };
+        if radius <= 0.0 {
+            return gather(world, r, self.max_depth, sampler, |_, _| Color::zeros());
+        }
+
+        gather(world, r, self.max_depth, sampler, |ray, rec| {
+            estimate(&map, ray, rec, radius)
+        })
+    }
+
+    /// The photon map is rebuilt every pass; only the radius carries over
+    fn write_state(&self, out: &mut StateWriter) {
+        out.write_f64(self.state.read().unwrap().1);
+    }
+
+    fn read_state(&self, input: &mut StateReader) -> std::io::Result<()> {
+        self.state.write().unwrap().1 = input.read_f64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How far a render has got, reported as tiles finish
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Pass being rendered, counting from 0
+    pub pass: u32,
+    /// Passes the render takes unless it stops early
+    pub passes: u32,
+    /// Tiles of this pass finished so far
+    pub tiles_done: usize,
+    /// Tiles in every pass
+    pub tiles: usize,
+    /// Time since the render started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Fraction of the render done, in \[0,1\]
+    pub fn fraction(&self) -> f64 {
+        let pass = self.tiles_done as f64 / self.tiles.max(1) as f64;
+        ((self.pass as f64 + pass) / self.passes.max(1) as f64).min(1.0)
+    }
+}
+
+/// Called with every progress update, possibly from several render threads at once
+pub type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Progress callback that forwards every update to the returned channel, for applications
+/// that would rather poll than be called back from render threads
+pub fn progress_channel() -> (ProgressCallback, Receiver<Progress>) {
+    let (sender, receiver) = mpsc::channel();
+    let callback: ProgressCallback = Arc::new(move |progress| {
+        // Nobody listening anymore is no reason to stop rendering
+        let _ = sender.send(progress);
+    });
+    (callback, receiver)
+}
+
+/// Shared flag to abort a render from another thread. The render finishes the tiles already
+/// being rendered, then stops and writes out the image as it stands.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
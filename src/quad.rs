@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use crate::{
+    geometry::{Interval, Onb, Point3, Ray, Vec3},
+    hittable::{HitRecord, Hittable},
+    material::Material,
+    sampler::Sampler,
+};
+
+/// Parallelogram with corner `q` and edges `u` and `v`
+pub struct Quad {
+    q: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    normal: Vec3,
+    d: f64,
+    area: f64,
+    mat: Arc<dyn Material>,
+}
+
+impl Quad {
+    pub fn new(q: Point3, u: Vec3, v: Vec3, mat: Arc<dyn Material>) -> Quad {
+        let n = Vec3::cross(u, v);
+        let normal = Vec3::unit_vector(n);
+        let d = Vec3::dot(normal, q);
+        let w = n / Vec3::dot(n, n);
+        let area = n.length();
+
+        Quad {
+            q,
+            u,
+            v,
+            w,
+            normal,
+            d,
+            area,
+            mat,
+        }
+    }
+
+    /// Where `r` crosses the quad within `ray_t`, cut-outs included
+    fn intersect(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+        let denom = Vec3::dot(self.normal, r.dir);
+
+        // Ray is parallel to the plane
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = (self.d - Vec3::dot(self.normal, r.orig)) / denom;
+        if !ray_t.contains(t) {
+            return None;
+        }
+
+        // Express the hit point in the quad's (alpha, beta) plane coordinates
+        let intersection = r.at(t);
+        let planar_hitpt = intersection - self.q;
+        let alpha = Vec3::dot(self.w, Vec3::cross(planar_hitpt, self.v));
+        let beta = Vec3::dot(self.w, Vec3::cross(self.u, planar_hitpt));
+
+        let unit = Interval { min: 0.0, max: 1.0 };
+        if !unit.contains(alpha) || !unit.contains(beta) {
+            return None;
+        }
+
+        let mut rec = HitRecord::new(Arc::clone(&self.mat));
+        rec.t = t;
+        rec.p = intersection;
+        rec.u = alpha;
+        rec.v = beta;
+        rec.dpdu = self.u;
+        rec.dpdv = self.v;
+        rec.set_face_normal(r, self.normal);
+        Some(rec)
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+        self.intersect(r, ray_t).filter(|rec| rec.alpha_test(r))
+    }
+
+    /// `random` samples the whole quad, so cut-outs count here too
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f64 {
+        let ray_t = Interval {
+            min: 0.001,
+            max: f64::INFINITY,
+        };
+        let Some(rec) = self.intersect(&Ray { orig: origin, dir }, &ray_t) else {
+            return 0.0;
+        };
+
+        let distance_squared = rec.t * rec.t * dir.length_squared();
+        let cosine = (Vec3::dot(dir, rec.normal) / dir.length()).abs();
+
+        distance_squared / (cosine * self.area)
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (alpha, beta) = sampler.next_2d();
+        let p = self.q + (alpha * self.u) + (beta * self.v);
+        p - origin
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (alpha, beta) = sampler.next_2d();
+
+        let mut rec = HitRecord::new(Arc::clone(&self.mat));
+        rec.p = self.q + (alpha * self.u) + (beta * self.v);
+        rec.u = alpha;
+        rec.v = beta;
+        rec.dpdu = self.u;
+        rec.dpdv = self.v;
+        rec.normal = self.normal;
+        rec.tangent = Onb::from_w_tangent(self.normal, self.u).u;
+
+        let pdf = self.surface_pdf(rec.p);
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, _p: Point3) -> f64 {
+        1.0 / self.area
+    }
+}
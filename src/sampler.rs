@@ -0,0 +1,419 @@
+use crate::checkpoint::{StateReader, StateWriter};
+
+/// Source of the uniform random numbers a render consumes.
+/// Every pixel sample restarts from a seed derived from its pixel and index, so a render
+/// is reproducible no matter how rows are scheduled across threads.
+///
+/// Each call consumes the next dimension(s) of the current sample. The camera takes the
+/// first ones (pixel position, then lens), and each bounce continues from there, so
+/// low-discrepancy samplers can spread every dimension well over a pixel's samples.
+pub trait Sampler {
+    /// Restart the sequence for sample `index` of pixel (`i`,`j`)
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32);
+
+    /// Next uniform value in [0,1)
+    fn next_f64(&mut self) -> f64;
+
+    /// Next pair of uniform values in [0,1)², for 2D decisions like a point on a lens
+    fn next_2d(&mut self) -> (f64, f64) {
+        (self.next_f64(), self.next_f64())
+    }
+
+    /// Next uniform value in [`min`,`max`)
+    fn next_range(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.next_f64()
+    }
+}
+
+/// Which `Sampler` a render uses; each thread builds its own with `create`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplerKind {
+    Random,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn write_state(self, out: &mut StateWriter) {
+        out.write_u64(match self {
+            SamplerKind::Random => 0,
+            SamplerKind::Stratified => 1,
+            SamplerKind::Halton => 2,
+            SamplerKind::Sobol => 3,
+        });
+    }
+
+    /// New sampler for renders taking `samples_per_pixel` samples in every pixel
+    pub fn create(self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Random => Box::new(RandomSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed, samples_per_pixel)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed, samples_per_pixel)),
+        }
+    }
+}
+
+/// Look up a sampler by its command line name
+pub fn by_name(name: &str) -> Option<SamplerKind> {
+    match name {
+        "random" => Some(SamplerKind::Random),
+        "stratified" => Some(SamplerKind::Stratified),
+        "halton" => Some(SamplerKind::Halton),
+        "sobol" => Some(SamplerKind::Sobol),
+        _ => None,
+    }
+}
+
+/// Independent uniform samples from a PCG32 generator (O'Neill, XSH-RR variant)
+#[derive(Debug, Clone)]
+pub struct RandomSampler {
+    seed: u64,
+    state: u64,
+    inc: u64,
+}
+
+impl RandomSampler {
+    const MULTIPLIER: u64 = 0x5851_f42d_4c95_7f2d;
+
+    /// Generator whose whole sequence is fixed by `seed`
+    pub fn new(seed: u64) -> RandomSampler {
+        RandomSampler::with_stream(seed, 0)
+    }
+
+    /// One of 2^63 independent sequences for the same `seed`
+    pub fn with_stream(seed: u64, stream: u64) -> RandomSampler {
+        let mut sampler = RandomSampler {
+            seed,
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        sampler.next_u32();
+        sampler.state = sampler.state.wrapping_add(seed);
+        sampler.next_u32();
+        sampler
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(RandomSampler::MULTIPLIER)
+            .wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    /// Save the generator's position, for render checkpoints
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.write_u64(self.seed);
+        out.write_u64(self.state);
+        out.write_u64(self.inc);
+    }
+
+    /// Generator continuing exactly where the one `write_state` saved left off
+    pub fn read_state(input: &mut StateReader) -> std::io::Result<RandomSampler> {
+        Ok(RandomSampler {
+            seed: input.read_u64()?,
+            state: input.read_u64()?,
+            inc: input.read_u64()?,
+        })
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        let key = hash_u64(&[self.seed, i as u64, j as u64, index as u64]);
+        *self = RandomSampler {
+            seed: self.seed,
+            ..RandomSampler::with_stream(key, 0)
+        };
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Where a dimension-aware sampler is within the current pixel sample
+#[derive(Debug, Clone)]
+struct SampleState {
+    seed: u64,
+    samples_per_pixel: u32,
+    pixel: (i32, i32),
+    index: u32,
+    dimension: u32,
+    /// Jitter and anything past the sampler's supported dimensions
+    rng: RandomSampler,
+}
+
+impl SampleState {
+    fn new(seed: u64, samples_per_pixel: u32) -> SampleState {
+        SampleState {
+            seed,
+            samples_per_pixel: samples_per_pixel.max(1),
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+            rng: RandomSampler::new(seed),
+        }
+    }
+
+    fn start(&mut self, i: i32, j: i32, index: u32) {
+        self.pixel = (i, j);
+        self.index = index;
+        self.dimension = 0;
+        self.rng.start_pixel_sample(i, j, index);
+    }
+
+    /// Hash identifying the next dimension of this pixel, shared by all its samples
+    fn next_dimension_hash(&mut self) -> u64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        hash_u64(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            dimension as u64,
+        ])
+    }
+}
+
+/// Jittered stratified samples: every dimension of a pixel's samples is split into as many
+/// strata as there are samples, visited in an order shuffled per pixel and dimension.
+/// 2D dimensions use a grid of strata as close to square as the sample count allows.
+#[derive(Debug, Clone)]
+pub struct StratifiedSampler {
+    state: SampleState,
+    /// Grid of 2D strata, `grid.0 * grid.1 == samples_per_pixel`
+    grid: (u32, u32),
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> StratifiedSampler {
+        let state = SampleState::new(seed, samples_per_pixel);
+        let n = state.samples_per_pixel;
+        let x = (1..=(n as f64).sqrt() as u32)
+            .rev()
+            .find(|x| n.is_multiple_of(*x))
+            .unwrap_or(1);
+
+        StratifiedSampler {
+            state,
+            grid: (x, n / x),
+        }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        let n = self.state.samples_per_pixel;
+        let hash = self.state.next_dimension_hash();
+        let stratum = permutation_element(self.state.index % n, n, hash as u32);
+        (stratum as f64 + self.state.rng.next_f64()) / n as f64
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let (nx, ny) = self.grid;
+        let hash = self.state.next_dimension_hash();
+        let stratum = permutation_element(self.state.index % (nx * ny), nx * ny, hash as u32);
+        let (x, y) = (stratum % nx, stratum / nx);
+        (
+            (x as f64 + self.state.rng.next_f64()) / nx as f64,
+            (y as f64 + self.state.rng.next_f64()) / ny as f64,
+        )
+    }
+}
+
+/// The Halton sequence over each pixel's samples, one prime base per dimension, with
+/// Owen scrambling seeded per pixel and dimension so neighbouring pixels decorrelate.
+/// Dimensions past the first `PRIMES.len()` fall back to independent random numbers.
+#[derive(Debug, Clone)]
+pub struct HaltonSampler {
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    const PRIMES: [u64; 32] = [
+        2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89,
+        97, 101, 103, 107, 109, 113, 127, 131,
+    ];
+
+    pub fn new(seed: u64, samples_per_pixel: u32) -> HaltonSampler {
+        HaltonSampler {
+            state: SampleState::new(seed, samples_per_pixel),
+        }
+    }
+
+    /// Radical inverse of `a` in `base` with every digit permuted by a hash of the digits
+    /// above it (Owen scrambling), continued to the 53 bits an f64 can hold.
+    /// Stopping there keeps `reversed_digits` below `base * 2^53`, well inside a u64.
+    fn scrambled_radical_inverse(base: u64, mut a: u64, hash: u64) -> f64 {
+        let inv_base = 1.0 / base as f64;
+        let mut reversed_digits: u64 = 0;
+        let mut inv_base_m = 1.0;
+
+        while inv_base_m > f64::EPSILON / 2.0 {
+            let next = a / base;
+            let digit = a - next * base;
+            let digit_hash = hash_u64(&[hash, reversed_digits]);
+            let digit = permutation_element(digit as u32, base as u32, digit_hash as u32) as u64;
+            reversed_digits = reversed_digits * base + digit;
+            inv_base_m *= inv_base;
+            a = next;
+        }
+
+        f64::min(inv_base_m * reversed_digits as f64, ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        let dimension = self.state.dimension as usize;
+        let hash = self.state.next_dimension_hash();
+        match HaltonSampler::PRIMES.get(dimension) {
+            Some(&base) => {
+                HaltonSampler::scrambled_radical_inverse(base, self.state.index as u64, hash)
+            }
+            None => self.state.rng.next_f64(),
+        }
+    }
+}
+
+/// Owen-scrambled Sobol points, padded: every 1D or 2D dimension uses the first two Sobol
+/// dimensions with its own scrambling and its own shuffle of the sample order, so each
+/// dimension and pair is well stratified at every power of two sample count.
+#[derive(Debug, Clone)]
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> SobolSampler {
+        SobolSampler {
+            state: SampleState::new(seed, samples_per_pixel),
+        }
+    }
+
+    /// Point `index` of Sobol dimension `dimension` (0 or 1), Owen-scrambled with `seed`
+    fn sample(index: u32, dimension: usize, seed: u32) -> f64 {
+        let mut v: u32 = 0;
+        let mut column: u32 = 1 << 31;
+        for bit in 0..32 {
+            if index & (1 << bit) != 0 {
+                v ^= if dimension == 0 {
+                    1 << (31 - bit)
+                } else {
+                    column
+                };
+            }
+            // Second dimension: each direction number is the previous xor itself shifted
+            column ^= column >> 1;
+        }
+
+        let v = fast_owen_scramble(v, seed);
+        f64::min(v as f64 / (1u64 << 32) as f64, ONE_MINUS_EPSILON)
+    }
+
+    fn shuffled_index(&self, hash: u64) -> u32 {
+        let n = self.state.samples_per_pixel;
+        permutation_element(self.state.index % n, n, hash as u32)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        let hash = self.state.next_dimension_hash();
+        let index = self.shuffled_index(hash);
+        SobolSampler::sample(index, 0, (hash >> 32) as u32)
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let hash = self.state.next_dimension_hash();
+        let index = self.shuffled_index(hash);
+        let seeds = hash_u64(&[hash]);
+        (
+            SobolSampler::sample(index, 0, seeds as u32),
+            SobolSampler::sample(index, 1, (seeds >> 32) as u32),
+        )
+    }
+}
+
+/// Largest f64 below 1
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+/// Element `i` of a pseudo-random permutation of 0..`l` chosen by `p` (Kensler 2013)
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | (p >> 27));
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+
+    i.wrapping_add(p) % l
+}
+
+/// Base 2 Owen scrambling of the bits of `v` (Laine-Karras style hash, as in pbrt-v4)
+fn fast_owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20_adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x0552_6c56);
+    v ^= v.wrapping_mul(0x53a2_2864);
+    v.reverse_bits()
+}
+
+/// Mix a handful of integers into a well distributed 64 bit key (splitmix64 finalizer)
+pub fn hash_u64(values: &[u64]) -> u64 {
+    let mut h: u64 = 0;
+    for v in values {
+        h = (h ^ v).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+    }
+    h
+}
@@ -0,0 +1,1296 @@
+use std::{collections::HashMap, fmt::Write, sync::Arc};
+
+use crate::{
+    camera::{
+        Camera, Exposure, ExposureMode, FisheyeMapping, Projection, Stereo, StereoLayout, StereoRig,
+    },
+    checkpoint::{invalid_data, StateReader, StateWriter},
+    color::Color,
+    filter,
+    geometry::{Point3, Vec3},
+    hittable::Hittable,
+    hittable_list::{Background, HittableList},
+    integrator,
+    lens::{Aperture, ApertureImage, LensSystem},
+    material::{
+        AnisotropicMetal, BumpMap, Cutout, Dielectric, DiffuseLight, Lambertian, Material, Metal,
+        Mix, NormalMap, Sheen, Subsurface,
+    },
+    quad::Quad,
+    sampler,
+    sphere::Sphere,
+    texture::{CheckerTexture, SolidColor, Texture, UvCheckerTexture},
+};
+
+/// Where the camera stands and what it sees, as for `Camera::new`
+#[derive(Debug, Clone)]
+pub struct CameraDescription {
+    pub look_from: Point3,
+    pub look_at: Point3,
+    pub vup: Vec3,
+    pub vfov: f64,
+    pub aspect_ratio: f64,
+    pub image_width: i32,
+    pub defocus_angle: f64,
+    pub focus_dist: f64,
+    pub projection: Projection,
+    pub stereo: Option<Stereo>,
+    pub aperture: ApertureDescription,
+    /// Cat's-eye vignetting strength, as for `Camera::set_cat_eye`
+    pub cat_eye: f64,
+    pub lens: Option<LensDescription>,
+    /// Sets the aperture, as for `Camera::set_f_number`, in place of the defocus angle
+    pub f_number: Option<f64>,
+    /// Film height in scene units, giving the focal length the f-number divides
+    pub film_height: f64,
+    pub exposure: Exposure,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        CameraDescription {
+            look_from: Point3::zeros(),
+            look_at: Point3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            vup: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            vfov: 90.0,
+            aspect_ratio: 16.0 / 9.0,
+            image_width: 400,
+            defocus_angle: 0.0,
+            focus_dist: 10.0,
+            projection: Projection::Perspective,
+            stereo: None,
+            aperture: ApertureDescription::Circle,
+            cat_eye: 0.0,
+            lens: None,
+            f_number: None,
+            // 35 mm film in a scene measured in metres
+            film_height: 0.024,
+            exposure: Exposure::default(),
+        }
+    }
+}
+
+impl CameraDescription {
+    /// Paths of the files building the camera reads: the lens and aperture image
+    pub fn files(&self) -> Vec<&str> {
+        let mut files = Vec::new();
+        if let ApertureDescription::Image(path) = &self.aperture {
+            files.push(path.as_str());
+        }
+        if let Some(lens) = &self.lens {
+            files.push(lens.path.as_str());
+        }
+        files
+    }
+}
+
+/// Shape of the camera's aperture, as for `Aperture`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApertureDescription {
+    Circle,
+    Polygon {
+        blades: u32,
+        rotation: f64,
+    },
+    /// Path of a PGM or PPM image of the opening
+    Image(String),
+}
+
+impl ApertureDescription {
+    fn build(&self) -> Result<Aperture, String> {
+        Ok(match self {
+            ApertureDescription::Circle => Aperture::Circle,
+            &ApertureDescription::Polygon { blades, rotation } => {
+                Aperture::Polygon { blades, rotation }
+            }
+            ApertureDescription::Image(path) => {
+                Aperture::Image(Arc::new(ApertureImage::load(path)?))
+            }
+        })
+    }
+}
+
+/// Lens file traced by the camera, as for `LensSystem::load`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LensDescription {
+    pub path: String,
+    /// Film diagonal in millimetres
+    pub sensor_diagonal: f64,
+    /// Scene units per millimetre
+    pub scale: f64,
+}
+
+/// A texture argument: a uniform gray level, or the name of one of the scene's textures
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextureInput {
+    Gray(f64),
+    Named(String),
+}
+
+impl TextureInput {
+    fn build(
+        &self,
+        textures: &HashMap<&str, Arc<dyn Texture>>,
+    ) -> Result<Arc<dyn Texture>, String> {
+        match self {
+            &TextureInput::Gray(value) => Ok(Arc::new(SolidColor::scalar(value))),
+            TextureInput::Named(name) => textures
+                .get(name.as_str())
+                .cloned()
+                .ok_or(format!("unknown texture `{name}`")),
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            TextureInput::Gray(value) => value.to_string(),
+            TextureInput::Named(name) => name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TextureDescription {
+    Solid {
+        color: Color,
+    },
+    /// Checker in space with cells `size` units wide, as for `CheckerTexture`
+    Checker {
+        size: f64,
+        even: TextureInput,
+        odd: TextureInput,
+    },
+    /// Checker in surface (u,v) space, as for `UvCheckerTexture`
+    UvChecker {
+        cells_u: f64,
+        cells_v: f64,
+        even: TextureInput,
+        odd: TextureInput,
+    },
+}
+
+impl TextureDescription {
+    fn build(
+        &self,
+        textures: &HashMap<&str, Arc<dyn Texture>>,
+    ) -> Result<Arc<dyn Texture>, String> {
+        Ok(match self {
+            &TextureDescription::Solid { color } => Arc::new(SolidColor { albedo: color }),
+            TextureDescription::Checker { size, even, odd } => Arc::new(CheckerTexture {
+                inv_scale: 1.0 / size,
+                even: even.build(textures)?,
+                odd: odd.build(textures)?,
+            }),
+            TextureDescription::UvChecker {
+                cells_u,
+                cells_v,
+                even,
+                odd,
+            } => Arc::new(UvCheckerTexture {
+                cells_u: *cells_u,
+                cells_v: *cells_v,
+                even: even.build(textures)?,
+                odd: odd.build(textures)?,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MaterialDescription {
+    Lambertian {
+        albedo: Color,
+    },
+    Metal {
+        albedo: Color,
+        fuzz: f64,
+    },
+    Dielectric {
+        refraction_index: f64,
+    },
+    /// Emitter; objects made of it are also sampled as lights
+    Light {
+        emit: Color,
+    },
+    AnisotropicMetal {
+        albedo: Color,
+        roughness: f64,
+        anisotropy: f64,
+    },
+    Sheen {
+        albedo: Color,
+        sheen: Color,
+        roughness: f64,
+    },
+    /// Material `b` where `weight` is bright and `a` where it is dark, as for `Mix`
+    Mix {
+        a: String,
+        b: String,
+        weight: TextureInput,
+    },
+    /// `material` with holes where `alpha` is dark, as for `Cutout`
+    Cutout {
+        material: String,
+        alpha: TextureInput,
+    },
+    /// `material` with its shading normal taken from `map`, as for `NormalMap`
+    NormalMap {
+        material: String,
+        map: TextureInput,
+    },
+    /// `material` shaded as if displaced by `height`, as for `BumpMap`
+    BumpMap {
+        material: String,
+        height: TextureInput,
+        scale: f64,
+        step: f64,
+    },
+    /// Random-walk subsurface scattering, as for `Subsurface`, inside the closed shape of
+    /// each object made of it
+    Subsurface {
+        albedo: Color,
+        mean_free_path: Color,
+        refraction_index: f64,
+    },
+}
+
+impl MaterialDescription {
+    /// The material, given those it refers to, the scene's textures and, for materials that
+    /// depend on it, the shape of the object it is for
+    fn build(
+        &self,
+        materials: &HashMap<&str, Arc<dyn Material>>,
+        textures: &HashMap<&str, Arc<dyn Texture>>,
+        shape: Option<&Shape>,
+    ) -> Result<Arc<dyn Material>, String> {
+        let material = |name: &String| {
+            materials
+                .get(name.as_str())
+                .cloned()
+                .ok_or(format!("unknown material `{name}`"))
+        };
+        Ok(match self {
+            &MaterialDescription::Lambertian { albedo } => Arc::new(Lambertian { albedo }),
+            &MaterialDescription::Metal { albedo, fuzz } => Arc::new(Metal { albedo, fuzz }),
+            &MaterialDescription::Dielectric { refraction_index } => {
+                Arc::new(Dielectric { refraction_index })
+            }
+            &MaterialDescription::Light { emit } => Arc::new(DiffuseLight { emit }),
+            &MaterialDescription::AnisotropicMetal {
+                albedo,
+                roughness,
+                anisotropy,
+            } => Arc::new(AnisotropicMetal::new(albedo, roughness, anisotropy)),
+            &MaterialDescription::Sheen {
+                albedo,
+                sheen,
+                roughness,
+            } => Arc::new(Sheen {
+                albedo,
+                sheen,
+                roughness,
+            }),
+            MaterialDescription::Mix { a, b, weight } => Arc::new(Mix {
+                a: material(a)?,
+                b: material(b)?,
+                weight: weight.build(textures)?,
+            }),
+            MaterialDescription::Cutout {
+                material: inner,
+                alpha,
+            } => Arc::new(Cutout {
+                mat: material(inner)?,
+                alpha: alpha.build(textures)?,
+            }),
+            MaterialDescription::NormalMap {
+                material: inner,
+                map,
+            } => Arc::new(NormalMap {
+                mat: material(inner)?,
+                map: map.build(textures)?,
+            }),
+            MaterialDescription::BumpMap {
+                material: inner,
+                height,
+                scale,
+                step,
+            } => Arc::new(BumpMap {
+                mat: material(inner)?,
+                height: height.build(textures)?,
+                scale: *scale,
+                step: *step,
+            }),
+            &MaterialDescription::Subsurface {
+                albedo,
+                mean_free_path,
+                refraction_index,
+            } => {
+                let boundary = match shape {
+                    Some(shape @ Shape::Sphere { .. }) => {
+                        shape.build(Arc::new(Lambertian { albedo }))
+                    }
+                    Some(Shape::Quad { .. }) => {
+                        return Err("subsurface materials need a closed shape".to_string())
+                    }
+                    None => return Err("subsurface materials need an object's shape".to_string()),
+                };
+                Arc::new(Subsurface::new(
+                    boundary,
+                    albedo,
+                    mean_free_path,
+                    refraction_index,
+                ))
+            }
+        })
+    }
+
+    /// Names of the materials this one is made of
+    fn parts(&self) -> Vec<&str> {
+        match self {
+            MaterialDescription::Mix { a, b, .. } => vec![a, b],
+            MaterialDescription::Cutout { material, .. }
+            | MaterialDescription::NormalMap { material, .. }
+            | MaterialDescription::BumpMap { material, .. } => vec![material],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Sphere {
+        center: Point3,
+        radius: f64,
+    },
+    /// Parallelogram with corner `q` and edges `u` and `v`
+    Quad {
+        q: Point3,
+        u: Vec3,
+        v: Vec3,
+    },
+}
+
+impl Shape {
+    /// The object with this shape, made of `mat`
+    fn build(&self, mat: Arc<dyn Material>) -> Arc<dyn Hittable> {
+        match *self {
+            Shape::Sphere { center, radius } => Arc::new(Sphere {
+                center,
+                radius,
+                mat,
+            }),
+            Shape::Quad { q, u, v } => Arc::new(Quad::new(q, u, v, mat)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectDescription {
+    pub shape: Shape,
+    /// Name of one of the scene's materials
+    pub material: String,
+}
+
+/// A scene as data, so it can be read from a file or sent to another process.
+///
+/// Scene files hold one directive per line, with `#` starting a comment:
+///
+/// ```text
+/// camera look_from X Y Z look_at X Y Z vup X Y Z vfov DEGREES aspect RATIO width PIXELS
+///        defocus_angle DEGREES focus_dist DISTANCE
+///        [orthographic VIEW_HEIGHT | equirectangular | cylindrical HFOV
+///         | fisheye equidistant|equal-area FOV]
+///        [stereo INTEROCULAR CONVERGENCE parallel|toed-in side-by-side|top-bottom]
+///        [aperture circle | aperture blades COUNT ROTATION | aperture image PATH]
+///        [cat_eye STRENGTH] [lens PATH SENSOR_DIAGONAL UNITS_PER_MM]
+///        [f_number N] [film_height HEIGHT]
+///        [exposure radiance | exposure manual ISO SHUTTER | exposure auto] [ev STOPS]
+///        (on one line, every key optional; perspective mono unless another projection or
+///        stereo is given)
+/// background sky | background R G B
+/// texture NAME solid R G B
+/// texture NAME checker SIZE EVEN ODD
+/// texture NAME uv_checker CELLS_U CELLS_V EVEN ODD
+/// material NAME lambertian R G B
+/// material NAME metal R G B FUZZ
+/// material NAME dielectric IOR
+/// material NAME light R G B
+/// material NAME anisotropic_metal R G B ROUGHNESS ANISOTROPY
+/// material NAME sheen R G B SHEEN_R SHEEN_G SHEEN_B ROUGHNESS
+/// material NAME mix MATERIAL_A MATERIAL_B WEIGHT
+/// material NAME cutout MATERIAL ALPHA
+/// material NAME normal_map MATERIAL MAP
+/// material NAME bump_map MATERIAL HEIGHT SCALE STEP
+/// material NAME subsurface R G B MEAN_FREE_PATH_R MEAN_FREE_PATH_G MEAN_FREE_PATH_B IOR
+/// sphere X Y Z RADIUS MATERIAL
+/// quad X Y Z UX UY UZ VX VY VZ MATERIAL
+/// ```
+///
+/// Texture arguments (EVEN, ODD, WEIGHT, ALPHA, MAP, HEIGHT) are a gray level or the name of a
+/// texture.
+/// Textures and materials can only refer to ones defined above them. Subsurface materials, and
+/// those made of them, only go on spheres.
+#[derive(Debug, Clone)]
+pub struct Scene {
+    pub camera: CameraDescription,
+    pub background: Background,
+    pub textures: Vec<(String, TextureDescription)>,
+    pub materials: Vec<(String, MaterialDescription)>,
+    pub objects: Vec<ObjectDescription>,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene {
+            camera: CameraDescription::default(),
+            background: Background::Sky,
+            textures: Vec::new(),
+            materials: Vec::new(),
+            objects: Vec::new(),
+        }
+    }
+}
+
+impl Scene {
+    pub fn parse(text: &str) -> Result<Scene, String> {
+        let mut scene = Scene::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut tokens = Tokens(line.split_whitespace());
+            let Some(directive) = tokens.0.next() else {
+                continue;
+            };
+            scene
+                .parse_directive(directive, &mut tokens)
+                .and_then(|()| tokens.end())
+                .map_err(|msg| format!("line {}: {msg}", number + 1))?;
+        }
+        Ok(scene)
+    }
+
+    fn parse_directive(&mut self, directive: &str, tokens: &mut Tokens) -> Result<(), String> {
+        match directive {
+            "camera" => {
+                let camera = &mut self.camera;
+                while let Some(key) = tokens.0.next() {
+                    match key {
+                        "look_from" => camera.look_from = tokens.vec3()?,
+                        "look_at" => camera.look_at = tokens.vec3()?,
+                        "vup" => camera.vup = tokens.vec3()?,
+                        "vfov" => camera.vfov = tokens.number()?,
+                        "aspect" => camera.aspect_ratio = tokens.ratio("aspect ratio")?,
+                        "width" => camera.image_width = tokens.parse("width")?,
+                        "defocus_angle" => camera.defocus_angle = tokens.number()?,
+                        "focus_dist" => camera.focus_dist = tokens.number()?,
+                        "orthographic" => {
+                            camera.projection = Projection::Orthographic {
+                                view_height: tokens.number()?,
+                            }
+                        }
+                        "equirectangular" => camera.projection = Projection::Equirectangular,
+                        "cylindrical" => {
+                            camera.projection = Projection::Cylindrical {
+                                hfov: tokens.number()?,
+                            }
+                        }
+                        "fisheye" => {
+                            let name = tokens.word("fisheye mapping")?;
+                            let mapping = FisheyeMapping::by_name(name)
+                                .ok_or(format!("unknown fisheye mapping `{name}`"))?;
+                            camera.projection = Projection::Fisheye {
+                                mapping,
+                                fov: tokens.number()?,
+                            }
+                        }
+                        "stereo" => {
+                            let interocular = tokens.number()?;
+                            let convergence = tokens.number()?;
+                            let name = tokens.word("stereo rig")?;
+                            let rig = StereoRig::by_name(name)
+                                .ok_or(format!("unknown stereo rig `{name}`"))?;
+                            let name = tokens.word("stereo layout")?;
+                            let layout = StereoLayout::by_name(name)
+                                .ok_or(format!("unknown stereo layout `{name}`"))?;
+                            if convergence <= 0.0 {
+                                return Err("stereo convergence must be positive".to_string());
+                            }
+                            camera.stereo = Some(Stereo {
+                                interocular,
+                                convergence,
+                                rig,
+                                layout,
+                            });
+                        }
+                        "aperture" => {
+                            camera.aperture = match tokens.word("aperture shape")? {
+                                "circle" => ApertureDescription::Circle,
+                                "blades" => {
+                                    let blades = tokens.parse("blade count")?;
+                                    if blades < 3 {
+                                        return Err(
+                                            "an aperture needs at least 3 blades".to_string()
+                                        );
+                                    }
+                                    ApertureDescription::Polygon {
+                                        blades,
+                                        rotation: tokens.number()?,
+                                    }
+                                }
+                                "image" => ApertureDescription::Image(
+                                    tokens.word("aperture image path")?.to_string(),
+                                ),
+                                shape => return Err(format!("unknown aperture shape `{shape}`")),
+                            };
+                        }
+                        "cat_eye" => {
+                            camera.cat_eye = tokens.number()?;
+                            if camera.cat_eye < 0.0 {
+                                return Err("cat's-eye strength must not be negative".to_string());
+                            }
+                        }
+                        "lens" => {
+                            let path = tokens.word("lens file path")?.to_string();
+                            let sensor_diagonal = tokens.number()?;
+                            let scale = tokens.number()?;
+                            if sensor_diagonal <= 0.0 || scale <= 0.0 {
+                                return Err(
+                                    "lens sensor size and scale must be positive".to_string()
+                                );
+                            }
+                            camera.lens = Some(LensDescription {
+                                path,
+                                sensor_diagonal,
+                                scale,
+                            });
+                        }
+                        "f_number" => {
+                            let f_number = tokens.number()?;
+                            if f_number <= 0.0 {
+                                return Err("f-number must be positive".to_string());
+                            }
+                            camera.f_number = Some(f_number);
+                        }
+                        "film_height" => {
+                            camera.film_height = tokens.number()?;
+                            if camera.film_height <= 0.0 {
+                                return Err("film height must be positive".to_string());
+                            }
+                        }
+                        "exposure" => {
+                            camera.exposure.mode = match tokens.word("exposure mode")? {
+                                "radiance" => ExposureMode::Radiance,
+                                "manual" => {
+                                    let iso = tokens.number()?;
+                                    let shutter = tokens.ratio("shutter time")?;
+                                    if iso <= 0.0 || shutter <= 0.0 {
+                                        return Err(
+                                            "ISO and shutter time must be positive".to_string()
+                                        );
+                                    }
+                                    ExposureMode::Manual { iso, shutter }
+                                }
+                                "auto" => ExposureMode::Auto,
+                                mode => return Err(format!("unknown exposure mode `{mode}`")),
+                            };
+                        }
+                        "ev" => camera.exposure.compensation = tokens.number()?,
+                        _ => return Err(format!("unknown camera setting `{key}`")),
+                    }
+                }
+                if camera.image_width < 1 {
+                    return Err("camera width must be at least 1".to_string());
+                }
+                if !(camera.aspect_ratio.is_finite() && camera.aspect_ratio > 0.0) {
+                    return Err("camera aspect ratio must be positive".to_string());
+                }
+            }
+            "background" => {
+                self.background = if tokens.0.clone().next() == Some("sky") {
+                    tokens.0.next();
+                    Background::Sky
+                } else {
+                    Background::Solid(tokens.vec3()?)
+                };
+            }
+            "texture" => {
+                let name = tokens.word("texture name")?.to_string();
+                if name.parse::<f64>().is_ok() {
+                    return Err(format!("texture name `{name}` reads as a gray level"));
+                }
+                let kind = tokens.word("texture type")?;
+                let texture = match kind {
+                    "solid" => TextureDescription::Solid {
+                        color: tokens.vec3()?,
+                    },
+                    "checker" => {
+                        let size = tokens.number()?;
+                        if size <= 0.0 {
+                            return Err("checker size must be positive".to_string());
+                        }
+                        TextureDescription::Checker {
+                            size,
+                            even: self.texture_input(tokens)?,
+                            odd: self.texture_input(tokens)?,
+                        }
+                    }
+                    "uv_checker" => TextureDescription::UvChecker {
+                        cells_u: tokens.number()?,
+                        cells_v: tokens.number()?,
+                        even: self.texture_input(tokens)?,
+                        odd: self.texture_input(tokens)?,
+                    },
+                    _ => return Err(format!("unknown texture type `{kind}`")),
+                };
+                if self.textures.iter().any(|(existing, _)| *existing == name) {
+                    return Err(format!("texture `{name}` is defined twice"));
+                }
+                self.textures.push((name, texture));
+            }
+            "material" => {
+                let name = tokens.word("material name")?.to_string();
+                let kind = tokens.word("material type")?;
+                let material = match kind {
+                    "lambertian" => MaterialDescription::Lambertian {
+                        albedo: tokens.vec3()?,
+                    },
+                    "metal" => MaterialDescription::Metal {
+                        albedo: tokens.vec3()?,
+                        fuzz: tokens.number()?,
+                    },
+                    "dielectric" => MaterialDescription::Dielectric {
+                        refraction_index: tokens.number()?,
+                    },
+                    "light" => MaterialDescription::Light {
+                        emit: tokens.vec3()?,
+                    },
+                    "anisotropic_metal" => MaterialDescription::AnisotropicMetal {
+                        albedo: tokens.vec3()?,
+                        roughness: tokens.number()?,
+                        anisotropy: tokens.number()?,
+                    },
+                    "sheen" => MaterialDescription::Sheen {
+                        albedo: tokens.vec3()?,
+                        sheen: tokens.vec3()?,
+                        roughness: tokens.number()?,
+                    },
+                    "mix" => MaterialDescription::Mix {
+                        a: self.material_name(tokens)?,
+                        b: self.material_name(tokens)?,
+                        weight: self.texture_input(tokens)?,
+                    },
+                    "cutout" => MaterialDescription::Cutout {
+                        material: self.material_name(tokens)?,
+                        alpha: self.texture_input(tokens)?,
+                    },
+                    "normal_map" => MaterialDescription::NormalMap {
+                        material: self.material_name(tokens)?,
+                        map: self.texture_input(tokens)?,
+                    },
+                    "bump_map" => {
+                        let material = self.material_name(tokens)?;
+                        let height = self.texture_input(tokens)?;
+                        let scale = tokens.number()?;
+                        let step = tokens.number()?;
+                        if step <= 0.0 {
+                            return Err("bump map step must be positive".to_string());
+                        }
+                        MaterialDescription::BumpMap {
+                            material,
+                            height,
+                            scale,
+                            step,
+                        }
+                    }
+                    "subsurface" => {
+                        let albedo = tokens.vec3()?;
+                        let mean_free_path = tokens.vec3()?;
+                        let refraction_index = tokens.number()?;
+                        if [mean_free_path.x, mean_free_path.y, mean_free_path.z]
+                            .iter()
+                            .any(|&d| d <= 0.0 || !d.is_finite())
+                        {
+                            return Err("subsurface mean free path must be positive".to_string());
+                        }
+                        MaterialDescription::Subsurface {
+                            albedo,
+                            mean_free_path,
+                            refraction_index,
+                        }
+                    }
+                    _ => return Err(format!("unknown material type `{kind}`")),
+                };
+                if self.materials.iter().any(|(existing, _)| *existing == name) {
+                    return Err(format!("material `{name}` is defined twice"));
+                }
+                self.materials.push((name, material));
+            }
+            "sphere" | "quad" => {
+                let shape = if directive == "sphere" {
+                    Shape::Sphere {
+                        center: tokens.vec3()?,
+                        radius: tokens.number()?,
+                    }
+                } else {
+                    Shape::Quad {
+                        q: tokens.vec3()?,
+                        u: tokens.vec3()?,
+                        v: tokens.vec3()?,
+                    }
+                };
+                let material = self.material_name(tokens)?;
+                self.objects.push(ObjectDescription { shape, material });
+            }
+            _ => return Err(format!("unknown directive `{directive}`")),
+        }
+        Ok(())
+    }
+
+    /// Name of a material defined so far
+    fn material_name(&self, tokens: &mut Tokens) -> Result<String, String> {
+        let name = tokens.word("material name")?;
+        if !self.materials.iter().any(|(material, _)| material == name) {
+            return Err(format!("unknown material `{name}`"));
+        }
+        Ok(name.to_string())
+    }
+
+    /// Gray level, or name of a texture defined so far
+    fn texture_input(&self, tokens: &mut Tokens) -> Result<TextureInput, String> {
+        let word = tokens.word("texture")?;
+        if let Ok(value) = word.parse() {
+            return Ok(TextureInput::Gray(value));
+        }
+        if !self.textures.iter().any(|(texture, _)| texture == word) {
+            return Err(format!("unknown texture `{word}`"));
+        }
+        Ok(TextureInput::Named(word.to_string()))
+    }
+
+    /// The scene file `parse` reads back as this scene, numbers included exactly
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let c = &self.camera;
+        write!(
+            text,
+            "camera look_from {} look_at {} vup {} vfov {} aspect {} width {} defocus_angle {} focus_dist {}",
+            v3(c.look_from),
+            v3(c.look_at),
+            v3(c.vup),
+            c.vfov,
+            c.aspect_ratio,
+            c.image_width,
+            c.defocus_angle,
+            c.focus_dist
+        )
+        .unwrap();
+        match c.projection {
+            Projection::Perspective => {}
+            Projection::Orthographic { view_height } => {
+                write!(text, " orthographic {view_height}").unwrap()
+            }
+            Projection::Equirectangular => write!(text, " equirectangular").unwrap(),
+            Projection::Cylindrical { hfov } => write!(text, " cylindrical {hfov}").unwrap(),
+            Projection::Fisheye { mapping, fov } => {
+                write!(text, " fisheye {} {fov}", mapping.name()).unwrap()
+            }
+        }
+        if let Some(stereo) = c.stereo {
+            write!(
+                text,
+                " stereo {} {} {} {}",
+                stereo.interocular,
+                stereo.convergence,
+                stereo.rig.name(),
+                stereo.layout.name()
+            )
+            .unwrap();
+        }
+        match &c.aperture {
+            ApertureDescription::Circle => {}
+            ApertureDescription::Polygon { blades, rotation } => {
+                write!(text, " aperture blades {blades} {rotation}").unwrap()
+            }
+            ApertureDescription::Image(path) => write!(text, " aperture image {path}").unwrap(),
+        }
+        if c.cat_eye != 0.0 {
+            write!(text, " cat_eye {}", c.cat_eye).unwrap();
+        }
+        if let Some(lens) = &c.lens {
+            write!(
+                text,
+                " lens {} {} {}",
+                lens.path, lens.sensor_diagonal, lens.scale
+            )
+            .unwrap();
+        }
+        if let Some(f_number) = c.f_number {
+            write!(text, " f_number {f_number} film_height {}", c.film_height).unwrap();
+        }
+        match c.exposure.mode {
+            ExposureMode::Radiance => {}
+            ExposureMode::Manual { iso, shutter } => {
+                write!(text, " exposure manual {iso} {shutter}").unwrap()
+            }
+            ExposureMode::Auto => write!(text, " exposure auto").unwrap(),
+        }
+        if c.exposure.compensation != 0.0 {
+            write!(text, " ev {}", c.exposure.compensation).unwrap();
+        }
+        text.push('\n');
+
+        match self.background {
+            Background::Sky => writeln!(text, "background sky").unwrap(),
+            Background::Solid(color) => writeln!(text, "background {}", v3(color)).unwrap(),
+        }
+
+        for (name, texture) in &self.textures {
+            let description = match texture {
+                TextureDescription::Solid { color } => format!("solid {}", v3(*color)),
+                TextureDescription::Checker { size, even, odd } => {
+                    format!("checker {size} {} {}", even.text(), odd.text())
+                }
+                TextureDescription::UvChecker {
+                    cells_u,
+                    cells_v,
+                    even,
+                    odd,
+                } => format!(
+                    "uv_checker {cells_u} {cells_v} {} {}",
+                    even.text(),
+                    odd.text()
+                ),
+            };
+            writeln!(text, "texture {name} {description}").unwrap();
+        }
+
+        for (name, material) in &self.materials {
+            let description = match material {
+                MaterialDescription::Lambertian { albedo } => format!("lambertian {}", v3(*albedo)),
+                MaterialDescription::Metal { albedo, fuzz } => {
+                    format!("metal {} {fuzz}", v3(*albedo))
+                }
+                MaterialDescription::Dielectric { refraction_index } => {
+                    format!("dielectric {refraction_index}")
+                }
+                MaterialDescription::Light { emit } => format!("light {}", v3(*emit)),
+                MaterialDescription::AnisotropicMetal {
+                    albedo,
+                    roughness,
+                    anisotropy,
+                } => format!("anisotropic_metal {} {roughness} {anisotropy}", v3(*albedo)),
+                MaterialDescription::Sheen {
+                    albedo,
+                    sheen,
+                    roughness,
+                } => format!("sheen {} {} {roughness}", v3(*albedo), v3(*sheen)),
+                MaterialDescription::Mix { a, b, weight } => {
+                    format!("mix {a} {b} {}", weight.text())
+                }
+                MaterialDescription::Cutout { material, alpha } => {
+                    format!("cutout {material} {}", alpha.text())
+                }
+                MaterialDescription::NormalMap { material, map } => {
+                    format!("normal_map {material} {}", map.text())
+                }
+                MaterialDescription::BumpMap {
+                    material,
+                    height,
+                    scale,
+                    step,
+                } => format!("bump_map {material} {} {scale} {step}", height.text()),
+                MaterialDescription::Subsurface {
+                    albedo,
+                    mean_free_path,
+                    refraction_index,
+                } => format!(
+                    "subsurface {} {} {refraction_index}",
+                    v3(*albedo),
+                    v3(*mean_free_path)
+                ),
+            };
+            writeln!(text, "material {name} {description}").unwrap();
+        }
+
+        for object in &self.objects {
+            match object.shape {
+                Shape::Sphere { center, radius } => {
+                    writeln!(text, "sphere {} {radius} {}", v3(center), object.material)
+                }
+                Shape::Quad { q, u, v } => writeln!(
+                    text,
+                    "quad {} {} {} {}",
+                    v3(q),
+                    v3(u),
+                    v3(v),
+                    object.material
+                ),
+            }
+            .unwrap();
+        }
+
+        text
+    }
+
+    pub fn build_world(&self) -> Result<HittableList, String> {
+        let mut textures = HashMap::new();
+        for (name, texture) in &self.textures {
+            let built = texture.build(&textures)?;
+            textures.insert(name.as_str(), built);
+        }
+        // Materials that depend on an object's shape are built for each object
+        let mut materials = HashMap::new();
+        for (name, material) in &self.materials {
+            if !self.per_object(name) {
+                let built = material.build(&materials, &textures, None)?;
+                materials.insert(name.as_str(), built);
+            }
+        }
+
+        let mut world = HittableList::new();
+        world.background = self.background;
+        for object in &self.objects {
+            let mat =
+                self.object_material(&object.material, &object.shape, &materials, &textures)?;
+            let hittable = object.shape.build(mat);
+            if self.emits(&object.material) {
+                world.add_light(hittable);
+            } else {
+                world.objects.push(hittable);
+            }
+        }
+        Ok(world)
+    }
+
+    /// Material `name` for an object shaped `shape`, given the materials shared by all objects
+    fn object_material(
+        &self,
+        name: &str,
+        shape: &Shape,
+        shared: &HashMap<&str, Arc<dyn Material>>,
+        textures: &HashMap<&str, Arc<dyn Texture>>,
+    ) -> Result<Arc<dyn Material>, String> {
+        if let Some(material) = shared.get(name) {
+            return Ok(material.clone());
+        }
+        let (_, description) = self
+            .materials
+            .iter()
+            .find(|(material, _)| material == name)
+            .ok_or(format!("unknown material `{name}`"))?;
+        let mut parts = HashMap::new();
+        for part in description.parts() {
+            parts.insert(part, self.object_material(part, shape, shared, textures)?);
+        }
+        description.build(&parts, textures, Some(shape))
+    }
+
+    /// Whether material `name` is, or is partly, built for each object
+    fn per_object(&self, name: &str) -> bool {
+        self.materials
+            .iter()
+            .find(|(material, _)| material == name)
+            .is_some_and(|(_, material)| {
+                matches!(material, MaterialDescription::Subsurface { .. })
+                    || material
+                        .parts()
+                        .into_iter()
+                        .any(|part| self.per_object(part))
+            })
+    }
+
+    /// Whether material `name` is, or is partly, a light
+    fn emits(&self, name: &str) -> bool {
+        self.materials
+            .iter()
+            .find(|(material, _)| material == name)
+            .is_some_and(|(_, material)| {
+                matches!(material, MaterialDescription::Light { .. })
+                    || material.parts().into_iter().any(|part| self.emits(part))
+            })
+    }
+
+    /// Camera for this scene, rendering with `settings`
+    pub fn build_camera(&self, settings: &RenderSettings) -> Result<Camera, String> {
+        let c = &self.camera;
+        let mut camera = Camera::new(
+            c.aspect_ratio,
+            c.image_width,
+            settings.max_depth,
+            c.vfov,
+            c.look_from,
+            c.look_at,
+            c.vup,
+            c.defocus_angle,
+            c.focus_dist,
+        );
+        camera.set_projection(c.projection);
+        camera.set_stereo(c.stereo)?;
+        camera.set_aperture(c.aperture.build()?);
+        camera.set_cat_eye(c.cat_eye);
+        if let Some(lens) = &c.lens {
+            let system = LensSystem::load(&lens.path, lens.sensor_diagonal, lens.scale)?;
+            camera.set_lens_system(Some(system))?;
+        }
+        if let Some(f_number) = c.f_number {
+            camera.set_f_number(f_number, c.film_height);
+        }
+        camera.set_exposure(c.exposure)?;
+        settings.apply(&mut camera)?;
+        Ok(camera)
+    }
+}
+
+/// Settings that decide what a render computes (as opposed to how it is run and reported),
+/// by name so they can be passed on the command line or sent to another process
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderSettings {
+    pub integrator: String,
+    pub max_depth: i32,
+    pub samples_per_pixel: i32,
+    pub seed: u64,
+    pub sampler: String,
+    pub filter: String,
+    /// Filter radius in pixels, or the filter's default
+    pub filter_radius: Option<f64>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            integrator: String::from("path"),
+            max_depth: 50,
+            samples_per_pixel: 500,
+            seed: 0,
+            sampler: String::from("random"),
+            filter: String::from("box"),
+            filter_radius: None,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// Set `camera` up to render with these settings
+    pub fn apply(&self, camera: &mut Camera) -> Result<(), String> {
+        let integrator = integrator::by_name(&self.integrator, self.max_depth)
+            .ok_or(format!("unknown integrator `{}`", self.integrator))?;
+        let sampler =
+            sampler::by_name(&self.sampler).ok_or(format!("unknown sampler `{}`", self.sampler))?;
+        let filter = filter::by_name(&self.filter, self.filter_radius)
+            .ok_or(format!("unknown filter `{}`", self.filter))?;
+
+        camera.set_integrator(integrator);
+        camera.set_samples_per_pixel(self.samples_per_pixel);
+        camera.set_seed(self.seed);
+        camera.set_sampler(sampler);
+        camera.set_filter(filter);
+        Ok(())
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.write_bytes(self.integrator.as_bytes());
+        out.write_u64(self.max_depth as u64);
+        out.write_u64(self.samples_per_pixel as u64);
+        out.write_u64(self.seed);
+        out.write_bytes(self.sampler.as_bytes());
+        out.write_bytes(self.filter.as_bytes());
+        out.write_bool(self.filter_radius.is_some());
+        out.write_f64(self.filter_radius.unwrap_or(0.0));
+    }
+
+    pub fn read_state(input: &mut StateReader) -> std::io::Result<RenderSettings> {
+        Ok(RenderSettings {
+            integrator: read_string(input)?,
+            max_depth: input.read_u64()? as i32,
+            samples_per_pixel: input.read_u64()? as i32,
+            seed: input.read_u64()?,
+            sampler: read_string(input)?,
+            filter: read_string(input)?,
+            filter_radius: {
+                let has_radius = input.read_bool()?;
+                let radius = input.read_f64()?;
+                has_radius.then_some(radius)
+            },
+        })
+    }
+}
+
+fn read_string(input: &mut StateReader) -> std::io::Result<String> {
+    String::from_utf8(input.read_bytes()?.to_vec())
+        .map_err(|_| invalid_data("invalid text in render settings"))
+}
+
+fn v3(v: Vec3) -> String {
+    format!("{} {} {}", v.x, v.y, v.z)
+}
+
+/// The whitespace separated words of one line of a scene (or animation) file
+pub(crate) struct Tokens<'a>(pub(crate) std::str::SplitWhitespace<'a>);
+
+impl<'a> Tokens<'a> {
+    pub(crate) fn word(&mut self, what: &str) -> Result<&'a str, String> {
+        self.0.next().ok_or(format!("missing {what}"))
+    }
+
+    pub(crate) fn parse<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, String> {
+        let word = self.word(what)?;
+        word.parse().map_err(|_| format!("invalid {what} `{word}`"))
+    }
+
+    pub(crate) fn number(&mut self) -> Result<f64, String> {
+        self.parse("number")
+    }
+
+    pub(crate) fn vec3(&mut self) -> Result<Vec3, String> {
+        Ok(Vec3 {
+            x: self.number()?,
+            y: self.number()?,
+            z: self.number()?,
+        })
+    }
+
+    /// A number, or a fraction like `16/9`
+    pub(crate) fn ratio(&mut self, what: &str) -> Result<f64, String> {
+        let word = self.word(what)?;
+        let invalid = || format!("invalid {what} `{word}`");
+        match word.split_once('/') {
+            Some((a, b)) => {
+                let a: f64 = a.parse().map_err(|_| invalid())?;
+                let b: f64 = b.parse().map_err(|_| invalid())?;
+                Ok(a / b)
+            }
+            None => word.parse().map_err(|_| invalid()),
+        }
+    }
+
+    pub(crate) fn end(&mut self) -> Result<(), String> {
+        match self.0.next() {
+            Some(word) => Err(format!("unexpected `{word}`")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        geometry::{Interval, Ray},
+        sampler::RandomSampler,
+    };
+
+    #[test]
+    fn textures_and_composite_materials_round_trip() {
+        let text = "\
+camera look_from 0 0 0 look_at 0 0 -1 width 8
+texture bars uv_checker 6 1 0 1
+texture tiles checker 0.5 bars 0.9
+material red lambertian 0.65 0.05 0.05
+material glow light 4 4 4
+material blend mix red glow tiles
+material fence cutout blend 0.25
+material bumpy bump_map fence tiles 0.1 0.001
+material dented normal_map bumpy 0.5
+quad 0 0 -1 1 0 0 0 1 0 fence
+quad 0 0 -2 1 0 0 0 1 0 dented
+";
+        let scene = Scene::parse(text).unwrap();
+        assert_eq!(
+            scene.to_text(),
+            Scene::parse(&scene.to_text()).unwrap().to_text()
+        );
+
+        // Made partly of a light, so they are sampled as lights
+        let world = scene.build_world().unwrap();
+        assert_eq!(world.lights.len(), 2);
+    }
+
+    #[test]
+    fn subsurface_walks_inside_the_shape_of_its_own_object() {
+        let text = "\
+camera look_from 0 0 0 look_at 0 0 -1 width 8
+material wax subsurface 0.9 0.8 0.7 0.05 0.05 0.05 1.4
+material red lambertian 0.65 0.05 0.05
+material waxy mix wax red 0
+sphere -3 0 -5 1 wax
+sphere 3 0 -5 0.5 waxy
+";
+        let scene = Scene::parse(text).unwrap();
+        assert_eq!(
+            scene.to_text(),
+            Scene::parse(&scene.to_text()).unwrap().to_text()
+        );
+        let world = scene.build_world().unwrap();
+        let ray_t = Interval {
+            min: 0.001,
+            max: f64::INFINITY,
+        };
+        let mut sampler = RandomSampler::new(1);
+        for (center, radius) in [
+            (
+                Vec3 {
+                    x: -3.0,
+                    y: 0.0,
+                    z: -5.0,
+                },
+                1.0,
+            ),
+            (
+                Vec3 {
+                    x: 3.0,
+                    y: 0.0,
+                    z: -5.0,
+                },
+                0.5,
+            ),
+        ] {
+            let ray = Ray {
+                orig: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                dir: center,
+            };
+            let rec = world.hit(&ray, &ray_t).unwrap();
+            let mut exits = 0;
+            for _ in 0..200 {
+                if let Some(result) = rec.mat.scatter(&ray, &rec, &mut sampler) {
+                    let distance = (result.scattered.orig - center).length();
+                    assert!((distance - radius).abs() < 1e-6, "{distance} vs {radius}");
+                    exits += 1;
+                }
+            }
+            assert!(exits > 0);
+        }
+
+        let err = Scene::parse(
+            "material wax subsurface 1 1 1 0.1 0.1 0.1 1.4\nquad 0 0 -1 1 0 0 0 1 0 wax",
+        )
+        .unwrap()
+        .build_world()
+        .err()
+        .unwrap();
+        assert!(err.contains("closed shape"), "{err}");
+        let err = Scene::parse("material wax subsurface 1 1 1 0.1 0 0.1 1.4").unwrap_err();
+        assert!(err.contains("mean free path"), "{err}");
+    }
+
+    #[test]
+    fn materials_and_textures_must_be_defined_before_use() {
+        let err = Scene::parse("material a mix b b 0.5\nmaterial b lambertian 1 1 1").unwrap_err();
+        assert!(err.contains("unknown material `b`"), "{err}");
+        let err = Scene::parse("material a cutout a missing").unwrap_err();
+        assert!(err.contains("unknown material `a`"), "{err}");
+        let err =
+            Scene::parse("material a lambertian 1 1 1\nmaterial b cutout a missing").unwrap_err();
+        assert!(err.contains("unknown texture `missing`"), "{err}");
+    }
+}
@@ -1,9 +1,10 @@
-use std::sync::Arc;
+use std::{f64::consts::PI, sync::Arc};
 
 use crate::{
-    geometry::{Interval, Point3, Ray, Vec3},
+    geometry::{Interval, Onb, Point3, Ray, Vec3},
     hittable::{HitRecord, Hittable},
     material::Material,
+    sampler::Sampler,
 };
 
 pub struct Sphere {
@@ -12,8 +13,54 @@ pub struct Sphere {
     pub mat: Arc<dyn Material>,
 }
 
-impl Hittable for Sphere {
-    fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+impl Sphere {
+    /// Map a point `p` on the unit sphere to (u,v) texture coordinates in \[0,1\]
+    fn get_sphere_uv(p: Point3) -> (f64, f64) {
+        let theta = (-p.y).acos();
+        let phi = f64::atan2(-p.z, p.x) + PI;
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    /// Partial derivatives (dp/du, dp/dv) of the `get_sphere_uv` parameterization at unit normal `n`
+    fn partials(&self, n: Vec3) -> (Vec3, Vec3) {
+        let dpdu = (2.0 * PI * self.radius)
+            * Vec3 {
+                x: n.z,
+                y: 0.0,
+                z: -n.x,
+            };
+
+        // sin(theta) vanishes at the poles, where dp/dv has no well-defined direction
+        let sin_theta = (n.x * n.x + n.z * n.z).sqrt();
+        let dpdv = if sin_theta < 1e-8 {
+            Vec3::zeros()
+        } else {
+            (PI * self.radius)
+                * Vec3 {
+                    x: -n.y * n.x / sin_theta,
+                    y: sin_theta,
+                    z: -n.y * n.z / sin_theta,
+                }
+        };
+
+        (dpdu, dpdv)
+    }
+
+    /// Direction within the cone subtended by a sphere of `radius` at squared `distance_squared`,
+    /// in a frame whose +z points at the sphere's center
+    fn random_to_sphere(radius: f64, distance_squared: f64, sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.next_2d();
+        let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
+
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * (1.0 - z * z).sqrt();
+        let y = phi.sin() * (1.0 - z * z).sqrt();
+
+        Vec3 { x, y, z }
+    }
+
+    /// Near and far ray parameters where `r` crosses the sphere, if it does
+    fn roots(&self, r: &Ray) -> Option<[f64; 2]> {
         let oc = self.center - r.orig;
         let a = r.dir.length_squared();
         let h = Vec3::dot(r.dir, oc);
@@ -25,20 +72,90 @@ impl Hittable for Sphere {
         }
 
         let sqrtd = discriminant.sqrt();
-        let mut root = (h - sqrtd) / a;
-        if !(ray_t.surrounds(root)) {
-            root = (h + sqrtd) / a;
+        Some([(h - sqrtd) / a, (h + sqrtd) / a])
+    }
+}
+
+impl Hittable for Sphere {
+    fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+        // Try the near root first, then the far one if it is out of range or alpha-culled
+        for root in self.roots(r)? {
             if !ray_t.surrounds(root) {
-                return None;
+                continue;
             }
+
+            let mut rec = HitRecord::new(Arc::clone(&self.mat));
+            rec.t = root;
+            rec.p = r.at(rec.t);
+            let outward_normal = (rec.p - self.center) / self.radius;
+            (rec.u, rec.v) = Self::get_sphere_uv(outward_normal);
+            (rec.dpdu, rec.dpdv) = self.partials(outward_normal);
+            rec.set_face_normal(r, outward_normal);
+
+            if rec.alpha_test(r) {
+                return Some(rec);
+            }
+        }
+
+        None
+    }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f64 {
+        let distance_squared = (self.center - origin).length_squared();
+        let radius_squared = self.radius * self.radius;
+
+        // From inside, directions are sampled uniformly over the whole sphere
+        if distance_squared <= radius_squared {
+            return 1.0 / (4.0 * PI);
+        }
+
+        let ray_t = Interval {
+            min: 0.001,
+            max: f64::INFINITY,
+        };
+        // `random` samples the whole cone, so cut-outs count here too
+        let hits = self
+            .roots(&Ray { orig: origin, dir })
+            .is_some_and(|roots| roots.iter().any(|&root| ray_t.surrounds(root)));
+        if !hits {
+            return 0.0;
+        }
+
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = self.center - origin;
+        let distance_squared = direction.length_squared();
+
+        if distance_squared <= self.radius * self.radius {
+            return Vec3::rand_unit_vector(sampler);
         }
 
+        Onb::from_w(direction).local(Self::random_to_sphere(
+            self.radius,
+            distance_squared,
+            sampler,
+        ))
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let outward_normal = Vec3::rand_unit_vector(sampler);
+
         let mut rec = HitRecord::new(Arc::clone(&self.mat));
-        rec.t = root;
-        rec.p = r.at(rec.t);
-        let outward_normal = (rec.p - self.center) / self.radius;
-        rec.set_face_normal(r, outward_normal);
+        rec.p = self.center + self.radius * outward_normal;
+        (rec.u, rec.v) = Self::get_sphere_uv(outward_normal);
+        (rec.dpdu, rec.dpdv) = self.partials(outward_normal);
+        rec.normal = outward_normal;
+        rec.tangent = Onb::from_w_tangent(outward_normal, rec.dpdu).u;
+
+        let pdf = self.surface_pdf(rec.p);
+        Some((rec, pdf))
+    }
 
-        return Some(rec);
+    fn surface_pdf(&self, _p: Point3) -> f64 {
+        1.0 / (4.0 * PI * self.radius * self.radius)
     }
 }
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use crate::{color::Color, geometry::Point3};
+
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, p: Point3) -> Color;
+}
+
+pub struct SolidColor {
+    pub albedo: Color,
+}
+
+impl SolidColor {
+    /// Grayscale texture, handy for scalar weights and alpha masks
+    pub fn scalar(value: f64) -> SolidColor {
+        SolidColor {
+            albedo: Color {
+                x: value,
+                y: value,
+                z: value,
+            },
+        }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _p: Point3) -> Color {
+        self.albedo
+    }
+}
+
+/// 3D checker pattern alternating between `even` and `odd` every `1 / inv_scale` units
+pub struct CheckerTexture {
+    pub inv_scale: f64,
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f64, v: f64, p: Point3) -> Color {
+        let x = (self.inv_scale * p.x).floor() as i64;
+        let y = (self.inv_scale * p.y).floor() as i64;
+        let z = (self.inv_scale * p.z).floor() as i64;
+
+        if (x + y + z) % 2 == 0 {
+            self.even.value(u, v, p)
+        } else {
+            self.odd.value(u, v, p)
+        }
+    }
+}
+
+/// Checker pattern in surface (u,v) space, `cells_u` x `cells_v` cells over the unit square.
+/// Useful as a binary mask for fences and grates.
+pub struct UvCheckerTexture {
+    pub cells_u: f64,
+    pub cells_v: f64,
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+}
+
+impl Texture for UvCheckerTexture {
+    fn value(&self, u: f64, v: f64, p: Point3) -> Color {
+        let iu = (u * self.cells_u).floor() as i64;
+        let iv = (v * self.cells_v).floor() as i64;
+
+        if (iu + iv) % 2 == 0 {
+            self.even.value(u, v, p)
+        } else {
+            self.odd.value(u, v, p)
+        }
+    }
+}
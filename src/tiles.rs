@@ -0,0 +1,101 @@
+/// Rectangle of pixels \[x0,x1) x \[y0,y1) rendered as one unit of work
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl Tile {
+    pub fn width(&self) -> i32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> i32 {
+        self.y1 - self.y0
+    }
+}
+
+/// Order tiles are handed out in; it decides where the image fills in first, not what it looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Row by row from the top left
+    Scanline,
+    /// Outwards from the center in square rings, so the middle of the image shows up first
+    Spiral,
+    /// Along a Hilbert curve, keeping consecutive tiles next to each other
+    Hilbert,
+}
+
+/// Look up a tile order by its command line name
+pub fn by_name(name: &str) -> Option<TileOrder> {
+    match name {
+        "scanline" => Some(TileOrder::Scanline),
+        "spiral" => Some(TileOrder::Spiral),
+        "hilbert" => Some(TileOrder::Hilbert),
+        _ => None,
+    }
+}
+
+/// Cover a `width` x `height` image with tiles of `size` pixels square (smaller along the
+/// right and bottom edges), listed in `order`
+pub fn tiles(width: i32, height: i32, size: i32, order: TileOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let columns = (width.max(0) + size - 1) / size;
+    let rows = (height.max(0) + size - 1) / size;
+
+    let mut cells: Vec<(i32, i32)> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            // Ring by ring around the center, clockwise from the top within each ring
+            let center = ((columns - 1) as f64 / 2.0, (rows - 1) as f64 / 2.0);
+            let key = |&(column, row): &(i32, i32)| {
+                let (dx, dy) = (column as f64 - center.0, row as f64 - center.1);
+                let ring = dx.abs().max(dy.abs()).ceil() as i64;
+                let angle = f64::atan2(dx, -dy).rem_euclid(std::f64::consts::TAU);
+                (ring, (angle * 1e6) as i64)
+            };
+            cells.sort_by_key(key);
+        }
+        TileOrder::Hilbert => {
+            let side = (columns.max(rows).max(1) as u32).next_power_of_two();
+            cells.sort_by_key(|&(column, row)| hilbert_index(side, column as u32, row as u32));
+        }
+    }
+
+    cells
+        .into_iter()
+        .map(|(column, row)| Tile {
+            x0: column * size,
+            y0: row * size,
+            x1: ((column + 1) * size).min(width),
+            y1: ((row + 1) * size).min(height),
+        })
+        .collect()
+}
+
+/// Distance along the Hilbert curve filling a `side` x `side` grid (a power of two) to cell (`x`,`y`)
+fn hilbert_index(side: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = u32::from(x & s > 0);
+        let ry = u32::from(y & s > 0);
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+
+        // Rotate the quadrant so the curve inside it runs the right way
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
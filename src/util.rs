@@ -1,11 +1,33 @@
-use rand::Rng;
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+};
 
-pub fn rand_f64() -> f64 {
-    let mut rng = rand::rng();
-    rng.random_range(0.0..1.0)
+/// Hash a handful of floats into a uniform value in [0,1).
+/// Used where a decision must be random-looking but repeatable for the same inputs.
+pub fn hash_f64(values: &[f64]) -> f64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for v in values {
+        h ^= v.to_bits();
+        h = h.wrapping_mul(0x100_0000_01b3);
+        h ^= h >> 29;
+    }
+    // splitmix64 finalizer
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+    (h >> 11) as f64 / (1u64 << 53) as f64
 }
 
-pub fn rand_f64_range(min: f64, max: f64) -> f64 {
-    let mut rng = rand::rng();
-    rng.random_range(min..max)
+/// Write a file through `contents` to a temporary path and rename it into place, so readers
+/// (an image viewer watching a progressive render, a resume after a crash) never see it half-written
+pub fn replace_file(
+    path: &str,
+    contents: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let partial_path = format!("{path}.partial");
+    let mut file = BufWriter::new(File::create(&partial_path)?);
+    contents(&mut file)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(partial_path, path)
 }
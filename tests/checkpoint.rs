@@ -0,0 +1,152 @@
+use std::{fs, path::PathBuf, sync::Arc};
+
+use raytracing::{
+    camera::{AdaptiveSampling, Camera, Checkpoints, SnapshotInterval},
+    hittable_list::HittableList,
+    image::Image,
+    integrator::{self, Integrator},
+    photon::ProgressivePhotonMapper,
+    progress::progress_channel,
+    scene::{RenderSettings, Scene},
+};
+
+const SCENE: &str = "\
+camera look_from 0 1 4 look_at 0 0.5 0 vfov 40 aspect 1.5 width 24
+background sky
+material floor lambertian 0.5 0.5 0.5
+material glass dielectric 1.5
+material lamp light 4 4 4
+sphere 0 -1000 0 1000 floor
+sphere -0.8 0.5 0 0.5 glass
+quad -1 2 -1 2 0 0 0 0 2 lamp
+";
+
+/// The integrator `settings` names, with fewer photons than usual to keep the test quick
+fn integrator(settings: &RenderSettings) -> Arc<dyn Integrator> {
+    match settings.integrator.as_str() {
+        "sppm" => Arc::new(ProgressivePhotonMapper::new(2_000, settings.max_depth)),
+        name => integrator::by_name(name, settings.max_depth).unwrap(),
+    }
+}
+
+fn setup(settings: &RenderSettings, adaptive: bool) -> (Camera, Arc<HittableList>) {
+    let scene = Scene::parse(SCENE).unwrap();
+    let mut camera = scene.build_camera(settings).unwrap();
+    camera.set_integrator(integrator(settings));
+    camera.set_quiet(true);
+    if adaptive {
+        camera.set_adaptive_sampling(Some(AdaptiveSampling {
+            min_samples: 2,
+            target_error: 0.2,
+            debug_image: None,
+        }));
+    }
+    (camera, Arc::new(scene.build_world().unwrap()))
+}
+
+fn render(camera: &Camera, world: Arc<HittableList>) -> std::io::Result<Image> {
+    Ok(camera.render_to_image(world, &mut |_| {})?.0)
+}
+
+fn bits(image: &Image) -> Vec<[u64; 3]> {
+    image
+        .pixels
+        .iter()
+        .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+        .collect()
+}
+
+fn checkpoint_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "raytracing-checkpoint-test-{}-{name}",
+        std::process::id()
+    ))
+}
+
+#[test]
+fn stopping_and_resuming_matches_an_uninterrupted_render() {
+    for (integrator, adaptive) in [
+        ("path", false),
+        ("path", true),
+        ("bdpt", false),
+        ("sppm", false),
+        ("mlt", false),
+    ] {
+        let settings = RenderSettings {
+            integrator: integrator.to_string(),
+            max_depth: 8,
+            samples_per_pixel: 8,
+            seed: 3,
+            filter: "gaussian".to_string(),
+            ..RenderSettings::default()
+        };
+        let (camera, world) = setup(&settings, adaptive);
+        let full = bits(&render(&camera, world).unwrap());
+
+        // Stop after 3 of the 8 passes, leaving a checkpoint behind
+        let path = checkpoint_path(&format!("{integrator}-{adaptive}"));
+        let stopped = RenderSettings {
+            samples_per_pixel: 3,
+            ..settings.clone()
+        };
+        let (mut camera, world) = setup(&stopped, adaptive);
+        camera.set_checkpoints(Some(Checkpoints {
+            path: path.to_str().unwrap().to_string(),
+            interval: SnapshotInterval::Passes(100),
+        }));
+        render(&camera, world).unwrap();
+
+        let (mut camera, world) = setup(&settings, adaptive);
+        camera.set_resume_from(Some(path.to_str().unwrap().to_string()));
+        let (progress, passes) = progress_channel();
+        camera.set_progress_callback(Some(progress));
+        let resumed = render(&camera, world);
+        fs::remove_file(&path).unwrap();
+        assert!(
+            full == bits(&resumed.unwrap()),
+            "{integrator}, adaptive {adaptive}"
+        );
+        assert_eq!(passes.try_iter().map(|p| p.pass).min(), Some(3));
+    }
+}
+
+#[test]
+fn resuming_with_different_settings_fails() {
+    let settings = RenderSettings {
+        samples_per_pixel: 2,
+        sampler: "stratified".to_string(),
+        ..RenderSettings::default()
+    };
+    let path = checkpoint_path("settings");
+    let (mut camera, world) = setup(&settings, false);
+    camera.set_checkpoints(Some(Checkpoints {
+        path: path.to_str().unwrap().to_string(),
+        interval: SnapshotInterval::Passes(100),
+    }));
+    render(&camera, world).unwrap();
+
+    // Stratified samples depend on the sample count, unlike random ones
+    for changed in [
+        RenderSettings {
+            samples_per_pixel: 4,
+            ..settings.clone()
+        },
+        RenderSettings {
+            seed: 1,
+            ..settings.clone()
+        },
+        RenderSettings {
+            filter: "tent".to_string(),
+            ..settings.clone()
+        },
+    ] {
+        let (mut camera, world) = setup(&changed, false);
+        camera.set_resume_from(Some(path.to_str().unwrap().to_string()));
+        let err = render(&camera, world).unwrap_err();
+        assert!(
+            err.to_string().contains("different camera settings"),
+            "{err}"
+        );
+    }
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,73 @@
+use std::{
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use raytracing::{
+    distributed,
+    image::Image,
+    scene::{ApertureDescription, RenderSettings, Scene},
+};
+
+const SCENE: &str = "\
+camera look_from 0 1 4 look_at 0 0.5 0 vfov 40 aspect 1.5 width 30
+background sky
+material floor lambertian 0.5 0.5 0.5
+material glass dielectric 1.5
+material lamp light 4 4 4
+sphere 0 -1000 0 1000 floor
+sphere -0.8 0.5 0 0.5 glass
+quad -1 2 -1 2 0 0 0 0 2 lamp
+";
+
+fn bits(image: &Image) -> Vec<[u64; 3]> {
+    image
+        .pixels
+        .iter()
+        .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+        .collect()
+}
+
+#[test]
+fn distributed_renders_match_local_ones() {
+    let scene = Scene::parse(SCENE).unwrap();
+    for integrator in ["path", "whitted"] {
+        let settings = RenderSettings {
+            integrator: integrator.to_string(),
+            max_depth: 8,
+            samples_per_pixel: 4,
+            seed: 11,
+            filter: "gaussian".to_string(),
+            ..RenderSettings::default()
+        };
+
+        let mut camera = scene.build_camera(&settings).unwrap();
+        camera.set_quiet(true);
+        let world = Arc::new(scene.build_world().unwrap());
+        let (local, _) = camera.render_to_image(world, &mut |_| {}).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let workers: Vec<_> = (0..3)
+            .map(|_| thread::spawn(move || distributed::work(TcpStream::connect(addr)?)))
+            .collect();
+        let merged = distributed::coordinate(listener, &scene, &settings, 8, true).unwrap();
+        // Workers still waiting when the last tile came in see the coordinator hang up
+        for worker in workers {
+            let _ = worker.join().unwrap();
+        }
+
+        assert!(bits(&local) == bits(&merged), "{integrator}");
+    }
+}
+
+#[test]
+fn scenes_reading_files_are_not_distributed() {
+    let mut scene = Scene::parse(SCENE).unwrap();
+    scene.camera.aperture = ApertureDescription::Image("/etc/hostname".to_string());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let err =
+        distributed::coordinate(listener, &scene, &RenderSettings::default(), 8, true).unwrap_err();
+    assert!(err.to_string().contains("/etc/hostname"), "{err}");
+}
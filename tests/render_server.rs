@@ -0,0 +1,157 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SCENE: &str = "\
+camera look_from 0 1 4 look_at 0 0.5 0 vfov 40 aspect 1.5 width 30
+background sky
+material floor lambertian 0.5 0.5 0.5
+material ball metal 0.8 0.6 0.2 0.1
+sphere 0 -1000 0 1000 floor
+sphere 0 0.5 0 0.5 ball
+";
+
+/// The server process, killed when dropped
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_render_server"))
+            .args(["--listen", "127.0.0.1:0", "--preview-every", "0.01"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let addr = line
+            .trim()
+            .strip_prefix("Listening on http://")
+            .unwrap_or_else(|| panic!("unexpected output `{line}`"))
+            .to_string();
+        // Keep draining stderr so the server never blocks writing to it
+        thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+        Server { child, addr }
+    }
+
+    /// Status code and body of the response to one request
+    fn request(&self, method: &str, target: &str, body: &str) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(&self.addr).unwrap();
+        write!(
+            stream,
+            "{method} {target} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{body}",
+            self.addr,
+            body.len()
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Value of `"key":` in a flat JSON object, as written
+fn json_field<'a>(json: &'a str, key: &str) -> &'a str {
+    let start = json.find(&format!("\"{key}\":")).unwrap() + key.len() + 3;
+    let len = json[start..].find([',', '}']).unwrap();
+    json[start..start + len].trim_matches('"')
+}
+
+/// Check `png` is a PNG of `width` by `height` pixels
+fn assert_png(png: &[u8], width: u32, height: u32) {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), width);
+    assert_eq!(u32::from_be_bytes(png[20..24].try_into().unwrap()), height);
+}
+
+#[test]
+fn renders_a_submitted_scene() {
+    let server = Server::start();
+    let (status, body) = server.request("POST", "/jobs?spp=16&max_depth=8&seed=5", SCENE);
+    let body = String::from_utf8(body).unwrap();
+    assert_eq!(status, 201, "{body}");
+    let id = json_field(&body, "id").to_string();
+
+    let start = Instant::now();
+    loop {
+        let (status, body) = server.request("GET", &format!("/jobs/{id}"), "");
+        assert_eq!(status, 200);
+        let body = String::from_utf8(body).unwrap();
+        match json_field(&body, "state") {
+            "done" => break,
+            "queued" | "rendering" => {}
+            state => panic!("job {state}: {body}"),
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(60),
+            "job never finished"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // 30 pixels wide at an aspect ratio of 1.5
+    for image in ["preview", "image"] {
+        let (status, png) = server.request("GET", &format!("/jobs/{id}/{image}"), "");
+        assert_eq!(status, 200, "{image}");
+        assert_png(&png, 30, 20);
+    }
+
+    let (status, _) = server.request("DELETE", &format!("/jobs/{id}"), "");
+    assert_eq!(status, 200);
+    let (status, _) = server.request("GET", &format!("/jobs/{id}"), "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn rejects_scenes_reading_files_and_settings_out_of_range() {
+    let server = Server::start();
+    for (query, scene) in [
+        (
+            "",
+            "camera look_from 0 0 0 look_at 0 0 -1 aperture image /etc/hostname\n",
+        ),
+        (
+            "",
+            "camera look_from 0 0 0 look_at 0 0 -1 lens /nonexistent/lens 43 1\n",
+        ),
+        ("?max_depth=1000000", SCENE),
+        ("?max_depth=0", SCENE),
+        ("?spp=0", SCENE),
+        ("?filter_radius=1e9", SCENE),
+    ] {
+        let (status, body) = server.request("POST", &format!("/jobs{query}"), scene);
+        assert_eq!(status, 400, "{query}: {}", String::from_utf8_lossy(&body));
+    }
+
+    // The same answer whether or not the file exists
+    let (_, exists) = server.request(
+        "POST",
+        "/jobs",
+        "camera look_from 0 0 0 look_at 0 0 -1 aperture image /etc/hostname\n",
+    );
+    let (_, missing) = server.request(
+        "POST",
+        "/jobs",
+        "camera look_from 0 0 0 look_at 0 0 -1 aperture image /nonexistent\n",
+    );
+    assert_eq!(exists, missing);
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use raytracing::{
+    image::Image,
+    integrator::{self, Integrator},
+    photon::ProgressivePhotonMapper,
+    scene::{RenderSettings, Scene},
+};
+
+const SCENE: &str = "\
+camera look_from 0 1 4 look_at 0 0.5 0 vfov 40 aspect 1.5 width 24
+background sky
+material floor lambertian 0.5 0.5 0.5
+material glass dielectric 1.5
+material brushed anisotropic_metal 0.9 0.8 0.7 0.3 0.6
+material lamp light 4 4 4
+sphere 0 -1000 0 1000 floor
+sphere -0.8 0.5 0 0.5 glass
+sphere 0.8 0.4 0 0.4 brushed
+quad -1 2 -1 2 0 0 0 0 2 lamp
+";
+
+/// The integrator `settings` names, with fewer photons than usual to keep the test quick
+fn integrator(settings: &RenderSettings) -> Arc<dyn Integrator> {
+    match settings.integrator.as_str() {
+        "sppm" => Arc::new(ProgressivePhotonMapper::new(2_000, settings.max_depth)),
+        name => integrator::by_name(name, settings.max_depth).unwrap(),
+    }
+}
+
+/// Render `SCENE` on a pool of `threads` threads
+fn render(settings: &RenderSettings, threads: usize) -> Image {
+    let scene = Scene::parse(SCENE).unwrap();
+    let world = Arc::new(scene.build_world().unwrap());
+    let mut camera = scene.build_camera(settings).unwrap();
+    camera.set_integrator(integrator(settings));
+    camera.set_quiet(true);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    pool.install(|| camera.render_to_image(world, &mut |_| {}))
+        .unwrap()
+        .0
+}
+
+fn bits(image: &Image) -> Vec<[u64; 3]> {
+    image
+        .pixels
+        .iter()
+        .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+        .collect()
+}
+
+#[test]
+fn renders_are_identical_across_runs_and_thread_counts() {
+    for integrator in ["path", "bdpt", "sppm", "mlt"] {
+        let settings = RenderSettings {
+            integrator: integrator.to_string(),
+            max_depth: 8,
+            samples_per_pixel: 4,
+            seed: 7,
+            filter: "gaussian".to_string(),
+            ..RenderSettings::default()
+        };
+        let reference = bits(&render(&settings, 1));
+        assert_eq!(reference, bits(&render(&settings, 1)), "{integrator}");
+        assert_eq!(reference, bits(&render(&settings, 3)), "{integrator}");
+        assert_eq!(reference, bits(&render(&settings, 4)), "{integrator}");
+
+        let reseeded = RenderSettings {
+            seed: 8,
+            ..settings
+        };
+        assert_ne!(reference, bits(&render(&reseeded, 3)), "{integrator}");
+    }
+}